@@ -0,0 +1,4 @@
+use solana_qos_common::channel::JoinError;
+
+/// Failed to join a channel of the topology
+pub type ClientError = JoinError;
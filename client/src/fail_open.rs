@@ -0,0 +1,228 @@
+//! Fail-open policy for a stalled sidecar. While qos does not beat, a
+//! [QosClient](crate::QosClient) routes packets straight to sigverify
+//! at a limited rate instead of halting ingestion.
+
+use std::time::Duration;
+
+use timer::{Clock, RealClock};
+
+#[derive(Debug, Clone, Copy)]
+pub struct FailOpenConfig {
+    /// How long qos may go without beating before it is considered
+    /// stalled
+    pub heartbeat_timeout: Duration,
+
+    /// Packets per second routed to sigverify while bypassing
+    pub bypass_pps: u64,
+
+    /// Packets that may be routed at once after an idle period
+    pub bypass_burst: u64,
+
+    /// Bypassed packets held until drained. Further packets are
+    /// dropped, bounding memory if the client is not drained.
+    pub bypass_capacity: usize,
+}
+
+impl Default for FailOpenConfig {
+    fn default() -> FailOpenConfig {
+        // TODO: hard coded parameter
+        FailOpenConfig {
+            heartbeat_timeout: Duration::from_millis(500),
+            bypass_pps: 20_000,
+            bypass_burst: 1_000,
+            bypass_capacity: 32_768,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct FailOpenStats {
+    /// Times qos was found stalled
+    pub stalls: usize,
+
+    /// Times qos resumed beating while bypassed
+    pub recoveries: usize,
+
+    /// Packets routed straight to sigverify
+    pub bypassed: usize,
+
+    /// Packets dropped by the bypass rate limit
+    pub dropped: usize,
+
+    /// Packets dropped as the bypass capacity was reached
+    pub overflowed: usize,
+}
+
+/// Tracks whether qos is alive and rate limits packets while it is not
+pub struct FailOpen<C: Clock = RealClock> {
+    config: FailOpenConfig,
+    clock: C,
+
+    last_heartbeat_ns: u64,
+    bypassing: bool,
+
+    /// Token bucket for bypassed packets
+    tokens: f64,
+    last_refill_ns: u64,
+
+    stats: FailOpenStats,
+}
+
+impl FailOpen {
+    pub fn new(config: FailOpenConfig) -> FailOpen {
+        FailOpen::with_clock(config, RealClock::new())
+    }
+}
+
+impl<C: Clock> FailOpen<C> {
+    /// Starts out trusting qos, with a full bucket
+    pub fn with_clock(config: FailOpenConfig, clock: C) -> FailOpen<C> {
+        let now_ns = clock.now_ns();
+        FailOpen {
+            config,
+            clock,
+            last_heartbeat_ns: now_ns,
+            bypassing: false,
+            tokens: config.bypass_burst as f64,
+            last_refill_ns: now_ns,
+            stats: FailOpenStats::default(),
+        }
+    }
+
+    /// Records whether qos beat since the last observation, switching
+    /// to or from bypass. Returns whether packets are now bypassed.
+    pub fn observe(&mut self, heartbeat: bool) -> bool {
+        let now_ns = self.clock.now_ns();
+        if heartbeat {
+            self.last_heartbeat_ns = now_ns;
+            if self.bypassing {
+                self.bypassing = false;
+                self.stats.recoveries += 1;
+            }
+        } else if !self.bypassing
+            && now_ns.saturating_sub(self.last_heartbeat_ns)
+                > self.config.heartbeat_timeout.as_nanos() as u64
+        {
+            self.bypassing = true;
+            self.stats.stalls += 1;
+        }
+
+        self.bypassing
+    }
+
+    pub fn bypassing(&self) -> bool {
+        self.bypassing
+    }
+
+    /// Whether a packet may be routed straight to sigverify, given
+    /// that `held` bypassed packets are awaiting a drain
+    pub fn admit(&mut self, held: usize) -> bool {
+        if held >= self.config.bypass_capacity {
+            self.stats.overflowed += 1;
+            return false;
+        }
+
+        let now_ns = self.clock.now_ns();
+        let elapsed_ns = now_ns.saturating_sub(self.last_refill_ns);
+        self.last_refill_ns = now_ns;
+        self.tokens = (self.tokens
+            + elapsed_ns as f64 * self.config.bypass_pps as f64 / 1e9)
+            .min(self.config.bypass_burst as f64);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.stats.bypassed += 1;
+            true
+        } else {
+            self.stats.dropped += 1;
+            false
+        }
+    }
+
+    pub fn stats(&self) -> &FailOpenStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use timer::ManualClock;
+
+    use super::*;
+
+    fn fail_open(clock: &ManualClock) -> FailOpen<ManualClock> {
+        FailOpen::with_clock(
+            FailOpenConfig {
+                heartbeat_timeout: Duration::from_millis(100),
+                bypass_pps: 1_000,
+                bypass_burst: 10,
+                bypass_capacity: 100,
+            },
+            clock.clone(),
+        )
+    }
+
+    #[test]
+    fn test_bypasses_after_timeout_and_recovers() {
+        let clock = ManualClock::new();
+        let mut fail_open = fail_open(&clock);
+
+        clock.advance(Duration::from_millis(100));
+        assert!(!fail_open.observe(false));
+
+        clock.advance(Duration::from_millis(1));
+        assert!(fail_open.observe(false));
+        assert!(fail_open.observe(false));
+        assert_eq!(fail_open.stats().stalls, 1);
+
+        assert!(!fail_open.observe(true));
+        assert_eq!(fail_open.stats().recoveries, 1);
+
+        // The timeout restarts from the last heartbeat
+        clock.advance(Duration::from_millis(50));
+        assert!(!fail_open.observe(false));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let clock = ManualClock::new();
+        let mut fail_open = fail_open(&clock);
+
+        // Burst
+        let admitted = (0..20)
+            .filter(|_| fail_open.admit(0))
+            .count();
+        assert_eq!(admitted, 10);
+        assert_eq!(fail_open.stats().dropped, 10);
+
+        // 1000 pps refills one token per ms
+        clock.advance(Duration::from_millis(5));
+        let admitted = (0..20)
+            .filter(|_| fail_open.admit(0))
+            .count();
+        assert_eq!(admitted, 5);
+
+        // Never beyond the burst
+        clock.advance(Duration::from_secs(60));
+        let admitted = (0..20)
+            .filter(|_| fail_open.admit(0))
+            .count();
+        assert_eq!(admitted, 10);
+    }
+
+    #[test]
+    fn test_bypass_capacity() {
+        let clock = ManualClock::new();
+        let mut fail_open = fail_open(&clock);
+
+        assert!(fail_open.admit(99));
+        assert!(!fail_open.admit(100));
+        assert_eq!(fail_open.stats().overflowed, 1);
+
+        // Overflow does not spend tokens
+        let admitted = (0..20)
+            .filter(|_| fail_open.admit(0))
+            .count();
+        assert_eq!(admitted, 9);
+    }
+}
@@ -0,0 +1,511 @@
+//! The validator side of the qos sidecar. A [QosClient] joins the
+//! channels described by a [Topology], publishes packets and feedback
+//! into qos and drains the packets qos prioritized for sigverify.
+//!
+//! If qos stops beating, the client fails open: packets bypass qos and
+//! are drained directly, at a limited rate, until qos recovers. See
+//! [FailOpen].
+
+use std::sync::atomic::Ordering;
+
+use que::{
+    headless_spmc::{consumer::Consumer, producer::Producer},
+    page_size::PageSize,
+};
+use solana_qos_common::{
+    channel::{join_consumer, join_producer},
+    channel_header::{ChannelHeader, ChannelType},
+    ipc_parameters::*,
+    packet_bytes::PacketBytes,
+    recent_signature::RecentSignature,
+    remaining_meta::QoSRemainingMeta,
+    topology::{InputSource, Topology},
+    xxhash::{xxHash, xxHasher},
+};
+use solana_qos_internal_common::{
+    packet_bytes::{as_packet, from_packet},
+    signature_bytes::sig_bytes,
+    xxhash::packet_hash,
+};
+use solana_sdk::{packet::Packet, signature::Signature};
+use timer::{Clock, RealClock};
+
+mod error;
+mod fail_open;
+pub use error::ClientError;
+pub use fail_open::{FailOpen, FailOpenConfig, FailOpenStats};
+
+pub struct QosClient<C: Clock = RealClock> {
+    /// Input channels with their source, in topology order
+    inputs:
+        Vec<(InputSource, Producer<PacketBytes, IPC_TPU_TO_QOS_CAP>)>,
+
+    sigverify_feedback: Producer<PacketBytes, IPC_SIG_TO_QOS_CAP>,
+
+    scheduler_feedback:
+        Producer<QoSRemainingMeta<()>, IPC_SCH_TO_QOS_CAP>,
+
+    status_cache:
+        Option<Producer<RecentSignature, IPC_STATUS_CACHE_CAP>>,
+
+    prioritized: Consumer<PacketBytes, IPC_QOS_TO_SIG_CAP>,
+
+    /// The seed qos last wrote into the scheduler channel header, and
+    /// the hasher for it
+    seed: u64,
+    hasher: xxHasher,
+
+    fail_open: FailOpen<C>,
+
+    /// Packets admitted while bypassing qos, awaiting a drain
+    bypass: Vec<Packet>,
+}
+
+impl QosClient {
+    /// Joins every channel in the topology. Input and feedback channels
+    /// are created if needed. The output channel must already exist,
+    /// e.g. created by `solana-qos-cli` or qos.
+    pub fn join(
+        topology: &Topology,
+        page_size: PageSize,
+        fail_open: FailOpenConfig,
+    ) -> Result<QosClient, ClientError> {
+        QosClient::join_with_clock(
+            topology,
+            page_size,
+            fail_open,
+            RealClock::new(),
+        )
+    }
+}
+
+impl<C: Clock> QosClient<C> {
+    pub fn join_with_clock(
+        topology: &Topology,
+        page_size: PageSize,
+        fail_open: FailOpenConfig,
+        clock: C,
+    ) -> Result<QosClient<C>, ClientError> {
+        let mut inputs = vec![];
+        for input in &topology.inputs {
+            let producer = join_producer(
+                &input.name,
+                ChannelType::Packets,
+                page_size,
+                false,
+            )?;
+            inputs.push((input.source, producer));
+        }
+
+        let feedback = &topology.feedback;
+        let sigverify_feedback = join_producer(
+            &feedback.sigverify.name,
+            ChannelType::Packets,
+            page_size,
+            false,
+        )?;
+        let scheduler_feedback = join_producer(
+            &feedback.scheduler.name,
+            ChannelType::RemainingMeta,
+            page_size,
+            false,
+        )?;
+        let status_cache = feedback
+            .status_cache
+            .as_ref()
+            .map(|channel| {
+                join_producer(
+                    &channel.name,
+                    ChannelType::Signatures,
+                    page_size,
+                    false,
+                )
+            })
+            .transpose()?;
+
+        let output = &topology.output.sigverify;
+        let prioritized = join_consumer(
+            &output.name,
+            ChannelType::Packets,
+            page_size,
+        )?;
+
+        let seed = 0;
+        Ok(QosClient {
+            inputs,
+            sigverify_feedback,
+            scheduler_feedback,
+            status_cache,
+            prioritized,
+            seed,
+            hasher: xxHasher::initialize_with_seed(seed),
+            fail_open: FailOpen::with_clock(fail_open, clock),
+            bypass: vec![],
+        })
+    }
+
+    /// Sends a packet received on `source` to qos. Packets go to the
+    /// first input declared with the source. Returns false if there is
+    /// none.
+    ///
+    /// While qos is stalled the packet is instead queued for
+    /// [QosClient::drain_prioritized], or dropped if over the bypass
+    /// rate or capacity.
+    pub fn submit_packet(
+        &mut self,
+        source: InputSource,
+        packet: &Packet,
+    ) -> bool {
+        let Some((_, producer)) = self
+            .inputs
+            .iter_mut()
+            .find(|(s, _)| *s == source)
+        else {
+            return false;
+        };
+
+        if self.fail_open.bypassing() {
+            if self.fail_open.admit(self.bypass.len()) {
+                self.bypass.push(packet.clone());
+            }
+        } else {
+            producer.push(from_packet(packet));
+        }
+        true
+    }
+
+    /// Reports a packet that failed sigverify, penalizing its sender
+    pub fn report_sigverify_failure(&mut self, packet: &Packet) {
+        self.sigverify_feedback
+            .push(from_packet(packet));
+    }
+
+    /// The key qos uses for a packet, to build the [QoSRemainingMeta]
+    /// passed to [QosClient::report_execution]. Must be computed from
+    /// the packet as it was drained from qos.
+    pub fn packet_hash(&mut self, packet: &Packet) -> xxHash {
+        // qos writes its seed on startup, so it changes if qos
+        // restarts with another seed
+        let header = unsafe {
+            ChannelHeader::from_padding(
+                self.scheduler_feedback
+                    .get_padding_ptr(),
+            )
+        };
+        let seed = header.seed.load(Ordering::Acquire);
+        if seed != self.seed {
+            self.seed = seed;
+            self.hasher = xxHasher::initialize_with_seed(seed);
+        }
+
+        packet_hash(&self.hasher, packet)
+    }
+
+    /// Reports what the scheduler did with a transaction
+    pub fn report_execution(
+        &mut self,
+        remaining_meta: &QoSRemainingMeta<()>,
+    ) {
+        self.scheduler_feedback
+            .push(remaining_meta);
+    }
+
+    /// Reports a signature processed in `slot`. Returns false if the
+    /// topology has no status cache channel.
+    pub fn report_confirmed_signature(
+        &mut self,
+        slot: u64,
+        signature: &Signature,
+    ) -> bool {
+        let Some(ref mut status_cache) = self.status_cache else {
+            return false;
+        };
+
+        status_cache
+            .push(&RecentSignature::new(slot, *sig_bytes(signature)));
+        true
+    }
+
+    /// Publishes everything pushed since the last sync. Call after
+    /// each batch of submissions and reports.
+    pub fn sync(&mut self) {
+        for (_, producer) in &mut self.inputs {
+            producer.sync();
+        }
+        self.sigverify_feedback.sync();
+        self.scheduler_feedback.sync();
+        if let Some(ref mut status_cache) = self.status_cache {
+            status_cache.sync();
+        }
+    }
+
+    /// Pops up to `max` packets prioritized by qos into `out`,
+    /// followed by packets that bypassed qos, returning how many were
+    /// popped. Call regularly, as bypassed packets are held until
+    /// drained.
+    pub fn drain_prioritized(
+        &mut self,
+        max: usize,
+        out: &mut Vec<Packet>,
+    ) -> usize {
+        let mut popped = 0;
+        while popped < max {
+            let Some(packet_bytes) = self.prioritized.pop() else {
+                break;
+            };
+            out.push(as_packet(packet_bytes));
+            popped += 1;
+        }
+
+        let bypassed = self.bypass.len().min(max - popped);
+        out.extend(self.bypass.drain(..bypassed));
+        popped + bypassed
+    }
+
+    /// Signals qos that the validator is alive. Call periodically.
+    pub fn beat(&self) {
+        for (_, producer) in &self.inputs {
+            producer.beat();
+        }
+        self.sigverify_feedback.beat();
+        self.scheduler_feedback.beat();
+        if let Some(ref status_cache) = self.status_cache {
+            status_cache.beat();
+        }
+        self.prioritized.beat();
+    }
+
+    /// Whether qos has beaten since the last call
+    pub fn sidecar_heartbeat(&self) -> bool {
+        // Polls every input rather than stopping at the first beat, so
+        // that no input carries a stale beat into the next call
+        self.inputs
+            .iter()
+            .fold(false, |beat, (_, producer)| {
+                producer.consumer_heartbeat() | beat
+            })
+    }
+
+    /// Checks the qos heartbeat, bypassing qos once it has been
+    /// stalled for the configured timeout and returning to it as soon
+    /// as it beats again. Returns whether qos is bypassed. Call
+    /// periodically, e.g. once per batch.
+    pub fn poll_sidecar(&mut self) -> bool {
+        let heartbeat = self.sidecar_heartbeat();
+        self.fail_open.observe(heartbeat)
+    }
+
+    pub fn fail_open_stats(&self) -> &FailOpenStats {
+        self.fail_open.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use solana_qos_common::topology::{
+        Channel, FeedbackChannels, InputChannel, OutputChannels,
+    };
+    use solana_qos_internal_common::test_utils::{
+        packet, packet_id, ShmRegion,
+    };
+    use timer::ManualClock;
+
+    use super::*;
+
+    /// A client with a single tpu input, and the qos ends of the
+    /// channels qos writes to
+    struct Harness {
+        client: QosClient<ManualClock>,
+        clock: ManualClock,
+        prioritized: Producer<PacketBytes, IPC_QOS_TO_SIG_CAP>,
+        scheduler_feedback:
+            Consumer<QoSRemainingMeta<()>, IPC_SCH_TO_QOS_CAP>,
+        /// Removed after the channels above are dropped
+        _regions: [ShmRegion; 4],
+    }
+
+    impl Harness {
+        fn new(test: &str) -> Harness {
+            let region = |channel: &str| {
+                ShmRegion::new(&format!("client_{test}_{channel}"))
+            };
+            let regions = [
+                region("tpu"),
+                region("sig"),
+                region("sch"),
+                region("out"),
+            ];
+            let [tpu, sig, sch, out] = &regions;
+            let channel = |region: &ShmRegion| Channel {
+                name: region.name().to_string(),
+                capacity: None,
+            };
+            let topology = Topology {
+                inputs: vec![InputChannel {
+                    name: tpu.name().to_string(),
+                    source: InputSource::Tpu,
+                    weight: 1,
+                    capacity: None,
+                }],
+                feedback: FeedbackChannels {
+                    sigverify: channel(sig),
+                    scheduler: channel(sch),
+                    status_cache: None,
+                },
+                output: OutputChannels {
+                    sigverify: channel(out),
+                },
+            };
+
+            let page_size = PageSize::Standard;
+            let prioritized = join_producer(
+                &topology.output.sigverify.name,
+                ChannelType::Packets,
+                page_size,
+                false,
+            )
+            .unwrap();
+            let clock = ManualClock::new();
+            let client = QosClient::join_with_clock(
+                &topology,
+                page_size,
+                FailOpenConfig {
+                    heartbeat_timeout: Duration::from_millis(100),
+                    bypass_pps: 1_000,
+                    bypass_burst: 10,
+                    bypass_capacity: 100,
+                },
+                clock.clone(),
+            )
+            .unwrap();
+            let scheduler_feedback = join_consumer(
+                &topology.feedback.scheduler.name,
+                ChannelType::RemainingMeta,
+                page_size,
+            )
+            .unwrap();
+
+            Harness {
+                client,
+                clock,
+                prioritized,
+                scheduler_feedback,
+                _regions: regions,
+            }
+        }
+
+        /// Lets the qos heartbeat time out
+        fn stall(&mut self) {
+            self.clock
+                .advance(Duration::from_millis(101));
+            assert!(self.client.poll_sidecar());
+        }
+    }
+
+    fn ids(packets: &[Packet]) -> Vec<u8> {
+        packets.iter().map(packet_id).collect()
+    }
+
+    #[test]
+    fn test_drain_prioritized_then_bypassed() {
+        let mut harness = Harness::new("drain");
+        for id in [1, 2] {
+            harness
+                .prioritized
+                .push(from_packet(&packet(id)));
+        }
+        harness.prioritized.sync();
+
+        harness.stall();
+        for id in [3, 4, 5] {
+            assert!(harness
+                .client
+                .submit_packet(InputSource::Tpu, &packet(id)));
+        }
+
+        let mut out = vec![];
+        assert_eq!(
+            harness
+                .client
+                .drain_prioritized(4, &mut out),
+            4
+        );
+        assert_eq!(ids(&out), [1, 2, 3, 4]);
+        assert_eq!(
+            harness
+                .client
+                .drain_prioritized(4, &mut out),
+            1
+        );
+        assert_eq!(ids(&out), [1, 2, 3, 4, 5]);
+        assert_eq!(
+            harness
+                .client
+                .drain_prioritized(4, &mut out),
+            0
+        );
+        assert_eq!(
+            harness
+                .client
+                .fail_open_stats()
+                .bypassed,
+            3
+        );
+    }
+
+    #[test]
+    fn test_submit_packet_unknown_source() {
+        let mut harness = Harness::new("unknown");
+        assert!(!harness
+            .client
+            .submit_packet(InputSource::Fwd, &packet(1)));
+
+        // Nor is it bypassed
+        harness.stall();
+        assert!(!harness
+            .client
+            .submit_packet(InputSource::Re1, &packet(1)));
+        assert_eq!(
+            harness
+                .client
+                .drain_prioritized(4, &mut vec![]),
+            0
+        );
+        assert_eq!(
+            harness
+                .client
+                .fail_open_stats()
+                .bypassed,
+            0
+        );
+    }
+
+    #[test]
+    fn test_packet_hash_follows_seed() {
+        let mut harness = Harness::new("seed");
+        let header = unsafe {
+            ChannelHeader::from_padding(
+                harness
+                    .scheduler_feedback
+                    .get_padding_ptr(),
+            )
+        };
+        let packet = packet(1);
+        let expected = |seed| {
+            packet_hash(&xxHasher::initialize_with_seed(seed), &packet)
+        };
+
+        // qos writes its seed on startup
+        header
+            .seed
+            .store(420, Ordering::Release);
+        assert_eq!(harness.client.packet_hash(&packet), expected(420));
+
+        // and again on restart
+        header.seed.store(69, Ordering::Release);
+        assert_eq!(harness.client.packet_hash(&packet), expected(69));
+        assert_ne!(expected(69), expected(420));
+    }
+}
@@ -0,0 +1,95 @@
+//! Joins channels and checks their [ChannelHeader], for both qos and
+//! the client.
+
+use bytemuck::Pod;
+use que::{
+    error::QueError,
+    headless_spmc::{consumer::Consumer, producer::Producer},
+    page_size::PageSize,
+};
+
+use crate::channel_header::{
+    ChannelHeader, ChannelLayout, ChannelType, HandshakeError,
+};
+
+#[derive(Debug)]
+pub enum JoinError {
+    /// Failed to create or join the channel
+    Que { name: String, error: QueError },
+
+    /// The other end of the channel expects a different layout
+    Handshake { name: String, error: HandshakeError },
+}
+
+/// Joins an existing channel and verifies its header, or stamps it if
+/// no end has yet
+pub fn join_consumer<T: Pod, const CAP: usize>(
+    name: &str,
+    channel_type: ChannelType,
+    #[allow(unused_variables)] page_size: PageSize,
+) -> Result<Consumer<T, CAP>, JoinError> {
+    let consumer = unsafe {
+        Consumer::join_shmem(
+            name,
+            #[cfg(target_os = "linux")]
+            page_size,
+        )
+    }
+    .map_err(|error| JoinError::Que {
+        name: name.to_string(),
+        error,
+    })?;
+
+    let header = unsafe {
+        ChannelHeader::from_padding(consumer.get_padding_ptr())
+    };
+    handshake::<T>(name, header, channel_type)?;
+
+    Ok(consumer)
+}
+
+/// Joins a channel, creating it if needed. With `restamp`, the header
+/// is stamped rather than verified, for the end that creates the
+/// channel.
+pub fn join_producer<T: Pod, const CAP: usize>(
+    name: &str,
+    channel_type: ChannelType,
+    #[allow(unused_variables)] page_size: PageSize,
+    restamp: bool,
+) -> Result<Producer<T, CAP>, JoinError> {
+    let producer = unsafe {
+        Producer::join_or_create_shmem(
+            name,
+            #[cfg(target_os = "linux")]
+            page_size,
+        )
+    }
+    .map_err(|error| JoinError::Que {
+        name: name.to_string(),
+        error,
+    })?;
+
+    let header = unsafe {
+        ChannelHeader::from_padding(producer.get_padding_ptr())
+    };
+    if restamp {
+        header.restamp(ChannelLayout::of::<T>(channel_type));
+    } else {
+        handshake::<T>(name, header, channel_type)?;
+    }
+
+    Ok(producer)
+}
+
+fn handshake<T>(
+    name: &str,
+    header: &ChannelHeader,
+    channel_type: ChannelType,
+) -> Result<(), JoinError> {
+    header
+        .stamp_or_verify(ChannelLayout::of::<T>(channel_type))
+        .map_err(|error| JoinError::Handshake {
+            name: name.to_string(),
+            error,
+        })
+}
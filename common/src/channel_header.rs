@@ -0,0 +1,314 @@
+//! A header stamped into the padding of every qos channel, so that both
+//! ends can check that they agree on the message layout before
+//! exchanging any messages.
+//!
+//! Whichever end joins first stamps the header. Every later join
+//! verifies it, and a mismatch means the two ends were built against
+//! different versions of this crate.
+//!
+//! A header is never stamped twice by [ChannelHeader::stamp_or_verify].
+//! The end that creates the channels (solana-qos-cli) instead calls
+//! [ChannelHeader::restamp], which replaces a header left by an older
+//! build or by a process that died mid stamp.
+
+use std::{
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+pub const CHANNEL_MAGIC: u32 = u32::from_le_bytes(*b"QoS\0");
+
+/// Bumped whenever a message layout or the meaning of a header field
+/// changes
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Marks a header that is being stamped
+const STAMPING: u32 = u32::MAX;
+
+/// What a channel carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ChannelType {
+    /// [crate::packet_bytes::PacketBytes]
+    Packets = 1,
+
+    /// [crate::remaining_meta::QoSRemainingMeta]
+    RemainingMeta = 2,
+
+    /// [crate::recent_signature::RecentSignature]
+    Signatures = 3,
+}
+
+/// The message layout one end of a channel expects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelLayout {
+    pub channel_type: ChannelType,
+    pub element_size: u32,
+    pub element_align: u32,
+}
+
+impl ChannelLayout {
+    pub const fn of<T>(channel_type: ChannelType) -> ChannelLayout {
+        ChannelLayout {
+            channel_type,
+            element_size: core::mem::size_of::<T>() as u32,
+            element_align: core::mem::align_of::<T>() as u32,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// The other end started stamping the header and never finished,
+    /// e.g. it crashed. Recreate the channel with solana-qos-cli, or
+    /// remove it (under `/dev/shm`, or the huge page mount) so that
+    /// the next join stamps it afresh.
+    Incomplete,
+
+    /// The header disagrees with this end
+    Mismatch {
+        field: &'static str,
+        expected: u32,
+        found: u32,
+    },
+}
+
+/// Lives at the start of the channel padding. 32 bytes.
+#[repr(C)]
+pub struct ChannelHeader {
+    /// The xxhash seed used for packet keys, written by qos into the
+    /// scheduler channel. First as it predates the header.
+    pub seed: AtomicU64,
+
+    magic: AtomicU32,
+    version: AtomicU32,
+    channel_type: AtomicU32,
+    element_size: AtomicU32,
+    element_align: AtomicU32,
+    _reserved: AtomicU32,
+}
+
+impl ChannelHeader {
+    /// # Safety
+    /// `padding` must point to at least 32 bytes of 8 byte aligned
+    /// memory that outlives the returned reference, i.e. the padding
+    /// of a joined channel.
+    pub unsafe fn from_padding<'a, P>(
+        padding: NonNull<P>,
+    ) -> &'a ChannelHeader {
+        padding.cast::<ChannelHeader>().as_ref()
+    }
+
+    /// Stamps the header if no end has yet, and verifies it otherwise
+    pub fn stamp_or_verify(
+        &self,
+        layout: ChannelLayout,
+    ) -> Result<(), HandshakeError> {
+        match self.magic.compare_exchange(
+            0,
+            STAMPING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                self.stamp(layout);
+                Ok(())
+            }
+            Err(_) => {
+                self.wait_for_stamp()?;
+                self.verify(layout)
+            }
+        }
+    }
+
+    /// Stamps the header whatever its state. Only for the end that
+    /// creates the channel, before the other ends join, as ends that
+    /// already verified a different layout are not told.
+    pub fn restamp(&self, layout: ChannelLayout) {
+        self.magic
+            .store(STAMPING, Ordering::Release);
+        self.stamp(layout);
+    }
+
+    fn stamp(&self, layout: ChannelLayout) {
+        self.version
+            .store(PROTOCOL_VERSION, Ordering::Relaxed);
+        self.channel_type
+            .store(layout.channel_type as u32, Ordering::Relaxed);
+        self.element_size
+            .store(layout.element_size, Ordering::Relaxed);
+        self.element_align
+            .store(layout.element_align, Ordering::Relaxed);
+
+        // Publish
+        self.magic
+            .store(CHANNEL_MAGIC, Ordering::Release);
+    }
+
+    fn wait_for_stamp(&self) -> Result<(), HandshakeError> {
+        // TODO: hard coded parameter
+        const TIMEOUT: Duration = Duration::from_secs(1);
+
+        let start = Instant::now();
+        while self.magic.load(Ordering::Acquire) == STAMPING {
+            if start.elapsed() > TIMEOUT {
+                return Err(HandshakeError::Incomplete);
+            }
+            std::thread::yield_now();
+        }
+
+        Ok(())
+    }
+
+    fn verify(
+        &self,
+        layout: ChannelLayout,
+    ) -> Result<(), HandshakeError> {
+        let fields = [
+            ("magic", CHANNEL_MAGIC, &self.magic),
+            ("version", PROTOCOL_VERSION, &self.version),
+            (
+                "channel_type",
+                layout.channel_type as u32,
+                &self.channel_type,
+            ),
+            ("element_size", layout.element_size, &self.element_size),
+            (
+                "element_align",
+                layout.element_align,
+                &self.element_align,
+            ),
+        ];
+
+        for (field, expected, value) in fields {
+            let found = value.load(Ordering::Acquire);
+            if found != expected {
+                return Err(HandshakeError::Mismatch {
+                    field,
+                    expected,
+                    found,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn padding() -> Box<[u64; 4]> {
+        Box::new([0; 4])
+    }
+
+    #[test]
+    fn test_first_join_stamps_later_joins_verify() {
+        let mut padding = padding();
+        let header = unsafe {
+            ChannelHeader::from_padding(NonNull::from(&mut *padding))
+        };
+        let layout =
+            ChannelLayout::of::<[u8; 64]>(ChannelType::Signatures);
+
+        header
+            .seed
+            .store(420, Ordering::Release);
+        header.stamp_or_verify(layout).unwrap();
+        header.stamp_or_verify(layout).unwrap();
+
+        // The seed keeps its place in the first 8 bytes
+        assert_eq!(padding[0], 420);
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let mut padding = padding();
+        let header = unsafe {
+            ChannelHeader::from_padding(NonNull::from(&mut *padding))
+        };
+        header
+            .stamp_or_verify(ChannelLayout::of::<[u8; 64]>(
+                ChannelType::Signatures,
+            ))
+            .unwrap();
+
+        let result =
+            header.stamp_or_verify(ChannelLayout::of::<[u8; 72]>(
+                ChannelType::Signatures,
+            ));
+        assert!(matches!(
+            result,
+            Err(HandshakeError::Mismatch {
+                field: "element_size",
+                expected: 72,
+                found: 64,
+            })
+        ));
+
+        let result =
+            header.stamp_or_verify(ChannelLayout::of::<[u8; 64]>(
+                ChannelType::Packets,
+            ));
+        assert!(matches!(
+            result,
+            Err(HandshakeError::Mismatch {
+                field: "channel_type",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_restamp_recovers_interrupted_stamp() {
+        let mut padding = padding();
+        // A stamp that never finished
+        padding[1] = STAMPING as u64;
+        let header = unsafe {
+            ChannelHeader::from_padding(NonNull::from(&mut *padding))
+        };
+        let layout = ChannelLayout::of::<u64>(ChannelType::Packets);
+
+        assert!(matches!(
+            header.stamp_or_verify(layout),
+            Err(HandshakeError::Incomplete)
+        ));
+
+        header.restamp(layout);
+        header.stamp_or_verify(layout).unwrap();
+    }
+
+    #[test]
+    fn test_restamp_replaces_stale_layout() {
+        let mut padding = padding();
+        let header = unsafe {
+            ChannelHeader::from_padding(NonNull::from(&mut *padding))
+        };
+        let old = ChannelLayout::of::<u64>(ChannelType::Packets);
+        let new = ChannelLayout::of::<[u8; 72]>(ChannelType::Packets);
+        header.stamp_or_verify(old).unwrap();
+
+        header.restamp(new);
+        header.stamp_or_verify(new).unwrap();
+        assert!(header.stamp_or_verify(old).is_err());
+    }
+
+    #[test]
+    fn test_foreign_padding_is_rejected() {
+        let mut padding = padding();
+        padding[1] = 0xdead_beef;
+        let header = unsafe {
+            ChannelHeader::from_padding(NonNull::from(&mut *padding))
+        };
+
+        let result = header.stamp_or_verify(ChannelLayout::of::<u64>(
+            ChannelType::Packets,
+        ));
+        assert!(matches!(
+            result,
+            Err(HandshakeError::Mismatch { field: "magic", .. })
+        ));
+    }
+}
@@ -1,7 +1,11 @@
+pub mod channel;
+pub mod channel_header;
 pub mod ipc_parameters;
 pub mod packet_bytes;
+pub mod recent_signature;
 pub mod remaining_meta;
 pub mod shared_stats;
+pub mod topology;
 pub mod xxhash;
 
 pub fn checked_drop_privileges() -> Result<(), String> {
@@ -0,0 +1,19 @@
+use bytemuck::{Pod, Zeroable};
+
+/// A signature the bank recently processed, sent from the status cache
+/// to qos
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+#[repr(C)]
+pub struct RecentSignature {
+    /// Slot in which the transaction was processed
+    pub slot: u64,
+
+    pub signature: [u8; 64],
+}
+
+impl RecentSignature {
+    #[inline(always)]
+    pub fn new(slot: u64, signature: [u8; 64]) -> RecentSignature {
+        RecentSignature { slot, signature }
+    }
+}
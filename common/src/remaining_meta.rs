@@ -5,25 +5,185 @@ use crate::xxhash::xxHash;
 #[derive(Debug, Clone, Copy, Zeroable)]
 #[repr(C, align(8))]
 #[cfg_attr(test, derive(PartialEq))]
-pub struct QoSRemainingMeta<A: Sized + AnyBitPattern> {
+pub struct QoSRemainingMeta<A>
+where
+    A: AnyBitPattern,
+{
     /// The xx3 hash of the packet bytes associated with this
     /// transaction. Used as the LRU cache key.
     pub packet_hash: xxHash,
 
-    /// Execution time (zero if not scheduled)
+    /// Execution time (zero if not executed)
     pub execution_nanos: u64,
 
+    /// Compute units consumed (zero if not executed)
+    pub consumed_cus: u64,
+
+    /// Fee charged in lamports (zero if not executed)
+    pub fee_charged: u64,
+
+    /// Slot in which the scheduler reached the outcome
+    pub slot: u64,
+
+    /// An [Outcome]. Kept as a byte so that any bit pattern is valid.
+    pub outcome: u8,
+
+    /// An [ErrorClass], if the outcome is [Outcome::Failed]
+    pub error_class: u8,
+
+    pub _padding: [u8; 6],
+
     /// Additional metadata (model-specific)
     pub additional_metadata: A,
 }
 
 unsafe impl<A: Pod + AnyBitPattern> Pod for QoSRemainingMeta<A> {}
 
+/// What the scheduler did with a transaction
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Outcome {
+    #[default]
+    NotScheduled = 0,
+
+    /// Executed and committed without error
+    Succeeded = 1,
+
+    /// Executed and committed with an error. The fee is still charged.
+    Failed = 2,
+
+    /// Dropped without executing as it was too old, e.g. its blockhash
+    /// expired
+    Expired = 3,
+}
+
+impl Outcome {
+    #[inline(always)]
+    pub fn from_u8(outcome: u8) -> Option<Outcome> {
+        match outcome {
+            0 => Some(Outcome::NotScheduled),
+            1 => Some(Outcome::Succeeded),
+            2 => Some(Outcome::Failed),
+            3 => Some(Outcome::Expired),
+            _ => None,
+        }
+    }
+
+    /// Whether the transaction was executed and charged
+    #[inline(always)]
+    pub fn executed(self) -> bool {
+        matches!(self, Outcome::Succeeded | Outcome::Failed)
+    }
+}
+
+/// Coarse class of the error a failed transaction executed with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorClass {
+    #[default]
+    None = 0,
+    InsufficientFunds = 1,
+    InstructionError = 2,
+    ComputeBudgetExceeded = 3,
+    AccountLoad = 4,
+    Other = 255,
+}
+
+impl ErrorClass {
+    #[inline(always)]
+    pub fn from_u8(error_class: u8) -> ErrorClass {
+        match error_class {
+            0 => ErrorClass::None,
+            1 => ErrorClass::InsufficientFunds,
+            2 => ErrorClass::InstructionError,
+            3 => ErrorClass::ComputeBudgetExceeded,
+            4 => ErrorClass::AccountLoad,
+            _ => ErrorClass::Other,
+        }
+    }
+}
+
 impl<A: Pod + AnyBitPattern> QoSRemainingMeta<A> {
     pub const SIZE: usize = core::mem::size_of::<Self>();
     pub const _ASSERT_ALIGN: () =
         assert!(core::mem::align_of::<A>() <= 8);
 
+    /// Feedback for a transaction the scheduler did not execute
+    pub fn not_executed(
+        packet_hash: xxHash,
+        outcome: Outcome,
+        slot: u64,
+        additional_metadata: A,
+    ) -> QoSRemainingMeta<A> {
+        debug_assert!(!outcome.executed());
+        QoSRemainingMeta {
+            packet_hash,
+            execution_nanos: 0,
+            consumed_cus: 0,
+            fee_charged: 0,
+            slot,
+            outcome: outcome as u8,
+            error_class: ErrorClass::None as u8,
+            _padding: [0; 6],
+            additional_metadata,
+        }
+    }
+
+    /// Feedback for an executed transaction. `error_class` is
+    /// [ErrorClass::None] if it succeeded.
+    #[allow(clippy::too_many_arguments)]
+    pub fn executed(
+        packet_hash: xxHash,
+        execution_nanos: u64,
+        consumed_cus: u64,
+        fee_charged: u64,
+        slot: u64,
+        error_class: ErrorClass,
+        additional_metadata: A,
+    ) -> QoSRemainingMeta<A> {
+        let outcome = match error_class {
+            ErrorClass::None => Outcome::Succeeded,
+            _ => Outcome::Failed,
+        };
+        QoSRemainingMeta {
+            packet_hash,
+            execution_nanos,
+            consumed_cus,
+            fee_charged,
+            slot,
+            outcome: outcome as u8,
+            error_class: error_class as u8,
+            _padding: [0; 6],
+            additional_metadata,
+        }
+    }
+
+    /// None if the outcome is unknown to this version
+    #[inline(always)]
+    pub fn outcome(&self) -> Option<Outcome> {
+        Outcome::from_u8(self.outcome)
+    }
+
+    #[inline(always)]
+    pub fn error_class(&self) -> ErrorClass {
+        ErrorClass::from_u8(self.error_class)
+    }
+
+    /// Fee charged per nanosecond of execution. Transactions that were
+    /// not executed, or whose execution time was not reported, earned
+    /// nothing for the block.
+    #[inline(always)]
+    pub fn value(&self) -> f64 {
+        match self.outcome() {
+            Some(outcome)
+                if outcome.executed() && self.execution_nanos > 0 =>
+            {
+                self.fee_charged as f64 / self.execution_nanos as f64
+            }
+            _ => 0.0,
+        }
+    }
+
     #[inline(always)]
     pub fn as_bytes(&self) -> &[u8] {
         // SAFETY:
@@ -37,7 +197,8 @@ impl<A: Pod + AnyBitPattern> QoSRemainingMeta<A> {
         }
     }
 
-    /// This assumes size and alignment are correct!
+    /// # Safety
+    /// `bytes` must be at least [Self::SIZE] long and 8 byte aligned
     #[inline(always)]
     pub unsafe fn from_bytes_unchecked(bytes: &[u8]) -> &Self {
         // SAFETY:
@@ -50,11 +211,15 @@ impl<A: Pod + AnyBitPattern> QoSRemainingMeta<A> {
 #[test]
 fn round_trip_several_variants() {
     // Unit
-    let remaining_meta = QoSRemainingMeta {
-        packet_hash: 3_u64,
-        execution_nanos: 123,
-        additional_metadata: (),
-    };
+    let remaining_meta = QoSRemainingMeta::executed(
+        3_u64,
+        123,
+        300,
+        5000,
+        42,
+        ErrorClass::None,
+        (),
+    );
     assert_eq!(&remaining_meta, unsafe {
         QoSRemainingMeta::from_bytes_unchecked(
             remaining_meta.as_bytes(),
@@ -62,12 +227,12 @@ fn round_trip_several_variants() {
     });
 
     // Primitive
-    let remaining_meta = QoSRemainingMeta {
-        packet_hash: 3_u64,
-
-        execution_nanos: 123,
-        additional_metadata: 0x69_u64,
-    };
+    let remaining_meta = QoSRemainingMeta::not_executed(
+        3_u64,
+        Outcome::Expired,
+        42,
+        0x69_u64,
+    );
     assert_eq!(&remaining_meta, unsafe {
         QoSRemainingMeta::from_bytes_unchecked(
             remaining_meta.as_bytes(),
@@ -81,17 +246,64 @@ fn round_trip_several_variants() {
         foo: u64,
         bar: u16,
     }
-    let remaining_meta = QoSRemainingMeta {
-        packet_hash: 3_u64,
-        execution_nanos: 123,
-        additional_metadata: MyType {
+    let remaining_meta = QoSRemainingMeta::executed(
+        3_u64,
+        123,
+        300,
+        5000,
+        42,
+        ErrorClass::InstructionError,
+        MyType {
             foo: 0x69_u64,
             bar: 0x420_u16,
         },
-    };
+    );
     assert_eq!(&remaining_meta, unsafe {
         QoSRemainingMeta::from_bytes_unchecked(
             remaining_meta.as_bytes(),
         )
     });
 }
+
+#[test]
+fn outcome_and_value() {
+    let succeeded = QoSRemainingMeta::executed(
+        3_u64,
+        1000,
+        300,
+        5000,
+        42,
+        ErrorClass::None,
+        (),
+    );
+    assert_eq!(succeeded.outcome(), Some(Outcome::Succeeded));
+    assert_eq!(succeeded.value(), 5.0);
+
+    // Failed transactions still pay their fee
+    let failed = QoSRemainingMeta::executed(
+        3_u64,
+        1000,
+        300,
+        5000,
+        42,
+        ErrorClass::InsufficientFunds,
+        (),
+    );
+    assert_eq!(failed.outcome(), Some(Outcome::Failed));
+    assert_eq!(failed.error_class(), ErrorClass::InsufficientFunds);
+    assert_eq!(failed.value(), 5.0);
+
+    let not_scheduled = QoSRemainingMeta::not_executed(
+        3_u64,
+        Outcome::NotScheduled,
+        42,
+        (),
+    );
+    assert_eq!(not_scheduled.value(), 0.0);
+
+    // Unknown outcomes, e.g. from a newer scheduler, are worth nothing
+    let mut unknown = succeeded;
+    unknown.outcome = 200;
+    assert_eq!(unknown.outcome(), None);
+    assert_eq!(unknown.value(), 0.0);
+}
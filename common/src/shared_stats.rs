@@ -1,54 +1,265 @@
-//! The locks used in here are not robust and are only used for demonstrative purposes.
-//! Do not reuse!
+//! Stats shared with other processes, e.g. a dashboard, through a
+//! seqlock. The writer never waits on readers, so a slow or crashed
+//! reader can not stall the writer.
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{
+    cell::UnsafeCell,
+    mem::size_of,
+    sync::atomic::{
+        fence, AtomicU32, AtomicU64, AtomicUsize, Ordering,
+    },
+};
 
+const MAGIC: u64 = u64::from_le_bytes(*b"QOS-STAT");
+
+/// The low half of the magic while a writer initializes the region,
+/// with the pid of the writer in the high half
+const CLAIM_TAG: u32 = u32::from_le_bytes(*b"QOSI");
+
+/// Bumped whenever the layout of [SharedStats] or [Stats] changes
+pub const LAYOUT_VERSION: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsLayoutError {
+    /// The region does not hold stats
+    BadMagic,
+
+    /// The region was written by an incompatible version
+    Version { found: u32, expected: u32 },
+
+    /// The region holds stats of a different size
+    Size { found: u32, expected: u32 },
+
+    /// A writer is initializing the region, or crashed while doing so.
+    /// `owner` is its pid.
+    Initializing { owner: u32 },
+}
+
+/// A snapshot of `T` with a single writer and any number of readers.
+///
+/// The writer bumps `seq` to odd before writing and back to even
+/// after. Readers retry until they read the same even `seq` before and
+/// after copying the snapshot.
 #[repr(C, align(128))]
-pub struct SharedStats {
-    pub lock: Lock,
-    pub stats: Stats,
-    pub should_reset: u64,
+pub struct SharedStats<T> {
+    magic: AtomicU64,
+    version: AtomicU32,
+    size: AtomicU32,
+
+    seq: AtomicU64,
+
+    /// Set by readers to ask the writer to start counting from zero
+    should_reset: AtomicU64,
+
+    stats: UnsafeCell<T>,
 }
 
-impl SharedStats {
-    pub unsafe fn reset(shared_stats: *mut u8) {
-        let shared_stats: *mut SharedStats = shared_stats.cast();
-        (*shared_stats).lock.take();
-        (*shared_stats).should_reset = 1;
-        (*shared_stats).stats = Stats::default();
-        (*shared_stats).lock.release();
+unsafe impl<T: Copy + Send> Sync for SharedStats<T> {}
+
+impl<T: Copy + Default> Default for SharedStats<T> {
+    fn default() -> SharedStats<T> {
+        SharedStats::new()
+    }
+}
+
+impl<T: Copy + Default> SharedStats<T> {
+    /// Bytes required by [SharedStats::join_or_initialize]
+    pub const SIZE: usize = size_of::<Self>();
+
+    /// Stats shared between threads of one process
+    pub fn new() -> SharedStats<T> {
+        SharedStats {
+            magic: AtomicU64::new(MAGIC),
+            version: AtomicU32::new(LAYOUT_VERSION),
+            size: AtomicU32::new(Self::SIZE as u32),
+            seq: AtomicU64::new(0),
+            should_reset: AtomicU64::new(0),
+            stats: UnsafeCell::new(T::default()),
+        }
     }
 
-    pub unsafe fn read(shared_stats: *mut u8) -> Stats {
-        let shared_stats: *mut SharedStats = shared_stats.cast();
-        (*shared_stats).lock.take();
-        let stats = (*shared_stats).stats.clone();
-        (*shared_stats).lock.release();
+    /// Attaches to stats previously initialized in `ptr` like
+    /// [SharedStats::join], or initializes them if there are none or
+    /// they are incompatible. Only for the writer, as initializing
+    /// rewrites the region. Readers [SharedStats::join].
+    ///
+    /// The region is claimed with a compare-and-swap of its magic
+    /// before it is initialized, so that of several writers racing to
+    /// initialize it one does and the others join again. A claim left
+    /// by a writer that is not `is_running` is taken over.
+    ///
+    /// Fails with the pid of the running writer initializing the
+    /// region.
+    ///
+    /// # Safety
+    /// `ptr` must be valid for [SharedStats::SIZE] bytes, aligned to
+    /// 128 bytes and outlive the returned reference. `T` must not
+    /// contain pointers.
+    pub unsafe fn join_or_initialize<'a>(
+        ptr: *mut u8,
+        is_running: impl Fn(u32) -> bool,
+    ) -> Result<&'a SharedStats<T>, u32> {
+        assert_eq!(ptr.align_offset(128), 0, "misaligned SharedStats");
+
+        let magic = &*ptr.cast::<AtomicU64>();
+        loop {
+            let observed = magic.load(Ordering::Acquire);
+            match SharedStats::join(ptr) {
+                Ok(stats) => return Ok(stats),
+                Err(StatsLayoutError::Initializing { owner })
+                    if is_running(owner) =>
+                {
+                    return Err(owner)
+                }
+                Err(_) => {}
+            }
+
+            // Another writer may have claimed the region since
+            if magic
+                .compare_exchange(
+                    observed,
+                    claim(std::process::id()),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_ok()
+            {
+                return Ok(SharedStats::initialize_in(ptr));
+            }
+        }
+    }
+
+    /// Attaches to stats initialized in `ptr` by the writer. Never
+    /// writes to the region.
+    ///
+    /// # Safety
+    /// Same as [SharedStats::join_or_initialize]
+    pub unsafe fn join<'a>(
+        ptr: *mut u8,
+    ) -> Result<&'a SharedStats<T>, StatsLayoutError> {
+        assert_eq!(ptr.align_offset(128), 0, "misaligned SharedStats");
+
+        let stats = &*ptr.cast::<SharedStats<T>>();
+        let magic = stats.magic.load(Ordering::Acquire);
+        if magic as u32 == CLAIM_TAG {
+            return Err(StatsLayoutError::Initializing {
+                owner: (magic >> 32) as u32,
+            });
+        }
+        if magic != MAGIC {
+            return Err(StatsLayoutError::BadMagic);
+        }
+        let version = stats.version.load(Ordering::Relaxed);
+        if version != LAYOUT_VERSION {
+            return Err(StatsLayoutError::Version {
+                found: version,
+                expected: LAYOUT_VERSION,
+            });
+        }
+        let size = stats.size.load(Ordering::Relaxed);
+        if size != Self::SIZE as u32 {
+            return Err(StatsLayoutError::Size {
+                found: size,
+                expected: Self::SIZE as u32,
+            });
+        }
+
+        Ok(stats)
+    }
+
+    /// Writes zeroed stats to `ptr`, claimed by this process
+    ///
+    /// # Safety
+    /// Same as [SharedStats::join_or_initialize]
+    unsafe fn initialize_in<'a>(ptr: *mut u8) -> &'a SharedStats<T> {
+        ptr.cast::<SharedStats<T>>()
+            .write(SharedStats {
+                magic: AtomicU64::new(claim(std::process::id())),
+                ..SharedStats::new()
+            });
+        let stats = &*ptr.cast::<SharedStats<T>>();
+
+        // Publish
         stats
+            .magic
+            .store(MAGIC, Ordering::Release);
+        stats
+    }
+
+    /// Publishes a snapshot. Only one writer may publish.
+    ///
+    /// Returns whether a reader asked for a reset, in which case zeroed
+    /// stats are published instead and the writer should reset its
+    /// own.
+    pub fn publish(&self, stats: &T) -> bool {
+        let should_reset = self
+            .should_reset
+            .swap(0, Ordering::Acquire)
+            != 0;
+        let stats = if should_reset { T::default() } else { *stats };
+
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq
+            .store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe { self.stats.get().write_volatile(stats) };
+        self.seq
+            .store(seq.wrapping_add(2), Ordering::Release);
+
+        should_reset
     }
 
-    pub unsafe fn update(shared_stats: *mut u8, stats: &Stats) -> bool {
-        let shared_stats: *mut SharedStats = shared_stats.cast();
-        (*shared_stats).lock.take();
-        let should_reset = (*shared_stats).should_reset;
-        if should_reset == 0 {
-            (*shared_stats).stats = stats.clone();
+    /// Returns the latest snapshot, or None if the writer is midway
+    /// through publishing one
+    pub fn try_read(&self) -> Option<T> {
+        let before = self.seq.load(Ordering::Acquire);
+        if before % 2 == 1 {
+            return None;
         }
-        (*shared_stats).should_reset = 0;
-        (*shared_stats).lock.release();
-        should_reset != 0
+        let stats = unsafe { self.stats.get().read_volatile() };
+        fence(Ordering::Acquire);
+        let after = self.seq.load(Ordering::Relaxed);
+
+        (before == after).then_some(stats)
     }
+
+    /// Returns the latest snapshot, spinning while the writer is
+    /// publishing one
+    pub fn read(&self) -> T {
+        loop {
+            if let Some(stats) = self.try_read() {
+                return stats;
+            }
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Asks the writer to start counting from zero
+    pub fn request_reset(&self) {
+        self.should_reset
+            .store(1, Ordering::Release);
+    }
+}
+
+/// The magic of a region claimed by `owner`
+fn claim(owner: u32) -> u64 {
+    (owner as u64) << 32 | CLAIM_TAG as u64
 }
 
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, Copy)]
 #[repr(C, align(128))]
 pub struct Stats {
     pub total_packets: usize,
     pub non_ipv4: usize,
     pub non_transaction_packet: usize,
     pub recently_processed: usize,
+    /// Recently processed drops by slots since the signature was
+    /// processed: under 4, under 32 and up to the blockhash window
+    pub recently_processed_by_age: [usize; 3],
     pub recently_processed_queued: usize,
+    pub rescored_queued: usize,
     pub recent_signatures_received: usize,
+    pub recent_signatures_expired: usize,
     pub invalid_meta_size: usize,
     pub failed_sanitize: usize,
     pub failed_view: usize,
@@ -58,44 +269,74 @@ pub struct Stats {
     pub banking_transmissions: usize,
     pub zero_score: usize,
     pub completed: usize,
+    pub expired_transmitted: usize,
+    pub expired_untransmitted: usize,
+    pub model_updates: usize,
+    /// Total time spent updating the model
+    pub model_update_us: usize,
 }
 
 impl Stats {
     pub fn new() -> Stats {
         Default::default()
     }
-}
 
-#[repr(C, align(128))]
-pub struct Lock {
-    lock: AtomicUsize,
-}
+    /// Adds `other` to these counters. Destructured so that new
+    /// counters are not missed.
+    pub fn add(&mut self, other: &Stats) {
+        let Stats {
+            total_packets,
+            non_ipv4,
+            non_transaction_packet,
+            recently_processed,
+            recently_processed_by_age,
+            recently_processed_queued,
+            rescored_queued,
+            recent_signatures_received,
+            recent_signatures_expired,
+            invalid_meta_size,
+            failed_sanitize,
+            failed_view,
+            invalid_packet_data,
+            leaked_priority,
+            duplicate_packets,
+            banking_transmissions,
+            zero_score,
+            completed,
+            expired_transmitted,
+            expired_untransmitted,
+            model_updates,
+            model_update_us,
+        } = *other;
 
-impl Lock {
-    fn take(&self) {
-        while self
-            .lock
-            .compare_exchange(
-                0,
-                1,
-                Ordering::Release,
-                Ordering::Acquire,
-            )
-            .is_err()
-        {}
-    }
-
-    fn release(&self) {
-        while self
-            .lock
-            .compare_exchange(
-                1,
-                0,
-                Ordering::Release,
-                Ordering::Acquire,
-            )
-            .is_err()
-        {}
+        self.total_packets += total_packets;
+        self.non_ipv4 += non_ipv4;
+        self.non_transaction_packet += non_transaction_packet;
+        self.recently_processed += recently_processed;
+        self.recently_processed_queued += recently_processed_queued;
+        self.rescored_queued += rescored_queued;
+        self.recent_signatures_received += recent_signatures_received;
+        self.recent_signatures_expired += recent_signatures_expired;
+        self.invalid_meta_size += invalid_meta_size;
+        self.failed_sanitize += failed_sanitize;
+        self.failed_view += failed_view;
+        self.invalid_packet_data += invalid_packet_data;
+        self.leaked_priority += leaked_priority;
+        self.duplicate_packets += duplicate_packets;
+        self.banking_transmissions += banking_transmissions;
+        self.zero_score += zero_score;
+        self.completed += completed;
+        self.expired_transmitted += expired_transmitted;
+        self.expired_untransmitted += expired_untransmitted;
+        self.model_updates += model_updates;
+        self.model_update_us += model_update_us;
+        for (total, count) in self
+            .recently_processed_by_age
+            .iter_mut()
+            .zip(recently_processed_by_age)
+        {
+            *total += count;
+        }
     }
 }
 
@@ -126,3 +367,156 @@ impl EngineStats {
         ]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    use super::*;
+
+    #[repr(C, align(128))]
+    struct Region([u8; SharedStats::<Stats>::SIZE]);
+
+    fn region() -> Box<Region> {
+        Box::new(Region([0; SharedStats::<Stats>::SIZE]))
+    }
+
+    #[test]
+    fn test_publish_read_and_reset() {
+        let mut region = region();
+        let ptr = region.0.as_mut_ptr();
+        assert_eq!(
+            unsafe { SharedStats::<Stats>::join(ptr) }.err(),
+            Some(StatsLayoutError::BadMagic)
+        );
+
+        let writer = unsafe {
+            SharedStats::<Stats>::join_or_initialize(ptr, |_| true)
+        }
+        .unwrap();
+        let reader =
+            unsafe { SharedStats::<Stats>::join(ptr) }.unwrap();
+
+        let stats = Stats {
+            total_packets: 42,
+            ..Stats::default()
+        };
+        assert!(!writer.publish(&stats));
+        assert_eq!(reader.read().total_packets, 42);
+
+        reader.request_reset();
+        assert!(writer.publish(&stats));
+        assert_eq!(reader.read().total_packets, 0);
+        assert!(!writer.publish(&stats));
+        assert_eq!(reader.read().total_packets, 42);
+    }
+
+    #[test]
+    fn test_add() {
+        let mut total = Stats {
+            total_packets: 1,
+            recently_processed_by_age: [1, 2, 3],
+            ..Stats::default()
+        };
+        total.add(&Stats {
+            total_packets: 2,
+            model_update_us: 5,
+            recently_processed_by_age: [0, 1, 0],
+            ..Stats::default()
+        });
+
+        assert_eq!(total.total_packets, 3);
+        assert_eq!(total.model_update_us, 5);
+        assert_eq!(total.recently_processed_by_age, [1, 3, 3]);
+    }
+
+    #[test]
+    fn test_rejects_other_layouts() {
+        let mut region = region();
+        let ptr = region.0.as_mut_ptr();
+        let writer = unsafe {
+            SharedStats::<Stats>::join_or_initialize(ptr, |_| true)
+        }
+        .unwrap();
+        writer.publish(&Stats {
+            total_packets: 42,
+            ..Stats::default()
+        });
+
+        // A reader of another layout fails without touching the stats
+        assert!(matches!(
+            unsafe { SharedStats::<[u64; 2]>::join(ptr) },
+            Err(StatsLayoutError::Size { .. })
+        ));
+        assert_eq!(writer.read().total_packets, 42);
+    }
+
+    #[test]
+    fn test_join_or_initialize_claims() {
+        let mut region = region();
+        let ptr = region.0.as_mut_ptr();
+        let magic = unsafe { &*ptr.cast::<AtomicU64>() };
+
+        // Another writer is initializing the region, or crashed while
+        // doing so
+        magic.store(claim(7), Ordering::Release);
+        assert_eq!(
+            unsafe { SharedStats::<Stats>::join(ptr) }.err(),
+            Some(StatsLayoutError::Initializing { owner: 7 })
+        );
+        assert_eq!(
+            unsafe {
+                SharedStats::<Stats>::join_or_initialize(ptr, |_| true)
+            }
+            .err(),
+            Some(7)
+        );
+
+        // A crashed writer's claim is taken over
+        let writer = unsafe {
+            SharedStats::<Stats>::join_or_initialize(ptr, |_| false)
+        }
+        .unwrap();
+        writer.publish(&Stats {
+            total_packets: 42,
+            ..Stats::default()
+        });
+        assert_eq!(magic.load(Ordering::Acquire), MAGIC);
+
+        // A restarted writer joins the published stats
+        let writer = unsafe {
+            SharedStats::<Stats>::join_or_initialize(ptr, |_| true)
+        }
+        .unwrap();
+        assert_eq!(writer.read().total_packets, 42);
+    }
+
+    #[test]
+    fn test_reads_are_consistent() {
+        #[derive(Default, Clone, Copy)]
+        struct Pair {
+            a: u64,
+            b: u64,
+        }
+
+        let shared = Arc::new(SharedStats::<Pair>::new());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let reader = {
+            let shared = shared.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    let pair = shared.read();
+                    assert_eq!(pair.a, pair.b);
+                }
+            })
+        };
+
+        for i in 0..100_000 {
+            shared.publish(&Pair { a: i, b: i });
+        }
+        done.store(true, Ordering::Relaxed);
+        reader.join().unwrap();
+    }
+}
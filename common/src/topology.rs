@@ -0,0 +1,345 @@
+//! Declarative description of the channels qos joins, read from a toml
+//! file. See `topology.toml` at the root of the repository.
+//!
+//! Channel capacities are not configurable here. They are compile time
+//! parameters of `que`, fixed in [crate::ipc_parameters]. A topology may
+//! declare them anyway, but they are only validated against the compiled
+//! ones and never change the size of a channel.
+
+use std::{collections::HashSet, path::Path};
+
+use serde::Deserialize;
+
+use crate::ipc_parameters::*;
+
+/// Channel names, sources and weights. Capacities are fixed at build
+/// time and declared ones are only checked when loading.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Topology {
+    /// Packet channels into qos, popped fairly by weight
+    pub inputs: Vec<InputChannel>,
+
+    pub feedback: FeedbackChannels,
+
+    pub output: OutputChannels,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InputChannel {
+    pub name: String,
+
+    /// Which ingress path this channel carries. Several channels may
+    /// share a source, e.g. a third relayer.
+    pub source: InputSource,
+
+    /// Relative share of pops when all inputs are busy
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+
+    /// Only validated against the compiled capacity, never applied
+    #[serde(default)]
+    pub capacity: Option<usize>,
+}
+
+/// RE1 and RE2 are relayer 1 (TPU) and relayer 2 (TPU FWD)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputSource {
+    Tpu,
+    Fwd,
+    Re1,
+    Re2,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeedbackChannels {
+    /// Packets that failed sigverify
+    pub sigverify: Channel,
+
+    /// Remaining metas of scheduled transactions
+    pub scheduler: Channel,
+
+    /// Recently confirmed signatures. Optional.
+    #[serde(default)]
+    pub status_cache: Option<Channel>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputChannels {
+    /// Prioritized packets to be sigverified
+    pub sigverify: Channel,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Channel {
+    pub name: String,
+
+    /// Only validated against the compiled capacity, never applied
+    #[serde(default)]
+    pub capacity: Option<usize>,
+}
+
+/// What a channel is used for, which determines its message type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelRole {
+    Input,
+    SigverifyFeedback,
+    SchedulerFeedback,
+    StatusCache,
+    SigverifyOutput,
+}
+
+impl ChannelRole {
+    /// The capacity of channels with this role, fixed at build time
+    pub fn capacity(self) -> usize {
+        match self {
+            ChannelRole::Input => IPC_TPU_TO_QOS_CAP,
+            ChannelRole::SigverifyFeedback => IPC_SIG_TO_QOS_CAP,
+            ChannelRole::SchedulerFeedback => IPC_SCH_TO_QOS_CAP,
+            ChannelRole::StatusCache => IPC_STATUS_CACHE_CAP,
+            ChannelRole::SigverifyOutput => IPC_QOS_TO_SIG_CAP,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TopologyError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl Topology {
+    pub fn load(
+        path: impl AsRef<Path>,
+    ) -> Result<Topology, TopologyError> {
+        let toml =
+            std::fs::read_to_string(path).map_err(TopologyError::Io)?;
+        Topology::from_toml(&toml)
+    }
+
+    pub fn from_toml(toml: &str) -> Result<Topology, TopologyError> {
+        let topology: Topology =
+            toml::from_str(toml).map_err(TopologyError::Parse)?;
+        topology.validate()?;
+        Ok(topology)
+    }
+
+    /// Every channel with its role and name
+    pub fn channels(
+        &self,
+    ) -> impl Iterator<Item = (ChannelRole, &str)> + '_ {
+        self.declared()
+            .map(|(role, name, _capacity)| (role, name))
+    }
+
+    /// Every channel with its role, name and declared capacity
+    fn declared(
+        &self,
+    ) -> impl Iterator<Item = (ChannelRole, &str, Option<usize>)> + '_
+    {
+        let inputs = self.inputs.iter().map(|input| {
+            (ChannelRole::Input, input.name.as_str(), input.capacity)
+        });
+        let others = [
+            (
+                ChannelRole::SigverifyFeedback,
+                Some(&self.feedback.sigverify),
+            ),
+            (
+                ChannelRole::SchedulerFeedback,
+                Some(&self.feedback.scheduler),
+            ),
+            (
+                ChannelRole::StatusCache,
+                self.feedback.status_cache.as_ref(),
+            ),
+            (
+                ChannelRole::SigverifyOutput,
+                Some(&self.output.sigverify),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(role, channel)| {
+            channel.map(|c| (role, c.name.as_str(), c.capacity))
+        });
+
+        inputs.chain(others)
+    }
+
+    fn validate(&self) -> Result<(), TopologyError> {
+        if self.inputs.is_empty() {
+            return Err(TopologyError::Invalid(
+                "at least one input is required".to_string(),
+            ));
+        }
+
+        if let Some(input) = self
+            .inputs
+            .iter()
+            .find(|i| i.weight == 0)
+        {
+            return Err(TopologyError::Invalid(format!(
+                "input {} has zero weight",
+                input.name
+            )));
+        }
+
+        let mut names = HashSet::new();
+        for (role, name, capacity) in self.declared() {
+            if !names.insert(name) {
+                return Err(TopologyError::Invalid(format!(
+                    "channel {name} is declared more than once"
+                )));
+            }
+
+            if let Some(capacity) = capacity {
+                if capacity != role.capacity() {
+                    return Err(TopologyError::Invalid(format!(
+                        "channel {name} has capacity {capacity}, but \
+                         {} was compiled in",
+                        role.capacity()
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The fixed topology qos used before topologies were configurable
+impl Default for Topology {
+    fn default() -> Topology {
+        let input = |name: &str, source| InputChannel {
+            name: name.to_string(),
+            source,
+            weight: default_weight(),
+            capacity: None,
+        };
+        let channel = |name: &str| Channel {
+            name: name.to_string(),
+            capacity: None,
+        };
+
+        Topology {
+            inputs: vec![
+                input(IPC_TPU_TO_QOS_NAME, InputSource::Tpu),
+                input(IPC_FWD_TO_QOS_NAME, InputSource::Fwd),
+                input(IPC_RE1_TO_QOS_NAME, InputSource::Re1),
+                input(IPC_RE2_TO_QOS_NAME, InputSource::Re2),
+            ],
+            feedback: FeedbackChannels {
+                sigverify: channel(IPC_SIG_TO_QOS_NAME),
+                scheduler: channel(IPC_SCH_TO_QOS_NAME),
+                status_cache: Some(channel(IPC_STATUS_CACHE_NAME)),
+            },
+            output: OutputChannels {
+                sigverify: channel(IPC_QOS_TO_SIG_NAME),
+            },
+        }
+    }
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repo_topology_is_default() {
+        let topology =
+            Topology::from_toml(include_str!("../../topology.toml"))
+                .unwrap();
+        assert_eq!(topology, Topology::default());
+    }
+
+    #[test]
+    fn test_without_relayer_or_status_cache() {
+        let topology = Topology::from_toml(
+            r#"
+            [[inputs]]
+            name = "tpu_to_qos"
+            source = "tpu"
+            weight = 3
+
+            [[inputs]]
+            name = "fwd_to_qos"
+            source = "fwd"
+
+            [feedback]
+            sigverify = { name = "sig_to_qos" }
+            scheduler = { name = "sch_to_qos" }
+
+            [output]
+            sigverify = { name = "qos_to_sig" }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(topology.inputs.len(), 2);
+        assert_eq!(topology.inputs[0].weight, 3);
+        assert_eq!(topology.inputs[1].weight, 1);
+        assert_eq!(topology.feedback.status_cache, None);
+        assert_eq!(topology.channels().count(), 5);
+    }
+
+    #[test]
+    fn test_rejects_invalid() {
+        let mut toml = include_str!("../../topology.toml").to_string();
+        toml = toml.replacen("fwd_to_qos", "tpu_to_qos", 1);
+        assert!(matches!(
+            Topology::from_toml(&toml),
+            Err(TopologyError::Invalid(_))
+        ));
+
+        let toml = include_str!("../../topology.toml")
+            .replacen("\"tpu\"", "\"tpu2\"", 1);
+        assert!(matches!(
+            Topology::from_toml(&toml),
+            Err(TopologyError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_capacity_must_match_compiled() {
+        let with_capacity = |capacity: usize| {
+            include_str!("../../topology.toml").replacen(
+                "source = \"tpu\"",
+                &format!("source = \"tpu\"\ncapacity = {capacity}"),
+                1,
+            )
+        };
+
+        let topology =
+            Topology::from_toml(&with_capacity(IPC_TPU_TO_QOS_CAP))
+                .unwrap();
+        assert_eq!(
+            topology.inputs[0].capacity,
+            Some(IPC_TPU_TO_QOS_CAP)
+        );
+
+        assert!(matches!(
+            Topology::from_toml(&with_capacity(2 * IPC_TPU_TO_QOS_CAP)),
+            Err(TopologyError::Invalid(_))
+        ));
+
+        let toml = include_str!("../../topology.toml").replacen(
+            "{ name = \"sch_to_qos\" }",
+            "{ name = \"sch_to_qos\", capacity = 1024 }",
+            1,
+        );
+        assert!(matches!(
+            Topology::from_toml(&toml),
+            Err(TopologyError::Invalid(_))
+        ));
+    }
+}
@@ -2,8 +2,10 @@ use std::{
     fs::File,
     io::{BufWriter, Write},
     path::Path,
+    ptr::NonNull,
     sync::atomic::{AtomicBool, Ordering},
     thread::Builder,
+    time::Instant,
 };
 
 use agave_transaction_view::transaction_view::TransactionView;
@@ -15,6 +17,7 @@ use que::{
     shmem::Shmem,
 };
 use solana_qos_common::{
+    channel_header::{ChannelHeader, ChannelLayout, ChannelType},
     checked_drop_privileges,
     ipc_parameters::{
         IPC_FWD_TO_QOS_CAP, IPC_FWD_TO_QOS_NAME, IPC_QOS_TO_SIG_CAP,
@@ -23,7 +26,9 @@ use solana_qos_common::{
         IPC_STATUS_CACHE_NAME, IPC_TPU_TO_QOS_CAP, IPC_TPU_TO_QOS_NAME,
     },
     packet_bytes::PacketBytes,
-    remaining_meta::QoSRemainingMeta,
+    recent_signature::RecentSignature,
+    remaining_meta::{ErrorClass, Outcome, QoSRemainingMeta},
+    shared_stats::{SharedStats, Stats},
     xxhash::xxHasher,
 };
 use solana_qos_core::{get_page_size, sig_bytes};
@@ -78,14 +83,23 @@ fn main() {
     // Join recent sig cache as producer
     let page_size = get_page_size(args.use_huge_pages);
     let mut recent_signatures = unsafe {
-        Producer::<[u8; 64], IPC_STATUS_CACHE_CAP>::join_or_create_shmem(IPC_STATUS_CACHE_NAME, page_size)
+        Producer::<RecentSignature, IPC_STATUS_CACHE_CAP>::join_or_create_shmem(IPC_STATUS_CACHE_NAME, page_size)
             .unwrap()
     };
 
+    handshake::<RecentSignature, _>(
+        IPC_STATUS_CACHE_NAME,
+        recent_signatures.get_padding_ptr(),
+        ChannelType::Signatures,
+    );
+
     // Joined shared stats shmem
-    let shared_stats =
-        Shmem::open_or_create("qos_stats", 2048, PageSize::Standard)
-            .unwrap();
+    let shared_stats = Shmem::open_or_create(
+        "qos_stats",
+        SharedStats::<Stats>::SIZE as i64,
+        PageSize::Standard,
+    )
+    .unwrap();
 
     // QoS -> Sigverify Consumer
     let mut banking_consumer = unsafe {
@@ -96,6 +110,12 @@ fn main() {
         .unwrap()
     };
 
+    handshake::<PacketBytes, _>(
+        IPC_QOS_TO_SIG_NAME,
+        banking_consumer.get_padding_ptr(),
+        ChannelType::Packets,
+    );
+
     // Sigverify -> QoS Producer
     let mut sig_qos_producer = unsafe {
         Producer::<
@@ -108,6 +128,12 @@ fn main() {
         .unwrap()
     };
 
+    handshake::<PacketBytes, _>(
+        IPC_SIG_TO_QOS_NAME,
+        sig_qos_producer.get_padding_ptr(),
+        ChannelType::Packets,
+    );
+
     // Scheduler -> QoS Producer
     let mut sch_qos_producer = unsafe {
         Producer::<
@@ -120,6 +146,12 @@ fn main() {
     .unwrap()
     };
 
+    handshake::<QoSRemainingMeta<()>, _>(
+        IPC_SCH_TO_QOS_NAME,
+        sch_qos_producer.get_padding_ptr(),
+        ChannelType::RemainingMeta,
+    );
+
     // Remove sudo privileges
     if let Err(e) = checked_drop_privileges() {
         panic!("{e:?}");
@@ -166,6 +198,9 @@ fn main() {
                 )
             });
 
+            // The mock has no bank. Slots advance every 400ms.
+            let start = Instant::now();
+
             let mut i = 0_usize;
             loop {
                 if let Some(packet_bytes) = banking_consumer.pop() {
@@ -197,33 +232,60 @@ fn main() {
 
                         // Passed sigverify, randomly assign
                         // scheduled status
-                        let mut remaining_meta = QoSRemainingMeta {
-                            packet_hash,
-                            execution_nanos: 0,
-                            additional_metadata: (),
-                        };
+                        let slot =
+                            start.elapsed().as_millis() as u64 / 400;
                         match sch {
                             0..192 => {
-                                // not scheduled, execution nanos
-                                // already 0
-                                sch_qos_producer.push(&remaining_meta);
+                                // not scheduled
+                                sch_qos_producer.push(
+                                    &QoSRemainingMeta::not_executed(
+                                        packet_hash,
+                                        Outcome::NotScheduled,
+                                        slot,
+                                        (),
+                                    ),
+                                );
                             }
                             _ => {
                                 // scheduled, assign random
                                 // execution time within 1us
                                 // Always adding 1 to modulo => always nonzero
-                                remaining_meta.execution_nanos =
-                                    (packet_hash
-                                        & const { 1024 * 1024 - 1 })
-                                        + 1;
-
-                                sch_qos_producer.push(&remaining_meta);
+                                let execution_nanos = (packet_hash
+                                    & const { 1024 * 1024 - 1 })
+                                    + 1;
+                                // base fee per signature
+                                let fee_charged = 5000
+                                    * tx_view.signatures().len() as u64;
+                                // one in 16 scheduled transactions fails
+                                let error_class = if sch < 196 {
+                                    ErrorClass::InstructionError
+                                } else {
+                                    ErrorClass::None
+                                };
+
+                                // compute budget + system transfer
+                                sch_qos_producer.push(
+                                    &QoSRemainingMeta::executed(
+                                        packet_hash,
+                                        execution_nanos,
+                                        300,
+                                        fee_charged,
+                                        slot,
+                                        error_class,
+                                        (),
+                                    ),
+                                );
                                 sched += 1;
 
                                 // Send recent signature
-                                recent_signatures.push(sig_bytes(
-                                    &tx_view.signatures()[0],
-                                ));
+                                recent_signatures.push(
+                                    &RecentSignature::new(
+                                        slot,
+                                        *sig_bytes(
+                                            &tx_view.signatures()[0],
+                                        ),
+                                    ),
+                                );
                                 sch_qos_producer.sync();
                                 recent_signatures.sync();
                             }
@@ -278,3 +340,17 @@ fn main() {
     mock_fwd.join().unwrap();
     mock_banking.join().unwrap();
 }
+
+/// Panics if the other end of the channel expects a different layout
+fn handshake<T, P>(
+    name: &str,
+    padding: NonNull<P>,
+    channel_type: ChannelType,
+) {
+    let header = unsafe { ChannelHeader::from_padding(padding) };
+    if let Err(e) =
+        header.stamp_or_verify(ChannelLayout::of::<T>(channel_type))
+    {
+        panic!("incompatible channel {name}: {e:?}");
+    }
+}
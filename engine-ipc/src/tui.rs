@@ -1,6 +1,8 @@
 use mock_tx_engine::STATS;
 use que::shmem::Shmem;
-use solana_qos_common::shared_stats::SharedStats;
+use solana_qos_common::shared_stats::{
+    SharedStats, Stats, StatsLayoutError,
+};
 use timer::Timer;
 
 pub struct FlowGraph;
@@ -18,7 +20,10 @@ impl FlowGraph {
 
 use std::time::Duration;
 
-use color_eyre::{eyre::Context, Result};
+use color_eyre::{
+    eyre::{bail, Context},
+    Result,
+};
 use ratatui::{
     crossterm::event::{self, Event, KeyCode},
     widgets::Paragraph,
@@ -29,8 +34,9 @@ fn run(
     mut terminal: DefaultTerminal,
     shared_stats: Shmem,
 ) -> Result<()> {
+    let qos_stats = join_stats(&shared_stats)?;
     // Reset to synchronize counter with engine timer
-    unsafe { SharedStats::reset(shared_stats.get_mut_ptr()) };
+    qos_stats.request_reset();
 
     Timer::memoize_ticks_per_ms_and_invariant_tsc_check();
     let timer = Timer::new();
@@ -38,17 +44,15 @@ fn run(
         let [tpu_sends, fwd_sends, sigverify_recvs, sigverify_sends, scheduler_sends] =
             STATS.load();
 
-        let draw_fn = unsafe {
-            draw(
-                timer.clone(),
-                tpu_sends,
-                fwd_sends,
-                sigverify_recvs,
-                sigverify_sends,
-                scheduler_sends,
-                shared_stats.get_mut_ptr(),
-            )
-        };
+        let draw_fn = draw(
+            timer.clone(),
+            tpu_sends,
+            fwd_sends,
+            sigverify_recvs,
+            sigverify_sends,
+            scheduler_sends,
+            qos_stats.read(),
+        );
         terminal.draw(draw_fn)?;
         if should_quit()? {
             break;
@@ -57,14 +61,31 @@ fn run(
     Ok(())
 }
 
-unsafe fn draw(
+/// Waits for qos to initialize its stats. Only qos writes the region, so
+/// stats of another layout are an error rather than reinitialized.
+fn join_stats(shared_stats: &Shmem) -> Result<&SharedStats<Stats>> {
+    loop {
+        match unsafe {
+            SharedStats::<Stats>::join(shared_stats.get_mut_ptr())
+        } {
+            Ok(stats) => return Ok(stats),
+            Err(
+                StatsLayoutError::BadMagic
+                | StatsLayoutError::Initializing { .. },
+            ) => std::thread::sleep(Duration::from_millis(100)),
+            Err(e) => bail!("incompatible qos stats: {e:?}"),
+        }
+    }
+}
+
+fn draw(
     timer: Timer,
     tpu_sends: usize,
     fwd_sends: usize,
     sigverify_recvs: usize,
     sigverify_sends: usize,
     scheduler_sends: usize,
-    shared_stats: *mut u8,
+    qos_stats: Stats,
 ) -> impl FnOnce(&mut Frame) {
     move |frame: &mut Frame| {
         let time = timer.elapsed_ms() as f64 / 1000.0;
@@ -81,7 +102,6 @@ unsafe fn draw(
             / time
             / 1e6;
 
-        let qos_stats = SharedStats::read(shared_stats);
         // All forms of dedup.
         // 1. we've seen twice (not yet processed, perhaps invalid or expired)
         // 2. recently processed sig
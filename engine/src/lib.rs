@@ -13,6 +13,9 @@ use rand::seq::SliceRandom;
 use rng::FastxxHashRng;
 use solana_qos_core::get_page_size;
 
+use solana_qos_common::channel_header::{
+    ChannelHeader, ChannelLayout, ChannelType, HandshakeError,
+};
 use solana_qos_common::packet_bytes::PacketBytes;
 use solana_qos_common::shared_stats::EngineStats;
 
@@ -49,6 +52,14 @@ impl<const N: usize> Engine<N> {
             #[cfg(target_os = "linux")]
             use_huge_pages,
         );
+        let spsc: Producer<PacketBytes, N> = unsafe {
+            Producer::join_or_create_shmem(shmem_id, page_size)
+        }?;
+        unsafe { ChannelHeader::from_padding(spsc.get_padding_ptr()) }
+            .stamp_or_verify(ChannelLayout::of::<PacketBytes>(
+                ChannelType::Packets,
+            ))?;
+
         Ok(Engine {
             consumer: initialize_generator_threads(
                 generators,
@@ -56,9 +67,7 @@ impl<const N: usize> Engine<N> {
                 shmem_id,
                 start,
             )?,
-            spsc: unsafe {
-                Producer::join_or_create_shmem(shmem_id, page_size)
-            }?,
+            spsc,
         })
     }
 
@@ -109,8 +118,8 @@ impl<const N: usize> Engine<N> {
 
 /// Spin up `generators` number of threads that generate signed/unsigned
 /// transactions according to `sign_probability`. Serializes and sends
-/// transactions as packets via a fast (unfair, non-fifo) wait-free mpsc
-/// queue.
+/// transactions as packets via a fast wait-free mpsc queue, popped
+/// round robin across generators.
 pub fn initialize_generator_threads(
     generators: usize,
     write_to_file: bool,
@@ -186,6 +195,7 @@ pub fn initialize_generator_threads(
 #[derive(Debug)]
 pub enum EngineError {
     QueError(QueError),
+    HandshakeError(HandshakeError),
 }
 
 impl From<QueError> for EngineError {
@@ -193,3 +203,9 @@ impl From<QueError> for EngineError {
         EngineError::QueError(value)
     }
 }
+
+impl From<HandshakeError> for EngineError {
+    fn from(value: HandshakeError) -> Self {
+        EngineError::HandshakeError(value)
+    }
+}
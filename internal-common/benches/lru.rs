@@ -1,7 +1,7 @@
 use std::num::NonZeroUsize;
 
 use criterion::{
-    criterion_group, criterion_main, Criterion, Throughput,
+    black_box, criterion_group, criterion_main, Criterion, Throughput,
 };
 use lru::LruCache;
 use nohash_hasher::BuildNoHashHasher;
@@ -56,6 +56,7 @@ fn custom(c: &mut Criterion) {
         u64,
         [u8; VALUE_SIZE],
         CAPACITY,
+        u64,
     >::new_boxed();
 
     // Fill the cache
@@ -89,8 +90,65 @@ fn custom(c: &mut Criterion) {
         },
     );
 
+    // Strided over the cache, so hits are spread over all entries
+    let mut j = 0_u64;
+    g.bench_function("lru-get-hit", |b| {
+        b.iter(|| {
+            let k = i - 1 - (j % CAPACITY as u64);
+            j = j.wrapping_add(7919);
+            black_box(
+                lru_cache
+                    .get(xxh3_64(&k.to_le_bytes()))
+                    .is_some(),
+            )
+        })
+    });
+
+    g.bench_function("lru-contains-miss", |b| {
+        b.iter(|| {
+            j += 1;
+            black_box(
+                lru_cache
+                    .contains(xxh3_64(&(u64::MAX - j).to_le_bytes())),
+            )
+        })
+    });
+
+    g.finish();
+}
+
+#[inline(never)]
+fn sharded(c: &mut Criterion) {
+    const SHARDS: usize = 16;
+    let lru_cache = qos_lru::ShardedLRUCache::<
+        u64,
+        [u8; VALUE_SIZE],
+        SHARDS,
+        { CAPACITY / SHARDS },
+    >::new();
+
+    // Fill the cache
+    let mut i = 0_u64;
+    while lru_cache.len() < CAPACITY {
+        lru_cache.put(xxh3_64(&i.to_le_bytes()), [0; VALUE_SIZE]);
+        i += 1;
+    }
+
+    let mut g = c.benchmark_group("cache");
+    // Measure throughput in max packet data (1264 bytes) per second
+    g.throughput(Throughput::Bytes(1264));
+
+    // Uncontended, so this measures locking and shard selection
+    // overhead over a single cache
+    g.bench_function("lru-sharded", |b| {
+        b.iter(|| {
+            lru_cache.put(xxh3_64(&i.to_le_bytes()), [0; VALUE_SIZE]);
+            i += 1;
+        })
+    });
+
     g.finish();
 }
 
-criterion_group!(lru, lru_crate, custom);
+criterion_group!(lru, lru_crate, custom, sharded);
 criterion_main!(lru);
@@ -2,5 +2,8 @@ pub mod packet_bytes;
 pub mod partial_meta;
 pub mod scored_transaction;
 pub mod signature_bytes;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+pub mod transaction_features;
 pub mod transaction_meta;
 pub mod xxhash;
@@ -1,54 +1,201 @@
-use std::net::Ipv4Addr;
-
-use super::transaction_meta::{QoSTransactionMeta, F64};
+use super::{
+    transaction_features::{TransactionFeatures, MAX_PROGRAM_IDS},
+    transaction_meta::{QoSTransactionMeta, F64},
+};
 use bytemuck::Pod;
-use solana_qos_common::remaining_meta::QoSRemainingMeta;
-use solana_sdk::pubkey::Pubkey;
+use solana_qos_common::remaining_meta::{Outcome, QoSRemainingMeta};
+
+// TODO: Fixed constant
+/// Execution time at which transactions that were not scheduled are
+/// valued
+pub const NOT_SCHEDULED_NANOS: u64 = 100_000;
 
-/// The subset of metadata available prior to sigverify and execution
+/// The subset of metadata available prior to sigverify and execution.
+///
+/// Lives in shared memory across restarts, so the layout is fixed and
+/// has no implicit padding. Bump [QoSPartialMeta::LAYOUT_VERSION] on
+/// any change.
+#[derive(Clone, Copy)]
+#[repr(C)]
 pub struct QoSPartialMeta {
-    pub ip: u32,
-    pub signer: [u8; 32],
     pub total_fee: u64,
+
+    /// Folded keys of the distinct invoked programs, used to train the
+    /// execution cost model once execution time is known
+    program_keys: [u64; MAX_PROGRAM_IDS],
+
+    pub ip: u32,
     pub cus: u32,
+    pub signer: [u8; 32],
+
+    /// Number of instructions in the transaction
+    pub num_instructions: u16,
+
+    num_program_keys: u8,
+
+    /// Whether the transaction was transmitted to sigverify. If not,
+    /// missing scheduler feedback says nothing about the sender.
+    pub transmitted: bool,
+
+    _padding: [u8; 4],
 }
 
 impl QoSPartialMeta {
+    pub const LAYOUT_VERSION: u32 = 1;
+
+    const _ASSERT_NO_PAD: () = assert!(
+        size_of::<u64>() * (1 + MAX_PROGRAM_IDS)
+            + size_of::<u32>() * 2
+            + 32
+            + size_of::<u16>()
+            + 2
+            + 4
+            == size_of::<Self>()
+    );
+
     #[inline(always)]
     pub fn new(
-        ip: &Ipv4Addr,
-        signer: &Pubkey,
+        features: &TransactionFeatures,
         total_fee: u64,
-        cus: u32,
     ) -> QoSPartialMeta {
+        let () = Self::_ASSERT_NO_PAD;
+
+        let mut program_keys = [0; MAX_PROGRAM_IDS];
+        let mut num_program_keys = 0;
+        for (slot, key) in program_keys
+            .iter_mut()
+            .zip(features.program_keys())
+        {
+            *slot = *key;
+            num_program_keys += 1;
+        }
+
         QoSPartialMeta {
-            ip: u32::from_le_bytes(ip.octets()),
-            signer: signer.to_bytes(),
+            ip: features.ip,
+            signer: features.signer,
             total_fee,
-            cus,
+            cus: features.requested_cus,
+            num_instructions: features.num_instructions,
+            program_keys,
+            num_program_keys,
+            transmitted: false,
+            _padding: [0; 4],
+        }
+    }
+
+    #[inline(always)]
+    pub fn program_keys(&self) -> &[u64] {
+        &self.program_keys[..self.num_program_keys as usize]
+    }
+
+    /// Completes a transmitted transaction whose scheduler feedback
+    /// never arrived. It is assigned zero value.
+    #[inline(always)]
+    pub fn expire<A: Default>(self) -> QoSTransactionMeta<A> {
+        QoSTransactionMeta {
+            ip: self.ip,
+            signer: self.signer,
+            value: F64::from(0.0),
+            requested_cus: self.cus,
+            outcome: Outcome::NotScheduled,
+            consumed_cus: 0,
+            additional_metadata: A::default(),
         }
     }
 
+    /// Completes a transaction with its scheduler feedback. It is
+    /// valued at the fee actually charged per nanosecond of execution.
+    /// A transaction that was not scheduled, e.g. because the block was
+    /// full or its accounts were locked, is valued at its fee over
+    /// [NOT_SCHEDULED_NANOS] rather than counted against its sender.
     #[inline(always)]
     pub fn merge<A: Pod>(
         self,
         remaining_meta: QoSRemainingMeta<A>,
     ) -> QoSTransactionMeta<A> {
-        let execution_nanos = if remaining_meta.execution_nanos == 0 {
-            // TODO: Fixed constant
-            // Value non-included transactions at 100 micros
-            100_000
-        } else {
-            remaining_meta.execution_nanos
+        // Unknown outcomes are treated as not scheduled
+        let outcome = remaining_meta
+            .outcome()
+            .unwrap_or(Outcome::NotScheduled);
+
+        let value = match outcome {
+            Outcome::NotScheduled => {
+                self.total_fee as f64 / NOT_SCHEDULED_NANOS as f64
+            }
+            _ => remaining_meta.value(),
         };
 
         QoSTransactionMeta {
             ip: self.ip,
             signer: self.signer,
-            value: F64::from(
-                self.total_fee as f64 / execution_nanos as f64,
-            ),
+            value: F64::from(value),
+            requested_cus: self.cus,
+            outcome,
+            consumed_cus: remaining_meta.consumed_cus,
             additional_metadata: remaining_meta.additional_metadata,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use solana_qos_common::remaining_meta::ErrorClass;
+
+    use super::*;
+
+    fn partial_meta(total_fee: u64) -> QoSPartialMeta {
+        QoSPartialMeta::new(
+            &TransactionFeatures::new_for_tests(1, [2; 32]),
+            total_fee,
+        )
+    }
+
+    #[test]
+    fn test_merge_values() {
+        // Executed transactions are valued at the fee charged per
+        // nanosecond of execution
+        let executed = QoSRemainingMeta::executed(
+            3_u64,
+            1000,
+            300,
+            5000,
+            42,
+            ErrorClass::None,
+            (),
+        );
+        let meta = partial_meta(10_000).merge(executed);
+        assert_eq!(*meta.value, 5.0);
+        assert_eq!(meta.outcome, Outcome::Succeeded);
+
+        // Not scheduled ones at their fee over 100 micros, as are
+        // unknown outcomes
+        let not_scheduled = QoSRemainingMeta::not_executed(
+            3_u64,
+            Outcome::NotScheduled,
+            42,
+            (),
+        );
+        let meta = partial_meta(10_000).merge(not_scheduled);
+        assert_eq!(*meta.value, 0.1);
+        assert_eq!(meta.outcome, Outcome::NotScheduled);
+
+        let mut unknown = not_scheduled;
+        unknown.outcome = 200;
+        let meta = partial_meta(10_000).merge(unknown);
+        assert_eq!(*meta.value, 0.1);
+
+        // Expired ones are worth nothing
+        let expired = QoSRemainingMeta::not_executed(
+            3_u64,
+            Outcome::Expired,
+            42,
+            (),
+        );
+        let meta = partial_meta(10_000).merge(expired);
+        assert_eq!(*meta.value, 0.0);
+
+        // Missing feedback is worth nothing
+        let meta = partial_meta(10_000).expire::<()>();
+        assert_eq!(*meta.value, 0.0);
+    }
+}
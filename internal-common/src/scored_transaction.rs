@@ -1,6 +1,9 @@
-use crate::{packet_bytes, transaction_meta::F64};
+use crate::{
+    packet_bytes, transaction_features::TransactionFeatures,
+    transaction_meta::F64,
+};
 use derivative::Derivative;
-use solana_qos_common::packet_bytes::PacketBytes;
+use solana_qos_common::{packet_bytes::PacketBytes, xxhash::xxHash};
 use solana_sdk::packet::Packet;
 
 #[derive(Debug, Derivative, PartialEq, Eq, Clone)]
@@ -17,6 +20,22 @@ pub struct ScoredTransaction {
 
     #[derivative(PartialOrd = "ignore", Ord = "ignore")]
     pub ipv4: u32,
+
+    /// Key of this packet's partial meta
+    #[derivative(PartialOrd = "ignore", Ord = "ignore")]
+    pub packet_key: xxHash,
+
+    /// Kept so that the transaction can be rescored while queued
+    #[derivative(PartialOrd = "ignore", Ord = "ignore")]
+    pub features: TransactionFeatures,
+
+    /// Score is the model score times this
+    #[derivative(PartialOrd = "ignore", Ord = "ignore")]
+    pub value_rate: F64,
+
+    /// Version of the model the score was computed with
+    #[derivative(PartialOrd = "ignore", Ord = "ignore")]
+    pub model_version: u64,
 }
 
 impl ScoredTransaction {
@@ -0,0 +1,77 @@
+//! Helpers shared by the tests of dependent crates
+
+use solana_sdk::packet::Packet;
+
+/// Names a shared memory region for one test, and removes it once the
+/// test is done with it. Regions named after it with a `_` suffix, e.g.
+/// the lanes of an mpsc channel, are removed too.
+pub struct ShmRegion(String);
+
+impl ShmRegion {
+    /// Unique per test and process, so tests can run concurrently
+    pub fn new(test: &str) -> ShmRegion {
+        ShmRegion(format!("test_{test}_{}", std::process::id()))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for ShmRegion {
+    fn drop(&mut self) {
+        let Ok(entries) = std::fs::read_dir("/dev/shm") else {
+            return;
+        };
+
+        let prefix = format!("{}_", self.0);
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name == self.0 || name.starts_with(&prefix) {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+/// A one byte packet holding `id`
+pub fn packet(id: u8) -> Packet {
+    let mut packet = Packet::default();
+    packet.buffer_mut()[0] = id;
+    packet.meta_mut().size = 1;
+    packet
+}
+
+/// The id of a packet made by [packet]
+pub fn packet_id(packet: &Packet) -> u8 {
+    *packet.data(0).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drop_removes_region_and_suffixed_regions() {
+        let region = ShmRegion::new("shm_region");
+        let path = |suffix: &str| {
+            std::path::PathBuf::from(format!(
+                "/dev/shm/{}{suffix}",
+                region.name()
+            ))
+        };
+        for suffix in ["", "_000", "0"] {
+            std::fs::write(path(suffix), []).unwrap();
+        }
+
+        let [own, lane, other] = ["", "_000", "0"].map(path);
+        drop(region);
+        assert!(!own.exists());
+        assert!(!lane.exists());
+
+        // Another name that merely starts the same is kept
+        assert!(other.exists());
+        std::fs::remove_file(other).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+use std::net::Ipv4Addr;
+
+/// Max number of distinct invoked programs recorded per transaction.
+/// This matches the number of instructions inspected for compute
+/// budget instructions.
+pub const MAX_PROGRAM_IDS: usize = 8;
+
+/// Max number of writable accounts recorded per transaction. The total
+/// count is always available via
+/// [TransactionFeatures::total_writable_accounts].
+pub const MAX_WRITABLE_ACCOUNTS: usize = 16;
+
+/// The ingress channel a packet arrived on.
+///
+/// RE1 and RE2 are relayer 1 (TPU) and relayer 2 (TPU FWD)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum PacketSource {
+    #[default]
+    Tpu = 0,
+    Fwd = 1,
+    Re1 = 2,
+    Re2 = 3,
+}
+
+impl PacketSource {
+    pub const ALL: [PacketSource; 4] = [
+        PacketSource::Tpu,
+        PacketSource::Fwd,
+        PacketSource::Re1,
+        PacketSource::Re2,
+    ];
+}
+
+/// Transaction features extracted once per packet prior to sigverify.
+///
+/// Models read whichever subset of these they need when scoring.
+/// Programs and accounts are kept as folded keys (see [account_key])
+/// so that the features are small enough to queue with every
+/// transaction for rescoring.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionFeatures {
+    /// Ipv4 source address
+    pub ip: u32,
+
+    /// Primary signer, i.e. fee payer
+    pub signer: [u8; 32],
+
+    /// Number of instructions in the transaction
+    pub num_instructions: u16,
+
+    /// Number of signatures in the transaction
+    pub num_signatures: u8,
+
+    /// Requested compute units (or the default if not requested)
+    pub requested_cus: u32,
+
+    /// Compute unit price in micro-lamports
+    pub cu_price: u64,
+
+    /// Size of the packet payload in bytes
+    pub packet_size: u16,
+
+    /// Channel this packet arrived on
+    pub source: PacketSource,
+
+    /// Total number of writable accounts, including those loaded from
+    /// address lookup tables
+    pub total_writable_accounts: u16,
+
+    program_keys: [u64; MAX_PROGRAM_IDS],
+    num_program_keys: u8,
+
+    writable_account_keys: [u64; MAX_WRITABLE_ACCOUNTS],
+    num_writable_accounts: u8,
+}
+
+impl TransactionFeatures {
+    #[inline(always)]
+    pub fn new(
+        ip: u32,
+        signer: [u8; 32],
+        source: PacketSource,
+    ) -> TransactionFeatures {
+        TransactionFeatures {
+            ip,
+            signer,
+            num_instructions: 0,
+            num_signatures: 0,
+            requested_cus: 0,
+            cu_price: 0,
+            packet_size: 0,
+            source,
+            total_writable_accounts: 0,
+            program_keys: [0; MAX_PROGRAM_IDS],
+            num_program_keys: 0,
+            writable_account_keys: [0; MAX_WRITABLE_ACCOUNTS],
+            num_writable_accounts: 0,
+        }
+    }
+
+    /// Only ip and signer are populated
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn new_for_tests(
+        ip: u32,
+        signer: [u8; 32],
+    ) -> TransactionFeatures {
+        TransactionFeatures::new(ip, signer, PacketSource::Tpu)
+    }
+
+    /// Records an invoked program. Duplicates and programs past
+    /// [MAX_PROGRAM_IDS] are ignored.
+    #[inline(always)]
+    pub fn push_program_id(&mut self, program_id: &[u8; 32]) {
+        let key = account_key(program_id);
+        let len = self.num_program_keys as usize;
+        if len == MAX_PROGRAM_IDS
+            || self.program_keys[..len].contains(&key)
+        {
+            return;
+        }
+        self.program_keys[len] = key;
+        self.num_program_keys += 1;
+    }
+
+    /// Records a writable account. Accounts past
+    /// [MAX_WRITABLE_ACCOUNTS] are ignored.
+    #[inline(always)]
+    pub fn push_writable_account(&mut self, account: &[u8; 32]) {
+        let len = self.num_writable_accounts as usize;
+        if len == MAX_WRITABLE_ACCOUNTS {
+            return;
+        }
+        self.writable_account_keys[len] = account_key(account);
+        self.num_writable_accounts += 1;
+    }
+
+    /// Keys of the distinct invoked programs, in order of first
+    /// invocation
+    #[inline(always)]
+    pub fn program_keys(&self) -> &[u64] {
+        &self.program_keys[..self.num_program_keys as usize]
+    }
+
+    /// Keys of the writable static accounts, in order of appearance
+    #[inline(always)]
+    pub fn writable_account_keys(&self) -> &[u64] {
+        &self.writable_account_keys
+            [..self.num_writable_accounts as usize]
+    }
+}
+
+/// Key of an ipv4 address in model tables, sketches and the
+/// container: its octets read as a little-endian u32
+#[inline(always)]
+pub fn ipv4_key(ip: &Ipv4Addr) -> u32 {
+    u32::from_le_bytes(ip.octets())
+}
+
+/// Folds an account (e.g. a program id or a signer) into a u64 key.
+/// Account keys are uniformly distributed so no further mixing is
+/// needed. Lanes are rotated so that equal lanes do not cancel.
+#[inline(always)]
+pub fn account_key(account: &[u8; 32]) -> u64 {
+    account
+        .chunks_exact(8)
+        .map(|chunk| {
+            u64::from_le_bytes(unsafe {
+                chunk.try_into().unwrap_unchecked()
+            })
+        })
+        .fold(0, |acc, lane| acc.rotate_left(17) ^ lane)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An account whose key is `byte`, as the last lane is folded in
+    /// unrotated
+    fn account(byte: u8) -> [u8; 32] {
+        let mut account = [0; 32];
+        account[24] = byte;
+        account
+    }
+
+    #[test]
+    fn test_program_ids_are_distinct_and_capped() {
+        let mut features =
+            TransactionFeatures::new_for_tests(0, [0; 32]);
+        features.push_program_id(&account(1));
+        features.push_program_id(&account(1));
+        assert_eq!(features.program_keys(), &[1]);
+
+        for i in 0..2 * MAX_PROGRAM_IDS as u8 {
+            features.push_program_id(&account(i + 2));
+        }
+        assert_eq!(features.program_keys().len(), MAX_PROGRAM_IDS);
+        assert_eq!(features.program_keys()[1], 2);
+    }
+
+    #[test]
+    fn test_writable_accounts_are_capped() {
+        let mut features =
+            TransactionFeatures::new_for_tests(0, [0; 32]);
+        for i in 0..2 * MAX_WRITABLE_ACCOUNTS as u8 {
+            features.push_writable_account(&account(i));
+        }
+        assert_eq!(
+            features.writable_account_keys().len(),
+            MAX_WRITABLE_ACCOUNTS
+        );
+        assert_eq!(features.writable_account_keys()[1], 1);
+    }
+
+    #[test]
+    fn test_account_key_folds_lanes() {
+        let mut account = [0; 32];
+        account[16] = 1;
+        account[24] = 2;
+        assert_eq!(account_key(&account), 1 << 17 | 2);
+
+        // Equal lanes do not cancel
+        assert_ne!(account_key(&[1; 32]), 0);
+    }
+
+    #[test]
+    fn test_features_stay_small() {
+        // Kept with every queued transaction
+        assert!(size_of::<TransactionFeatures>() <= 256);
+    }
+
+    #[test]
+    fn test_ipv4_key_is_little_endian() {
+        // Every call site keys ips this way, unlike Ipv4Addr::to_bits
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        assert_eq!(ipv4_key(&ip), 0x0403_0201);
+        assert_eq!(ipv4_key(&ip), ip.to_bits().swap_bytes());
+    }
+}
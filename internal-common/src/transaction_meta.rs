@@ -1,4 +1,5 @@
 use ordered_float::OrderedFloat;
+use solana_qos_common::remaining_meta::Outcome;
 
 pub type F64 = OrderedFloat<f64>;
 
@@ -13,11 +14,21 @@ pub struct QoSTransactionMeta<A> {
     /// Fee in lamports / execution time in nanos
     pub value: F64,
 
+    /// Compute units requested by the transaction (zero if unknown)
+    pub requested_cus: u32,
+
+    /// What the scheduler did with the transaction
+    pub outcome: Outcome,
+
+    /// Compute units consumed (zero if not executed)
+    pub consumed_cus: u64,
+
     pub additional_metadata: A,
 }
 
 impl<A> QoSTransactionMeta<A> {
-    // test only. will panic if nanos == 0
+    // test only. will panic if nanos == 0. requested and consumed cus
+    // are unknown
     pub fn new_for_tests(
         ip: u32,
         signer: [u8; 32],
@@ -29,6 +40,9 @@ impl<A> QoSTransactionMeta<A> {
             ip,
             signer,
             value: F64::from(fee as f64 / execution_nanos as f64),
+            requested_cus: 0,
+            outcome: Outcome::Succeeded,
+            consumed_cus: 0,
             additional_metadata,
         }
     }
@@ -1,58 +1,122 @@
 use std::alloc::Layout;
-use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::mem::MaybeUninit;
 
 use nohash_hasher::{BuildNoHashHasher, IsEnabled};
 
-#[derive(Copy, Clone)]
-struct Node<K, V> {
-    key: K,
-    value: V,
-    prev: Option<usize>,
-    next: Option<usize>,
+mod sharded;
+mod shared;
+pub use sharded::ShardedLRUCache;
+pub use shared::{Joined, LayoutError, LAYOUT_VERSION};
+
+/// Per entry timestamp for [LRUCache::expire_older_than]. Caches that
+/// never expire use `()`, which takes no space in the node.
+///
+/// Sealed, as zeroed memory must be a valid timestamp.
+pub trait Timestamp: Copy + Default + Ord + sealed::Sealed {}
+
+impl Timestamp for () {}
+impl Timestamp for u64 {}
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for () {}
+    impl Sealed for u64 {}
 }
 
+/// Links and the free list store a node index plus one, so that zero
+/// means "none". This makes all-zero memory a valid empty cache.
+const NONE: u32 = 0;
+
+/// Table entries pack a 32 bit hash tag above a node link, so probes
+/// and deletions rarely need to load the node. Zero is empty.
+const EMPTY: u64 = 0;
+
+/// Control bytes mirror the table with 7 bits of the tag and the high
+/// bit set. Zero is empty.
+const CTRL_EMPTY: u8 = 0;
+
+/// Table slots per node. Short probe sequences are worth the memory,
+/// as every eviction shifts the rest of its sequence back.
+const SLOTS_PER_NODE: usize = 4;
+
+/// Control bytes scanned per step of a probe
+const GROUP: usize = 8;
+const LOW_BITS: u64 = 0x0101_0101_0101_0101;
+const HIGH_BITS: u64 = 0x8080_8080_8080_8080;
+
+#[repr(C)]
+struct Node<K, V, T> {
+    key: MaybeUninit<K>,
+    value: MaybeUninit<V>,
+    timestamp: T,
+    prev: u32,
+    next: u32,
+}
+
+/// A fixed capacity LRU cache.
+///
+/// Nodes live in a contiguous array and are linked with u32 indices.
+/// Keys are located with an inline open-addressed table of 4N slots
+/// (load factor at most 1/4) using linear probing and backward shift
+/// deletion, so there are no separate heap allocations.
+///
+/// Probes scan a byte per slot first, which is small enough to stay in
+/// cache, and only read the table on a 7 bit tag match. Most misses
+/// never touch the table.
+///
+/// Entries are stamped with a [Timestamp] `T` only if one is given.
 #[repr(C, align(128))]
 pub struct LRUCache<
     K: Copy + Eq + std::hash::Hash + IsEnabled,
     V,
     const N: usize,
+    T: Timestamp = (),
 > {
-    nodes: [Option<Node<K, V>>; N],
-    map: HashMap<K, usize, BuildNoHashHasher<K>>,
-    free_list: Vec<usize>,
-    head: Option<usize>,
-    tail: Option<usize>,
+    head: u32,
+    tail: u32,
+    len: u32,
+
+    /// Nodes `[0, bump)` have been handed out at least once
+    bump: u32,
+
+    /// Head of the free list, threaded through `next`
+    free: u32,
+
+    ctrl: [[u8; N]; SLOTS_PER_NODE],
+    table: [[u64; N]; SLOTS_PER_NODE],
+    nodes: [Node<K, V, T>; N],
 }
 
-impl<K: Copy + Eq + std::hash::Hash + IsEnabled, V, const N: usize>
-    LRUCache<K, V, N>
+impl<
+        K: Copy + Eq + std::hash::Hash + IsEnabled,
+        V,
+        const N: usize,
+        T: Timestamp,
+    > LRUCache<K, V, N, T>
 {
-    const _ASSERT_NONZERO: () =
-        assert!(N > 0, "capacity of LRUCache must be nonzero");
+    const _ASSERT_CAPACITY: () = assert!(
+        N > 0 && N <= (u32::MAX / 2) as usize,
+        "capacity of LRUCache must be nonzero and at most 2^31 - 1"
+    );
 
     pub fn new() -> Self {
-        let mut free_list = Vec::with_capacity(N);
-        for i in (0..N).rev() {
-            free_list.push(i);
-        }
+        let () = Self::_ASSERT_CAPACITY;
 
-        LRUCache {
-            nodes: core::array::from_fn(|_| None),
-            map: HashMap::with_capacity_and_hasher(
-                N,
-                BuildNoHashHasher::<K>::default(),
-            ),
-            head: None,
-            tail: None,
-            free_list,
-        }
+        // SAFETY:
+        //
+        // All fields are integers, timestamps or MaybeUninit, and zero
+        // is the empty state
+        unsafe { core::mem::zeroed() }
     }
 
     pub fn new_boxed() -> Box<Self> {
+        let () = Self::_ASSERT_CAPACITY;
+
+        // Zeroed memory is a valid empty cache, so large caches never
+        // touch the stack and untouched pages are never faulted in
         let layout = Layout::new::<Self>();
-        // This needs to be alloc_zeroed otherwise the Vec and
-        // HashMap may have nonzero data/capacity and attempt to
-        // deallocate a nonexisting allocation
         let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
         if ptr.is_null() {
             // This is a fairly rare case and program should probably
@@ -60,273 +124,567 @@ impl<K: Copy + Eq + std::hash::Hash + IsEnabled, V, const N: usize>
             panic!("failed allocation");
         }
 
-        // Initialize
-        let lru_cache_ptr: *mut Self = ptr.cast();
-        unsafe {
-            // Note:
-            // We iterate and set each element because initializing the
-            // full array sometimes leads to a stack
-            // overflow for large N...
-            for i in 0..N {
-                (*lru_cache_ptr).nodes[i] = None;
-            }
+        unsafe { Box::from_raw(ptr.cast()) }
+    }
 
-            (*lru_cache_ptr).free_list = Vec::with_capacity(N);
-            for i in (0..N).rev() {
-                (*lru_cache_ptr).free_list.push(i);
-            }
+    pub fn get(&mut self, key: K) -> Option<&V> {
+        let (_slot, link) = self.find(&key)?;
+        self.move_to_front(link);
+        Some(unsafe { self.node(link).value.assume_init_ref() })
+    }
 
-            (*lru_cache_ptr).map = HashMap::with_capacity_and_hasher(
-                N,
-                BuildNoHashHasher::<K>::default(),
-            );
+    /// Similar to get but does NOT move to front
+    pub fn contains(&self, key: K) -> bool {
+        self.find(&key).is_some()
+    }
 
-            (*lru_cache_ptr).head = None;
-            (*lru_cache_ptr).tail = None;
+    /// Similar to get but does NOT move to front
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        let (_slot, link) = self.find(key)?;
+        Some(unsafe { self.node(link).value.assume_init_ref() })
+    }
 
-            Box::from_raw(lru_cache_ptr)
-        }
+    /// Similar to get but does NOT move to front
+    pub fn peek_mut(&mut self, key: &K) -> Option<&mut V> {
+        let (_slot, link) = self.find(key)?;
+        Some(unsafe {
+            self.node_mut(link)
+                .value
+                .assume_init_mut()
+        })
     }
 
-    pub fn get(&mut self, key: K) -> Option<&V> {
-        if let Some(&index) = self.map.get(&key) {
-            self.move_to_front(index);
-            return unsafe {
-                self.nodes
-                    .get_unchecked(index)
-                    .as_ref()
-                    .map(|node| &node.value)
-            };
+    /// Returns the least recently used entry without removing it
+    pub fn peek_lru(&self) -> Option<(&K, &V)> {
+        if self.tail == NONE {
+            return None;
         }
-        None
+        let node = unsafe { self.node(self.tail) };
+        Some(unsafe {
+            (node.key.assume_init_ref(), node.value.assume_init_ref())
+        })
     }
 
-    /// Similar to get but does NOT move to front
-    pub fn contains(&self, key: K) -> bool {
-        self.map.get(&key).is_some()
+    pub fn len(&self) -> usize {
+        self.len as usize
     }
 
-    pub fn pop(&mut self, key: &K) -> Option<(K, V)> {
-        if let Some(index) = self.map.remove(key) {
-            let node = unsafe {
-                self.nodes
-                    .get_unchecked_mut(index)
-                    .take()
-                    .unwrap_unchecked()
-            };
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
 
-            if let Some(prev_index) = node.prev {
-                unsafe {
-                    self.nodes
-                        .get_unchecked_mut(prev_index)
-                        .as_mut()
-                        .unwrap_unchecked()
-                        .next = node.next;
-                }
-            } else {
-                self.head = node.next;
-            }
+    pub const fn capacity(&self) -> usize {
+        N
+    }
 
-            if let Some(next_index) = node.next {
-                unsafe {
-                    self.nodes
-                        .get_unchecked_mut(next_index)
-                        .as_mut()
-                        .unwrap_unchecked()
-                        .prev = node.prev;
-                }
-            } else {
-                self.tail = node.prev;
-            }
+    /// Iterates from most to least recently used
+    pub fn iter(&self) -> Iter<'_, K, V, N, T> {
+        Iter {
+            cache: self,
+            next: self.head,
+        }
+    }
 
-            self.free_list.push(index);
-            Some((node.key, node.value))
-        } else {
-            None
+    /// Removes and yields least recently used entries with a timestamp
+    /// older than `cutoff`, stopping at the first entry that is not.
+    ///
+    /// Removal is lazy: only yielded entries are removed, so the
+    /// iterator must be exhausted to expire every old entry, and e.g.
+    /// `.take(n)` expires at most `n`.
+    ///
+    /// This expires every old entry as long as entries are only
+    /// touched via [LRUCache::put_with_timestamp] with nondecreasing
+    /// timestamps, as [LRUCache::get] reorders without restamping.
+    pub fn expire_older_than(
+        &mut self,
+        cutoff: T,
+    ) -> ExpireOlderThan<'_, K, V, N, T> {
+        ExpireOlderThan {
+            cache: self,
+            cutoff,
         }
     }
 
+    pub fn pop(&mut self, key: &K) -> Option<(K, V)> {
+        let (slot, link) = self.find(key)?;
+        Some(unsafe { self.remove(slot, link) })
+    }
+
     /// Returns lru value if full, and returns whether this was a duplicate
     /// 1) not full, not duplicate = None, false
     /// 2) full, not duplicate = Some(...), false
     /// 3) not full, duplicate = None, true
     /// 4) full, duplicate, None, true
+    ///
+    /// The entry is stamped with the default (zero) timestamp.
     pub fn put(&mut self, key: K, value: V) -> (Option<(K, V)>, bool) {
-        if let Some(&index) = self.map.get(&key) {
-            // NOTE: If K -> V map is unique, this write can be avoided entirely
-            unsafe {
-                self.nodes
-                    .get_unchecked_mut(index)
-                    .as_mut()
-                    .unwrap_unchecked()
+        self.put_with_timestamp(key, value, T::default())
+    }
+
+    /// Same as [LRUCache::put], but stamps the entry with `timestamp`
+    /// (in any unit) for use with [LRUCache::expire_older_than].
+    /// Duplicates are restamped.
+    pub fn put_with_timestamp(
+        &mut self,
+        key: K,
+        value: V,
+        timestamp: T,
+    ) -> (Option<(K, V)>, bool) {
+        self.insert(key, value, timestamp, true)
+    }
+
+    /// Same as [LRUCache::put_with_timestamp], but a duplicate is left
+    /// untouched: it keeps its value, timestamp and position
+    pub fn put_new_with_timestamp(
+        &mut self,
+        key: K,
+        value: V,
+        timestamp: T,
+    ) -> (Option<(K, V)>, bool) {
+        self.insert(key, value, timestamp, false)
+    }
+
+    #[inline(always)]
+    fn insert(
+        &mut self,
+        key: K,
+        value: V,
+        timestamp: T,
+        overwrite: bool,
+    ) -> (Option<(K, V)>, bool) {
+        let tag = Self::tag(&key);
+        let slot = match self.probe(tag, &key) {
+            Ok((_slot, link)) => {
+                if overwrite {
+                    // NOTE: If K -> V map is unique, this write can be avoided entirely
+                    let node = unsafe { self.node_mut(link) };
+                    unsafe { node.value.assume_init_drop() };
+                    node.value.write(value);
+                    node.timestamp = timestamp;
+                    self.move_to_front(link);
+                }
+                return (None, true);
             }
-            .value = value;
-            self.move_to_front(index);
-            (None, true)
+            Err(slot) => slot,
+        };
+
+        // Make room. Eviction may shift entries in the table, so the
+        // free slot is only valid if nothing was evicted.
+        let evicted = if self.len as usize == N {
+            self.evict()
         } else {
-            if self.map.len() == N {
-                let evicted = self.evict();
-                let index =
-                    unsafe { self.free_list.pop().unwrap_unchecked() };
-                unsafe {
-                    *self.nodes.get_unchecked_mut(index) = Some(Node {
-                        key,
-                        value,
-                        prev: None,
-                        next: self.head,
-                    });
-                }
+            None
+        };
+        let slot = if evicted.is_some() {
+            self.empty_slot(Self::home_slot(tag))
+        } else {
+            slot
+        };
+
+        let link = self.allocate();
+        let old_head = self.head;
+        let node = unsafe { self.node_mut(link) };
+        node.key.write(key);
+        node.value.write(value);
+        node.timestamp = timestamp;
+        node.prev = NONE;
+        node.next = old_head;
+        if old_head != NONE {
+            unsafe { self.node_mut(old_head) }.prev = link;
+        } else {
+            self.tail = link;
+        }
+        self.head = link;
+
+        unsafe {
+            *self.ctrls_mut().get_unchecked_mut(slot) = Self::ctrl(tag);
+            *self.slots_mut().get_unchecked_mut(slot) =
+                Self::entry(tag, link)
+        };
+        self.len += 1;
+
+        (evicted, false)
+    }
+
+    fn move_to_front(&mut self, link: u32) {
+        if link == self.head {
+            return;
+        }
+
+        unsafe { self.unlink(link) };
+
+        let old_head = self.head;
+        let node = unsafe { self.node_mut(link) };
+        node.prev = NONE;
+        node.next = old_head;
+        if old_head != NONE {
+            unsafe { self.node_mut(old_head) }.prev = link;
+        } else {
+            self.tail = link;
+        }
+        self.head = link;
+    }
+
+    fn evict(&mut self) -> Option<(K, V)> {
+        if self.tail == NONE {
+            return None;
+        }
+
+        let link = self.tail;
+        let key = unsafe { self.node(link).key.assume_init_ref() };
+        let (slot, _link) =
+            unsafe { self.find(key).unwrap_unchecked() };
+        Some(unsafe { self.remove(slot, link) })
+    }
+
+    /// Removes an occupied node from the table and list, returning its
+    /// entry and freeing the node.
+    ///
+    /// # Safety
+    /// `slot` must be the table slot holding `link`.
+    unsafe fn remove(&mut self, slot: usize, link: u32) -> (K, V) {
+        self.remove_slot(slot);
+        self.unlink(link);
+
+        let free = self.free;
+        let node = self.node_mut(link);
+        let entry = (
+            node.key.assume_init_read(),
+            node.value.assume_init_read(),
+        );
+        node.next = free;
+        self.free = link;
+        self.len -= 1;
+
+        entry
+    }
+
+    /// Detaches a node from the list, leaving its own links stale
+    unsafe fn unlink(&mut self, link: u32) {
+        let Node { prev, next, .. } = *self.node(link);
 
-                if let Some(head_index) = self.head {
-                    unsafe {
-                        self.nodes
-                            .get_unchecked_mut(head_index)
-                            .as_mut()
-                            .unwrap_unchecked()
+        if prev != NONE {
+            self.node_mut(prev).next = next;
+        } else {
+            self.head = next;
+        }
+
+        if next != NONE {
+            self.node_mut(next).prev = prev;
+        } else {
+            self.tail = prev;
+        }
+    }
+
+    /// Returns an unused node, preferring previously freed ones. Only
+    /// called when not full.
+    #[inline(always)]
+    fn allocate(&mut self) -> u32 {
+        if self.free != NONE {
+            let link = self.free;
+            self.free = unsafe { self.node(link) }.next;
+            link
+        } else {
+            self.bump += 1;
+            self.bump
+        }
+    }
+
+    /// Finds the table slot and node of a key
+    #[inline(always)]
+    fn find(&self, key: &K) -> Option<(usize, u32)> {
+        self.probe(Self::tag(key), key).ok()
+    }
+
+    /// Returns the table slot and node of a key if present, otherwise
+    /// the empty slot that ends its probe sequence
+    #[inline(always)]
+    fn probe(&self, tag: u32, key: &K) -> Result<(usize, u32), usize> {
+        let ctrl = Self::ctrl(tag);
+        let mut slot = Self::home_slot(tag);
+
+        // Most hits are in their home slot. Checking it first keeps
+        // hits at one table read, and on a miss the read overlaps the
+        // control byte scan.
+        if let Some(link) = self.matches(slot, tag, key) {
+            return Ok((slot, link));
+        }
+
+        loop {
+            // Scan a group of control bytes at once, or a single one
+            // where the group would wrap around
+            if slot + GROUP <= SLOTS_PER_NODE * N {
+                let group = u64::from_le_bytes(unsafe {
+                    self.ctrls()
+                        .as_ptr()
+                        .add(slot)
+                        .cast::<[u8; GROUP]>()
+                        .read()
+                });
+
+                // Occupied control bytes have the high bit set
+                let empty = !group & HIGH_BITS;
+
+                // Bytes equal to `ctrl`, plus rare false positives that
+                // the tag check rejects. Only those before the first
+                // empty slot belong to the probe sequence.
+                let x = group ^ (ctrl as u64 * LOW_BITS);
+                let mut matches =
+                    x.wrapping_sub(LOW_BITS) & !x & HIGH_BITS;
+                matches &=
+                    (empty & empty.wrapping_neg()).wrapping_sub(1);
+                while matches != 0 {
+                    let at =
+                        slot + matches.trailing_zeros() as usize / 8;
+                    if let Some(link) = self.matches(at, tag, key) {
+                        return Ok((at, link));
                     }
-                    .prev = Some(index);
+                    matches &= matches - 1;
                 }
-                self.head = Some(index);
 
-                if self.tail.is_none() {
-                    self.tail = Some(index);
+                if empty != 0 {
+                    return Err(
+                        slot + empty.trailing_zeros() as usize / 8
+                    );
                 }
-
-                self.map.insert(key, index);
-                return (evicted, false);
+                slot = Self::next_slot(slot + GROUP - 1);
             } else {
-                let index =
-                    unsafe { self.free_list.pop().unwrap_unchecked() };
-                unsafe {
-                    *self.nodes.get_unchecked_mut(index) = Some(Node {
-                        key,
-                        value,
-                        prev: None,
-                        next: self.head,
-                    });
+                let c = unsafe { *self.ctrls().get_unchecked(slot) };
+                if c == CTRL_EMPTY {
+                    return Err(slot);
                 }
-
-                if let Some(head_index) = self.head {
-                    unsafe {
-                        self.nodes
-                            .get_unchecked_mut(head_index)
-                            .as_mut()
-                            .unwrap_unchecked()
+                if c == ctrl {
+                    if let Some(link) = self.matches(slot, tag, key) {
+                        return Ok((slot, link));
                     }
-                    .prev = Some(index);
                 }
-                self.head = Some(index);
-
-                if self.tail.is_none() {
-                    self.tail = Some(index);
-                }
-
-                self.map.insert(key, index);
-                (None, false)
+                slot = Self::next_slot(slot);
             }
         }
     }
 
-    fn move_to_front(&mut self, index: usize) {
-        if Some(index) == self.head {
-            return;
+    /// Returns the node in a slot if it holds `key`
+    #[inline(always)]
+    fn matches(&self, slot: usize, tag: u32, key: &K) -> Option<u32> {
+        let entry = unsafe { *self.slots().get_unchecked(slot) };
+        let link = entry as u32;
+        ((entry >> 32) as u32 == tag
+            && link != NONE
+            && unsafe { self.node(link).key.assume_init_ref() } == key)
+            .then_some(link)
+    }
+
+    #[inline(always)]
+    fn empty_slot(&self, home: usize) -> usize {
+        let mut slot = home;
+        while unsafe { *self.ctrls().get_unchecked(slot) } != CTRL_EMPTY
+        {
+            slot = Self::next_slot(slot);
         }
+        slot
+    }
 
-        let prev_index = unsafe {
-            self.nodes
-                .get_unchecked(index)
-                .as_ref()
-                .unwrap_unchecked()
-                .prev
-        };
-        let next_index = unsafe {
-            self.nodes
-                .get_unchecked(index)
-                .as_ref()
-                .unwrap_unchecked()
-                .next
-        };
+    /// Backward shift deletion: moves later entries of the probe
+    /// sequence into the hole so lookups never need tombstones.
+    fn remove_slot(&mut self, slot: usize) {
+        let mut hole = slot;
+        let mut next = Self::next_slot(hole);
+        loop {
+            let c = unsafe { *self.ctrls().get_unchecked(next) };
+            if c == CTRL_EMPTY {
+                break;
+            }
+            let entry = unsafe { *self.slots().get_unchecked(next) };
 
-        if let Some(prev) = prev_index {
-            unsafe {
-                self.nodes
-                    .get_unchecked_mut(prev)
-                    .as_mut()
-                    .unwrap_unchecked()
+            // An entry may fill the hole unless its home lies
+            // cyclically within (hole, next]
+            let home = Self::home_slot((entry >> 32) as u32);
+            let stays = if hole <= next {
+                hole < home && home <= next
+            } else {
+                hole < home || home <= next
+            };
+            if !stays {
+                unsafe {
+                    *self.ctrls_mut().get_unchecked_mut(hole) = c;
+                    *self.slots_mut().get_unchecked_mut(hole) = entry;
+                }
+                hole = next;
             }
-            .next = next_index;
+
+            next = Self::next_slot(next);
+        }
+        unsafe {
+            *self.ctrls_mut().get_unchecked_mut(hole) = CTRL_EMPTY;
+            *self.slots_mut().get_unchecked_mut(hole) = EMPTY;
         }
+    }
 
-        if let Some(next) = next_index {
-            unsafe {
-                self.nodes
-                    .get_unchecked_mut(next)
-                    .as_mut()
-                    .unwrap_unchecked()
-            }
-            .prev = prev_index;
+    /// Keys are hashed by value, so mix and keep the high bits
+    #[inline(always)]
+    fn tag(key: &K) -> u32 {
+        let hash = BuildNoHashHasher::<K>::default()
+            .hash_one(key)
+            .wrapping_mul(0x9e3779b97f4a7c15);
+        (hash >> 32) as u32
+    }
+
+    /// Maps a tag to [0, 4N) without a modulo
+    #[inline(always)]
+    fn home_slot(tag: u32) -> usize {
+        ((tag as u64 * (SLOTS_PER_NODE * N) as u64) >> 32) as usize
+    }
+
+    #[inline(always)]
+    fn entry(tag: u32, link: u32) -> u64 {
+        (tag as u64) << 32 | link as u64
+    }
+
+    /// Takes the low bits, as [Self::home_slot] uses the high ones
+    #[inline(always)]
+    fn ctrl(tag: u32) -> u8 {
+        tag as u8 | 0x80
+    }
+
+    #[inline(always)]
+    fn next_slot(slot: usize) -> usize {
+        if slot + 1 == SLOTS_PER_NODE * N {
+            0
         } else {
-            self.tail = prev_index;
+            slot + 1
         }
+    }
 
-        unsafe {
-            self.nodes
-                .get_unchecked_mut(index)
-                .as_mut()
-                .unwrap_unchecked()
+    #[inline(always)]
+    fn ctrls(&self) -> &[u8] {
+        self.ctrl.as_flattened()
+    }
+
+    #[inline(always)]
+    fn ctrls_mut(&mut self) -> &mut [u8] {
+        self.ctrl.as_flattened_mut()
+    }
+
+    #[inline(always)]
+    fn slots(&self) -> &[u64] {
+        self.table.as_flattened()
+    }
+
+    #[inline(always)]
+    fn slots_mut(&mut self) -> &mut [u64] {
+        self.table.as_flattened_mut()
+    }
+
+    /// # Safety
+    /// `link` must not be [NONE]
+    #[inline(always)]
+    unsafe fn node(&self, link: u32) -> &Node<K, V, T> {
+        self.nodes
+            .get_unchecked(link as usize - 1)
+    }
+
+    /// # Safety
+    /// `link` must not be [NONE]
+    #[inline(always)]
+    unsafe fn node_mut(&mut self, link: u32) -> &mut Node<K, V, T> {
+        self.nodes
+            .get_unchecked_mut(link as usize - 1)
+    }
+}
+
+impl<K, V, const N: usize, T> Default for LRUCache<K, V, N, T>
+where
+    K: Copy + Eq + std::hash::Hash + IsEnabled,
+    T: Timestamp,
+{
+    fn default() -> Self {
+        LRUCache::new()
+    }
+}
+
+impl<K, V, const N: usize, T> Drop for LRUCache<K, V, N, T>
+where
+    K: Copy + Eq + std::hash::Hash + IsEnabled,
+    T: Timestamp,
+{
+    fn drop(&mut self) {
+        if !core::mem::needs_drop::<V>() {
+            return;
         }
-        .prev = None;
-        unsafe {
-            self.nodes
-                .get_unchecked_mut(index)
-                .as_mut()
-                .unwrap_unchecked()
+
+        let mut link = self.head;
+        while link != NONE {
+            let node = unsafe { self.node_mut(link) };
+            unsafe { node.value.assume_init_drop() };
+            link = node.next;
         }
-        .next = self.head;
-
-        if let Some(head_index) = self.head {
-            unsafe {
-                self.nodes
-                    .get_unchecked_mut(head_index)
-                    .as_mut()
-                    .unwrap_unchecked()
-            }
-            .prev = Some(index);
+    }
+}
+
+pub struct Iter<'a, K, V, const N: usize, T = ()>
+where
+    K: Copy + Eq + std::hash::Hash + IsEnabled,
+    T: Timestamp,
+{
+    cache: &'a LRUCache<K, V, N, T>,
+    next: u32,
+}
+
+impl<'a, K, V, const N: usize, T> Iterator for Iter<'a, K, V, N, T>
+where
+    K: Copy + Eq + std::hash::Hash + IsEnabled,
+    T: Timestamp,
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == NONE {
+            return None;
         }
 
-        self.head = Some(index);
+        let node = unsafe { self.cache.node(self.next) };
+        self.next = node.next;
+        Some(unsafe {
+            (node.key.assume_init_ref(), node.value.assume_init_ref())
+        })
     }
+}
 
-    fn evict(&mut self) -> Option<(K, V)> {
-        if let Some(tail_index) = self.tail {
-            let tail_node = unsafe {
-                self.nodes
-                    .get_unchecked_mut(tail_index)
-                    .take()
-                    .unwrap_unchecked()
-            };
-            self.map.remove(&tail_node.key);
-            self.tail = tail_node.prev;
+/// See [LRUCache::expire_older_than]. Dropping it early keeps the
+/// remaining old entries.
+pub struct ExpireOlderThan<'a, K, V, const N: usize, T = ()>
+where
+    K: Copy + Eq + std::hash::Hash + IsEnabled,
+    T: Timestamp,
+{
+    cache: &'a mut LRUCache<K, V, N, T>,
+    cutoff: T,
+}
 
-            if let Some(prev_index) = self.tail {
-                unsafe {
-                    self.nodes
-                        .get_unchecked_mut(prev_index)
-                        .as_mut()
-                        .unwrap_unchecked()
-                }
-                .next = None;
-            } else {
-                self.head = None;
-            }
+impl<K, V, const N: usize, T> Iterator
+    for ExpireOlderThan<'_, K, V, N, T>
+where
+    K: Copy + Eq + std::hash::Hash + IsEnabled,
+    T: Timestamp,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cache.tail == NONE {
+            return None;
+        }
 
-            self.free_list.push(tail_index);
-            Some((tail_node.key, tail_node.value))
+        let is_expired = unsafe { self.cache.node(self.cache.tail) }
+            .timestamp
+            < self.cutoff;
+        if is_expired {
+            self.cache.evict()
         } else {
             None
         }
     }
 }
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -502,4 +860,187 @@ mod tests {
         // Pop a non-existing key
         assert_eq!(cache.pop(&4), None);
     }
+
+    #[test]
+    fn test_peek_does_not_update_lru_order() {
+        let mut cache = LRUCache::<i32, &str, 2>::new_boxed();
+        cache.put(1, "one");
+        cache.put(2, "two");
+
+        assert_eq!(cache.peek(&1), Some(&"one"));
+        *cache.peek_mut(&1).unwrap() = "uno";
+        assert_eq!(cache.peek_lru(), Some((&1, &"uno")));
+
+        // Key 1 is still least recently used
+        assert_eq!(cache.put(3, "three"), (Some((1, "uno")), false));
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut cache = LRUCache::<i32, &str, 3>::new_boxed();
+        assert_eq!(cache.iter().next(), None);
+        cache.put(1, "one");
+        cache.put(2, "two");
+        cache.put(3, "three");
+        cache.get(1);
+
+        let keys: Vec<i32> = cache.iter().map(|(&k, _)| k).collect();
+        assert_eq!(keys, vec![1, 3, 2]);
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn test_expire_older_than() {
+        let mut cache = LRUCache::<i32, &str, 4, u64>::new_boxed();
+        cache.put_with_timestamp(1, "one", 10);
+        cache.put_with_timestamp(2, "two", 20);
+        cache.put_with_timestamp(3, "three", 30);
+
+        // Restamping a duplicate moves it to the front
+        cache.put_with_timestamp(1, "one", 40);
+
+        let expired: Vec<(i32, &str)> =
+            cache.expire_older_than(35).collect();
+        assert_eq!(expired, vec![(2, "two"), (3, "three")]);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.peek_lru(), Some((&1, &"one")));
+
+        // Only yielded entries are expired
+        cache.put_with_timestamp(4, "four", 50);
+        cache.put_with_timestamp(5, "five", 50);
+        assert_eq!(
+            cache
+                .expire_older_than(55)
+                .take(1)
+                .count(),
+            1
+        );
+        assert!(!cache.contains(1));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.expire_older_than(45).count(), 0);
+
+        // Freed slots are reused
+        for i in 6..8 {
+            assert_eq!(
+                cache.put_with_timestamp(i, "new", 60),
+                (None, false)
+            );
+        }
+        assert_eq!(cache.len(), 4);
+    }
+
+    #[test]
+    fn test_put_new_keeps_duplicate() {
+        let mut cache = LRUCache::<u64, u64, 4, u64>::new_boxed();
+        cache.put_with_timestamp(1, 10, 100);
+        cache.put_with_timestamp(2, 20, 200);
+
+        assert_eq!(
+            cache.put_new_with_timestamp(1, 11, 300),
+            (None, true)
+        );
+        assert_eq!(cache.peek(&1), Some(&10));
+
+        // Still the oldest, with its original timestamp
+        let expired: Vec<_> = cache.expire_older_than(150).collect();
+        assert_eq!(expired, [(1, 10)]);
+    }
+
+    #[test]
+    fn test_timestamps_are_opt_in() {
+        // Untimestamped nodes are a key, a value and two links
+        assert_eq!(size_of::<Node<u64, u64, ()>>(), 24);
+        assert_eq!(size_of::<Node<u64, u64, u64>>(), 32);
+
+        // Without timestamps nothing is older than anything else
+        let mut cache = LRUCache::<i32, &str, 2>::new_boxed();
+        cache.put(1, "one");
+        assert_eq!(cache.expire_older_than(()).count(), 0);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_churn_matches_reference() {
+        // Small capacity with many collisions exercises probe
+        // wraparound and backward shift deletion
+        let mut cache = LRUCache::<u64, u64, 64>::new_boxed();
+        let mut reference: Vec<(u64, u64)> = vec![]; // MRU first
+
+        let mut rng = 0x2545f4914f6cdd1d_u64;
+        for i in 0..100_000_u64 {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            let key = rng % 200;
+
+            match rng % 3 {
+                0 => {
+                    let popped = cache.pop(&key);
+                    let position = reference
+                        .iter()
+                        .position(|&(k, _)| k == key);
+                    assert_eq!(
+                        popped,
+                        position.map(|p| reference.remove(p))
+                    );
+                }
+                1 => {
+                    let value = cache.get(key).copied();
+                    let position = reference
+                        .iter()
+                        .position(|&(k, _)| k == key);
+                    assert_eq!(value, position.map(|p| reference[p].1));
+                    if let Some(p) = position {
+                        let entry = reference.remove(p);
+                        reference.insert(0, entry);
+                    }
+                }
+                _ => {
+                    let position = reference
+                        .iter()
+                        .position(|&(k, _)| k == key);
+                    let evicted = match position {
+                        Some(p) => {
+                            reference.remove(p);
+                            None
+                        }
+                        None if reference.len() == 64 => {
+                            reference.pop()
+                        }
+                        None => None,
+                    };
+                    reference.insert(0, (key, i));
+                    assert_eq!(
+                        cache.put(key, i),
+                        (evicted, position.is_some())
+                    );
+                }
+            }
+
+            assert_eq!(cache.len(), reference.len());
+        }
+
+        let entries: Vec<(u64, u64)> = cache
+            .iter()
+            .map(|(&k, &v)| (k, v))
+            .collect();
+        assert_eq!(entries, reference);
+    }
+
+    #[test]
+    fn test_drops_values() {
+        use std::rc::Rc;
+
+        let value = Rc::new(());
+        {
+            let mut cache = LRUCache::<i32, Rc<()>, 2>::new_boxed();
+            cache.put(1, value.clone());
+            cache.put(1, value.clone()); // Overwrite drops the old one
+            cache.put(2, value.clone());
+            cache.put(3, value.clone()); // Evicted value is returned
+            cache.pop(&2);
+            assert_eq!(Rc::strong_count(&value), 2);
+        }
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
 }
@@ -0,0 +1,224 @@
+//! A sharded [LRUCache] that can be shared by multiple threads.
+
+use std::hash::BuildHasher;
+use std::sync::{Mutex, MutexGuard};
+
+use nohash_hasher::{BuildNoHashHasher, IsEnabled};
+
+use crate::{LRUCache, Timestamp};
+
+type Shard<K, V, const N: usize, T> = Mutex<Box<LRUCache<K, V, N, T>>>;
+
+/// `SHARDS` independently locked caches of capacity `N` each. A key
+/// always maps to the same shard, so duplicate detection is exactly
+/// that of a single cache. When a shard is full, the least recently
+/// used entry of that shard is evicted, which approximates global LRU
+/// order for uniformly hashed keys.
+pub struct ShardedLRUCache<
+    K: Copy + Eq + std::hash::Hash + IsEnabled,
+    V,
+    const SHARDS: usize,
+    const N: usize,
+    T: Timestamp = (),
+> {
+    shards: Box<[Shard<K, V, N, T>]>,
+}
+
+impl<K, V, const SHARDS: usize, const N: usize, T>
+    ShardedLRUCache<K, V, SHARDS, N, T>
+where
+    K: Copy + Eq + std::hash::Hash + IsEnabled,
+    T: Timestamp,
+{
+    const _ASSERT_SHARDS: () = assert!(
+        SHARDS > 0 && SHARDS <= u32::MAX as usize,
+        "number of shards must be nonzero and fit in a u32"
+    );
+
+    pub fn new() -> Self {
+        let () = Self::_ASSERT_SHARDS;
+
+        ShardedLRUCache {
+            shards: (0..SHARDS)
+                .map(|_| Mutex::new(LRUCache::new_boxed()))
+                .collect(),
+        }
+    }
+
+    /// Same as [LRUCache::contains]
+    pub fn contains(&self, key: K) -> bool {
+        self.shard(&key).contains(key)
+    }
+
+    /// Same as [LRUCache::pop]
+    pub fn pop(&self, key: &K) -> Option<(K, V)> {
+        self.shard(key).pop(key)
+    }
+
+    /// Same as [LRUCache::put]. An evicted entry is from the same shard
+    /// as `key`.
+    pub fn put(&self, key: K, value: V) -> (Option<(K, V)>, bool) {
+        self.shard(&key).put(key, value)
+    }
+
+    /// Same as [LRUCache::put_with_timestamp]
+    pub fn put_with_timestamp(
+        &self,
+        key: K,
+        value: V,
+        timestamp: T,
+    ) -> (Option<(K, V)>, bool) {
+        self.shard(&key)
+            .put_with_timestamp(key, value, timestamp)
+    }
+
+    /// Same as [LRUCache::put_new_with_timestamp]
+    pub fn put_new_with_timestamp(
+        &self,
+        key: K,
+        value: V,
+        timestamp: T,
+    ) -> (Option<(K, V)>, bool) {
+        self.shard(&key)
+            .put_new_with_timestamp(key, value, timestamp)
+    }
+
+    /// Total number of entries. Shards are locked one at a time, so
+    /// this is not a snapshot under concurrent writes.
+    pub fn len(&self) -> usize {
+        (0..SHARDS)
+            .map(|index| self.lock_shard(index).len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total capacity across shards
+    pub const fn capacity(&self) -> usize {
+        SHARDS * N
+    }
+
+    /// Locks the shard holding `key`, for operations not mirrored here
+    /// (e.g. [LRUCache::peek_mut])
+    pub fn shard(
+        &self,
+        key: &K,
+    ) -> MutexGuard<'_, Box<LRUCache<K, V, N, T>>> {
+        self.lock_shard(Self::shard_index(key))
+    }
+
+    /// Locks the shard at `index`, e.g. to expire entries shard by
+    /// shard
+    pub fn lock_shard(
+        &self,
+        index: usize,
+    ) -> MutexGuard<'_, Box<LRUCache<K, V, N, T>>> {
+        // A panic while holding a lock is fatal to the pipeline anyway
+        self.shards[index].lock().unwrap()
+    }
+
+    /// Maps a key to [0, SHARDS) without a modulo. Uses the lower half
+    /// of the mixed hash, as the upper half picks the table slot within
+    /// the shard.
+    #[inline(always)]
+    fn shard_index(key: &K) -> usize {
+        let hash = BuildNoHashHasher::<K>::default()
+            .hash_one(key)
+            .wrapping_mul(0x9e3779b97f4a7c15) as u32;
+        ((hash as u64 * SHARDS as u64) >> 32) as usize
+    }
+}
+
+impl<K, V, const SHARDS: usize, const N: usize, T> Default
+    for ShardedLRUCache<K, V, SHARDS, N, T>
+where
+    K: Copy + Eq + std::hash::Hash + IsEnabled,
+    T: Timestamp,
+{
+    fn default() -> Self {
+        ShardedLRUCache::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn test_matches_single_shard() {
+        let sharded = ShardedLRUCache::<u64, u64, 1, 64>::new();
+        let mut reference = LRUCache::<u64, u64, 64>::new_boxed();
+
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        for i in 0..10_000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let key = state % 256;
+            if i % 3 == 0 {
+                assert_eq!(sharded.pop(&key), reference.pop(&key));
+            } else {
+                assert_eq!(sharded.put(key, i), reference.put(key, i));
+            }
+            assert_eq!(sharded.contains(key), reference.contains(key));
+        }
+        assert_eq!(sharded.len(), reference.len());
+    }
+
+    #[test]
+    fn test_duplicates_and_eviction() {
+        let cache = ShardedLRUCache::<u64, u64, 4, 8>::new();
+        assert_eq!(cache.capacity(), 32);
+
+        for key in 0..1000 {
+            let (evicted, duplicate) = cache.put(key, key);
+            assert!(!duplicate);
+
+            // Eviction only happens within the key's shard
+            if let Some((evicted, _)) = evicted {
+                assert_eq!(
+                    ShardedLRUCache::<u64, u64, 4, 8>::shard_index(
+                        &evicted
+                    ),
+                    ShardedLRUCache::<u64, u64, 4, 8>::shard_index(
+                        &key
+                    ),
+                );
+            }
+            assert_eq!(cache.put(key, key), (None, true));
+        }
+        assert_eq!(cache.len(), 32);
+    }
+
+    #[test]
+    fn test_concurrent_puts() {
+        const THREADS: u64 = 4;
+        const KEYS: u64 = 10_000;
+        let cache =
+            Arc::new(ShardedLRUCache::<u64, (), 16, 4096>::new());
+
+        // Every thread inserts the same keys. Exactly one insert of
+        // each key is not a duplicate.
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let cache = Arc::clone(&cache);
+                std::thread::spawn(move || {
+                    (0..KEYS)
+                        .filter(|&key| !cache.put(key, ()).1)
+                        .count()
+                })
+            })
+            .collect();
+        let inserted: usize = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .sum();
+
+        assert_eq!(inserted, KEYS as usize);
+        assert_eq!(cache.len(), KEYS as usize);
+    }
+}
@@ -0,0 +1,441 @@
+//! Placement of an [LRUCache] in externally owned (e.g. shared) memory,
+//! behind a layout header so that a restarted process can reattach to
+//! its cache.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use nohash_hasher::IsEnabled;
+
+use crate::{LRUCache, Timestamp};
+
+const MAGIC: u64 = u64::from_le_bytes(*b"QOS-LRU\0");
+
+/// Bumped whenever the layout of [LRUCache] changes
+pub const LAYOUT_VERSION: u32 = 5;
+
+const STATE_ATTACHED: u32 = 1;
+const STATE_DETACHED: u32 = 2;
+const STATE_INITIALIZING: u32 = 3;
+
+#[repr(C, align(128))]
+struct LRUHeader {
+    magic: u64,
+
+    /// The state in the low half and the pid of the process that last
+    /// attached in the high half, so that both change in one
+    /// compare-and-swap. A cache left attached by a crashed process
+    /// may have been interrupted mid-operation.
+    lock: AtomicU64,
+
+    version: u32,
+
+    /// Version of the value layout, chosen by the caller
+    value_version: u32,
+
+    key_size: u32,
+    key_align: u32,
+    value_size: u32,
+    value_align: u32,
+    timestamp_size: u32,
+    capacity: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutError {
+    /// The region does not hold an LRUCache
+    BadMagic,
+
+    /// The region was written by an incompatible version
+    Version { found: u32, expected: u32 },
+
+    /// The region holds a cache of different key, value, timestamp or
+    /// capacity
+    Mismatch,
+
+    /// The region holds values of a different layout version
+    ValueVersion { found: u32, expected: u32 },
+
+    /// Another process is attached or initializing, or the last one
+    /// did not detach (e.g. it crashed). `owner` is the pid that last
+    /// attached.
+    NotDetached { owner: u32 },
+}
+
+/// How [LRUCache::join_or_initialize] attached to a cache
+pub enum Joined<'a, C> {
+    /// The existing cache was joined
+    Restored(&'a mut C),
+
+    /// An empty cache was initialized, as joining failed with this
+    /// error
+    Initialized(&'a mut C, LayoutError),
+}
+
+impl<K, V, const N: usize, T> LRUCache<K, V, N, T>
+where
+    K: Copy + Eq + std::hash::Hash + IsEnabled,
+    V: Copy,
+    T: Timestamp,
+{
+    /// Bytes required by [LRUCache::initialize_in]
+    pub const SHARED_SIZE: usize =
+        size_of::<LRUHeader>() + size_of::<Self>();
+
+    /// Initializes an empty cache in `ptr`, overwriting whatever was
+    /// there, and attaches to it.
+    ///
+    /// `value_version` identifies the layout of `V`, which size and
+    /// alignment alone do not. It must change whenever that does.
+    ///
+    /// # Safety
+    /// `ptr` must be valid for [LRUCache::SHARED_SIZE] bytes, aligned
+    /// to 128 bytes and not otherwise accessed while attached. Keys and
+    /// values must not contain pointers if the region is shared.
+    pub unsafe fn initialize_in<'a>(
+        ptr: *mut u8,
+        value_version: u32,
+    ) -> &'a mut Self {
+        let () = Self::_ASSERT_CAPACITY;
+        assert_eq!(ptr.align_offset(128), 0, "misaligned LRUCache");
+
+        Self::write_empty(ptr, value_version)
+    }
+
+    /// Joins the cache in `ptr` like [LRUCache::join], or initializes
+    /// an empty one unless a process that `is_running` is attached.
+    ///
+    /// The region is claimed with a compare-and-swap before it is
+    /// initialized, so that of several processes racing to initialize
+    /// it one does and the others join again, and find it attached.
+    ///
+    /// Fails with the pid of the running process attached to the
+    /// cache.
+    ///
+    /// # Safety
+    /// Same as [LRUCache::initialize_in]
+    pub unsafe fn join_or_initialize<'a>(
+        ptr: *mut u8,
+        value_version: u32,
+        is_running: impl Fn(u32) -> bool,
+    ) -> Result<Joined<'a, Self>, u32> {
+        let () = Self::_ASSERT_CAPACITY;
+        assert_eq!(ptr.align_offset(128), 0, "misaligned LRUCache");
+
+        let header = &*ptr.cast::<LRUHeader>();
+        loop {
+            let lock = header.lock.load(Ordering::Acquire);
+            let error =
+                match Self::join_locked(ptr, value_version, lock) {
+                    Ok(cache) => return Ok(Joined::Restored(cache)),
+                    Err(LayoutError::NotDetached { owner })
+                        if is_running(owner) =>
+                    {
+                        return Err(owner)
+                    }
+                    Err(e) => e,
+                };
+
+            // Another process may have claimed the region since
+            if Self::claim(ptr, lock) {
+                let cache = Self::write_empty(ptr, value_version);
+                return Ok(Joined::Initialized(cache, error));
+            }
+        }
+    }
+
+    /// Claims the region for initializing if its lock still reads
+    /// `lock`
+    unsafe fn claim(ptr: *mut u8, lock: u64) -> bool {
+        let claimed = pack_lock(STATE_INITIALIZING, std::process::id());
+        (*ptr.cast::<LRUHeader>())
+            .lock
+            .compare_exchange(
+                lock,
+                claimed,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+    }
+
+    /// Writes an empty cache to `ptr`, claimed by this process
+    unsafe fn write_empty<'a>(
+        ptr: *mut u8,
+        value_version: u32,
+    ) -> &'a mut Self {
+        let cache = ptr.add(size_of::<LRUHeader>());
+
+        // Zero is the empty state
+        cache.write_bytes(0, size_of::<Self>());
+
+        let owner = std::process::id();
+        ptr.cast::<LRUHeader>()
+            .write(LRUHeader {
+                magic: MAGIC,
+                lock: AtomicU64::new(pack_lock(
+                    STATE_INITIALIZING,
+                    owner,
+                )),
+                version: LAYOUT_VERSION,
+                value_version,
+                key_size: size_of::<K>() as u32,
+                key_align: align_of::<K>() as u32,
+                value_size: size_of::<V>() as u32,
+                value_align: align_of::<V>() as u32,
+                timestamp_size: size_of::<T>() as u32,
+                capacity: N as u64,
+            });
+
+        // Publish
+        (*ptr.cast::<LRUHeader>())
+            .lock
+            .store(pack_lock(STATE_ATTACHED, owner), Ordering::Release);
+
+        &mut *cache.cast::<Self>()
+    }
+
+    /// Attaches to a cache previously initialized in `ptr` with the
+    /// same `value_version` and cleanly detached with
+    /// [LRUCache::detach_in].
+    ///
+    /// # Safety
+    /// Same as [LRUCache::initialize_in]
+    pub unsafe fn join<'a>(
+        ptr: *mut u8,
+        value_version: u32,
+    ) -> Result<&'a mut Self, LayoutError> {
+        assert_eq!(ptr.align_offset(128), 0, "misaligned LRUCache");
+
+        let lock = (*ptr.cast::<LRUHeader>())
+            .lock
+            .load(Ordering::Acquire);
+        Self::join_locked(ptr, value_version, lock)
+    }
+
+    /// Joins the cache in `ptr` if its lock still reads `lock`
+    unsafe fn join_locked<'a>(
+        ptr: *mut u8,
+        value_version: u32,
+        lock: u64,
+    ) -> Result<&'a mut Self, LayoutError> {
+        let (state, owner) = unpack_lock(lock);
+
+        // The layout is being written
+        if state == STATE_INITIALIZING {
+            return Err(LayoutError::NotDetached { owner });
+        }
+
+        let header = &*ptr.cast::<LRUHeader>();
+        if header.magic != MAGIC {
+            return Err(LayoutError::BadMagic);
+        }
+        if header.version != LAYOUT_VERSION {
+            return Err(LayoutError::Version {
+                found: header.version,
+                expected: LAYOUT_VERSION,
+            });
+        }
+        if header.key_size != size_of::<K>() as u32
+            || header.key_align != align_of::<K>() as u32
+            || header.value_size != size_of::<V>() as u32
+            || header.value_align != align_of::<V>() as u32
+            || header.timestamp_size != size_of::<T>() as u32
+            || header.capacity != N as u64
+        {
+            return Err(LayoutError::Mismatch);
+        }
+        if header.value_version != value_version {
+            return Err(LayoutError::ValueVersion {
+                found: header.value_version,
+                expected: value_version,
+            });
+        }
+        if state != STATE_DETACHED {
+            return Err(LayoutError::NotDetached { owner });
+        }
+        let attached = pack_lock(STATE_ATTACHED, std::process::id());
+        if let Err(lock) = header.lock.compare_exchange(
+            lock,
+            attached,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            let (_state, owner) = unpack_lock(lock);
+            return Err(LayoutError::NotDetached { owner });
+        }
+
+        Ok(&mut *ptr
+            .add(size_of::<LRUHeader>())
+            .cast::<Self>())
+    }
+
+    /// Marks the cache in `ptr` as cleanly detached so that it can be
+    /// joined again. The cache must not be used afterwards.
+    ///
+    /// # Safety
+    /// `ptr` must hold a cache attached via [LRUCache::initialize_in]
+    /// or [LRUCache::join].
+    pub unsafe fn detach_in(ptr: *mut u8) {
+        (*ptr.cast::<LRUHeader>()).lock.store(
+            pack_lock(STATE_DETACHED, std::process::id()),
+            Ordering::Release,
+        );
+    }
+}
+
+fn pack_lock(state: u32, owner: u32) -> u64 {
+    (owner as u64) << 32 | state as u64
+}
+
+fn unpack_lock(lock: u64) -> (u32, u32) {
+    (lock as u32, (lock >> 32) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{alloc_zeroed, dealloc, Layout};
+
+    use super::*;
+
+    type Cache = LRUCache<u64, u64, 16, u64>;
+
+    fn with_region(f: impl FnOnce(*mut u8)) {
+        let layout =
+            Layout::from_size_align(Cache::SHARED_SIZE, 128).unwrap();
+        unsafe {
+            let ptr = alloc_zeroed(layout);
+            f(ptr);
+            dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn rejoin_after_detach() {
+        with_region(|ptr| unsafe {
+            let cache = Cache::initialize_in(ptr, 1);
+            cache.put_with_timestamp(1, 10, 100);
+            cache.put(2, 20);
+
+            // Still attached
+            assert_eq!(
+                Cache::join(ptr, 1).err(),
+                Some(LayoutError::NotDetached {
+                    owner: std::process::id()
+                })
+            );
+
+            Cache::detach_in(ptr);
+            let cache = Cache::join(ptr, 1).unwrap();
+            assert_eq!(cache.len(), 2);
+            assert_eq!(cache.pop(&1), Some((1, 10)));
+            assert_eq!(cache.peek(&2), Some(&20));
+        });
+    }
+
+    #[test]
+    fn join_or_initialize_claims_once() {
+        with_region(|ptr| unsafe {
+            let owner = std::process::id();
+            let Ok(Joined::Initialized(cache, LayoutError::BadMagic)) =
+                Cache::join_or_initialize(ptr, 1, |_| true)
+            else {
+                panic!("expected a fresh cache");
+            };
+            cache.put(1, 10);
+
+            // Attached by a running process
+            assert!(matches!(
+                Cache::join_or_initialize(ptr, 1, |_| true),
+                Err(pid) if pid == owner
+            ));
+
+            // Attached by a crashed process
+            let Ok(Joined::Initialized(cache, error)) =
+                Cache::join_or_initialize(ptr, 1, |_| false)
+            else {
+                panic!("expected a reinitialized cache");
+            };
+            assert_eq!(error, LayoutError::NotDetached { owner });
+            assert_eq!(cache.len(), 0);
+
+            Cache::detach_in(ptr);
+            assert!(matches!(
+                Cache::join_or_initialize(ptr, 1, |_| true),
+                Ok(Joined::Restored(_))
+            ));
+
+            // Another process is initializing the region, or crashed
+            // while doing so
+            let header = &*ptr.cast::<LRUHeader>();
+            header.lock.store(
+                pack_lock(STATE_INITIALIZING, 7),
+                Ordering::Release,
+            );
+            assert_eq!(
+                Cache::join(ptr, 1).err(),
+                Some(LayoutError::NotDetached { owner: 7 })
+            );
+            assert!(matches!(
+                Cache::join_or_initialize(ptr, 1, |_| true),
+                Err(7)
+            ));
+            assert!(matches!(
+                Cache::join_or_initialize(ptr, 1, |_| false),
+                Ok(Joined::Initialized(_, _))
+            ));
+        });
+    }
+
+    #[test]
+    fn stale_claims_fail() {
+        with_region(|ptr| unsafe {
+            // Two processes saw an empty region, and the other one
+            // claimed it first
+            assert!(Cache::claim(ptr, 0));
+            assert!(!Cache::claim(ptr, 0));
+            Cache::write_empty(ptr, 1);
+            assert!(!Cache::claim(ptr, 0));
+
+            // So the loser joins again, and finds it attached
+            assert_eq!(
+                Cache::join(ptr, 1).err(),
+                Some(LayoutError::NotDetached {
+                    owner: std::process::id()
+                })
+            );
+        });
+    }
+
+    #[test]
+    fn rejects_foreign_layouts() {
+        with_region(|ptr| unsafe {
+            assert_eq!(
+                Cache::join(ptr, 1).err(),
+                Some(LayoutError::BadMagic)
+            );
+
+            Cache::initialize_in(ptr, 1);
+            Cache::detach_in(ptr);
+            assert_eq!(
+                LRUCache::<u64, u32, 16, u64>::join(ptr, 1).err(),
+                Some(LayoutError::Mismatch)
+            );
+            assert_eq!(
+                LRUCache::<u64, u64, 8, u64>::join(ptr, 1).err(),
+                Some(LayoutError::Mismatch)
+            );
+            assert_eq!(
+                LRUCache::<u64, u64, 16>::join(ptr, 1).err(),
+                Some(LayoutError::Mismatch)
+            );
+            assert_eq!(
+                Cache::join(ptr, 2).err(),
+                Some(LayoutError::ValueVersion {
+                    found: 1,
+                    expected: 2
+                })
+            );
+            assert!(Cache::join(ptr, 1).is_ok());
+        });
+    }
+}
@@ -1,7 +1,7 @@
 use std::array::from_fn;
 
 use criterion::{criterion_group, criterion_main, Criterion};
-use qos_minmax::MinMaxHeap;
+use qos_minmax::{IndexedMinMaxHeap, MinMaxHeap};
 
 fn throughput(c: &mut Criterion) {
     type Element = [u8; 1232];
@@ -38,5 +38,45 @@ fn throughput(c: &mut Criterion) {
     g.finish();
 }
 
-criterion_group!(minmax, throughput);
+fn indexed_throughput(c: &mut Criterion) {
+    type Element = [u8; 1232];
+    const N: usize = 15_000_usize.next_power_of_two();
+
+    let mut heap = IndexedMinMaxHeap::<u64, Element, N>::new();
+
+    while heap.len() < N - 1 {
+        heap.push(0, [1; 1232]);
+    }
+
+    let mut values = vec![];
+    for _ in 0..N {
+        values.push(from_fn::<u8, 1232, _>(|_| rand::random::<u8>()));
+    }
+
+    let mut priorities = vec![];
+    for _ in 0..N {
+        priorities.push(rand::random::<u64>());
+    }
+
+    let mut indices = vec![];
+    for _ in 0..N {
+        indices.push(rand::random::<usize>() % N);
+    }
+
+    let mut g = c.benchmark_group("Push");
+    g.throughput(criterion::Throughput::Bytes(1232));
+
+    let mut i = 0;
+    g.bench_function("indexed-full-push", |b| {
+        b.iter(|| {
+            let index = indices[i & (N - 1)];
+            heap.push(priorities[index], values[index]);
+            i += 1;
+        });
+    });
+
+    g.finish();
+}
+
+criterion_group!(minmax, throughput, indexed_throughput);
 criterion_main!(minmax);
@@ -0,0 +1,604 @@
+use std::mem::MaybeUninit;
+
+/// Heap entry. Orders by priority, then slot so that ties are broken
+/// consistently.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Entry<P> {
+    priority: P,
+    slot: u32,
+}
+
+/// Refers to a value pushed into an [IndexedMinMaxHeap]. A handle goes
+/// stale once its value is popped, evicted or removed, even if the slot
+/// is reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Handle {
+    slot: u32,
+    generation: u32,
+}
+
+impl Handle {
+    /// Slab slot of the value, in `[0, N)`. Live handles have distinct
+    /// slots, so this can index per value side tables.
+    #[inline(always)]
+    pub fn slot(&self) -> usize {
+        self.slot as usize
+    }
+}
+
+/// A fixed capacity min-max heap over large values.
+///
+/// The heap only holds `(priority, slot)` pairs, so sifts move a few
+/// bytes regardless of the size of `T`. Values stay put in a slab of N
+/// slots until popped or evicted. The heap position of every slot is
+/// tracked, so values can be removed or reprioritized via a [Handle].
+///
+/// Has the same capacity semantics as [crate::MinMaxHeap].
+pub struct IndexedMinMaxHeap<P, T, const N: usize> {
+    /// Array min-max heap. Even levels are min levels.
+    heap: Vec<Entry<P>>,
+
+    /// Heap index of each referenced slot
+    positions: Box<[u32]>,
+
+    /// Bumped whenever a slot is freed, invalidating its handles
+    generations: Box<[u32]>,
+
+    /// A slot is initialized iff it is referenced by the heap
+    slab: Box<[MaybeUninit<T>]>,
+
+    /// Unreferenced slots
+    free: Vec<u32>,
+}
+
+impl<P: Ord + Copy, T, const N: usize> IndexedMinMaxHeap<P, T, N> {
+    const _ASSERT: () = assert!(N > 0 && N <= u32::MAX as usize);
+
+    pub fn new() -> IndexedMinMaxHeap<P, T, N> {
+        let () = Self::_ASSERT;
+
+        IndexedMinMaxHeap {
+            heap: Vec::with_capacity(N),
+            positions: vec![0; N].into_boxed_slice(),
+            generations: vec![0; N].into_boxed_slice(),
+            slab: (0..N)
+                .map(|_| MaybeUninit::uninit())
+                .collect(),
+            // Reversed so that low slots are handed out first
+            free: (0..N as u32).rev().collect(),
+        }
+    }
+
+    /// If the inner heap is at capacity, this returns the minimum value,
+    /// which may be the one just pushed (its handle is then stale)
+    #[inline(always)]
+    pub fn push(
+        &mut self,
+        priority: P,
+        value: T,
+    ) -> (Handle, Option<(P, T)>) {
+        // At most N - 1 slots are referenced outside of this scope
+        let slot = unsafe { self.free.pop().unwrap_unchecked() };
+        unsafe {
+            self.slab
+                .get_unchecked_mut(slot as usize)
+        }
+        .write(value);
+        let handle = Handle {
+            slot,
+            generation: self.generations[slot as usize],
+        };
+
+        let index = self.heap.len();
+        self.heap.push(Entry { priority, slot });
+        self.positions[slot as usize] = index as u32;
+        self.bubble_up(index);
+
+        // Evict minimum value if full
+        if self.heap.len() == N {
+            return (handle, self.pop_min());
+        }
+
+        (handle, None)
+    }
+
+    #[inline(always)]
+    pub fn pop_max(&mut self) -> Option<(P, T)> {
+        let index = self.max_index()?;
+        Some(unsafe { self.remove_at(index) })
+    }
+
+    #[inline(always)]
+    pub fn pop_min(&mut self) -> Option<(P, T)> {
+        if self.heap.is_empty() {
+            return None;
+        }
+        Some(unsafe { self.remove_at(0) })
+    }
+
+    #[inline(always)]
+    pub fn peek_max(&self) -> Option<(P, &T)> {
+        let entry = self.heap[self.max_index()?];
+        Some((entry.priority, unsafe { self.value(entry.slot) }))
+    }
+
+    #[inline(always)]
+    pub fn peek_min(&self) -> Option<(P, &T)> {
+        let entry = self.heap.first()?;
+        Some((entry.priority, unsafe { self.value(entry.slot) }))
+    }
+
+    /// Whether the handle's value is still in the heap
+    #[inline(always)]
+    pub fn contains(&self, handle: Handle) -> bool {
+        self.position(handle).is_some()
+    }
+
+    #[inline(always)]
+    pub fn get(&self, handle: Handle) -> Option<(P, &T)> {
+        let index = self.position(handle)?;
+        let entry = self.heap[index];
+        Some((entry.priority, unsafe { self.value(entry.slot) }))
+    }
+
+    /// Mutable access to the handle's value. Its priority is unchanged.
+    #[inline(always)]
+    pub fn get_mut(&mut self, handle: Handle) -> Option<&mut T> {
+        let index = self.position(handle)?;
+        let slot = self.heap[index].slot as usize;
+        Some(unsafe { self.slab[slot].assume_init_mut() })
+    }
+
+    /// The value at position `index` of the underlying array, in no
+    /// particular order. Useful for sweeping over values a few at a
+    /// time, though values move as others are pushed or removed.
+    #[inline(always)]
+    pub fn nth(&self, index: usize) -> Option<(Handle, P, &T)> {
+        let entry = *self.heap.get(index)?;
+        Some(self.resolve(entry))
+    }
+
+    /// Removes the handle's value. Returns `None` if it is stale.
+    pub fn remove(&mut self, handle: Handle) -> Option<(P, T)> {
+        let index = self.position(handle)?;
+        Some(unsafe { self.remove_at(index) })
+    }
+
+    /// Changes the priority of the handle's value. Returns the old
+    /// priority, or `None` if the handle is stale.
+    pub fn update_priority(
+        &mut self,
+        handle: Handle,
+        priority: P,
+    ) -> Option<P> {
+        let index = self.position(handle)?;
+        let old =
+            std::mem::replace(&mut self.heap[index].priority, priority);
+        self.restore(index);
+        Some(old)
+    }
+
+    /// The k highest priority values in descending order, without
+    /// removing them. Linear in the number of values.
+    pub fn peek_top_k(&self, k: usize) -> Vec<(Handle, P, &T)> {
+        let mut entries = self.heap.clone();
+        let k = k.min(entries.len());
+        if k == 0 {
+            return vec![];
+        }
+        if k < entries.len() {
+            entries.select_nth_unstable_by(k - 1, |a, b| b.cmp(a));
+            entries.truncate(k);
+        }
+        entries.sort_unstable_by(|a, b| b.cmp(a));
+
+        entries
+            .into_iter()
+            .map(|entry| self.resolve(entry))
+            .collect()
+    }
+
+    // Get an iterator over values in descending priority order, which
+    // pops them as it goes
+    #[inline(always)]
+    pub fn get_max_values(&mut self) -> IndexedPopDesc<'_, P, T, N> {
+        IndexedPopDesc { inner: self }
+    }
+
+    /// Iterates over all values in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (Handle, P, &T)> {
+        self.heap
+            .iter()
+            .map(|&entry| self.resolve(entry))
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    #[inline(always)]
+    fn position(&self, handle: Handle) -> Option<usize> {
+        let slot = handle.slot as usize;
+        if slot >= N || self.generations[slot] != handle.generation {
+            return None;
+        }
+
+        // Freed slots have their generation bumped, so this slot is
+        // referenced
+        Some(self.positions[slot] as usize)
+    }
+
+    #[inline(always)]
+    fn resolve(&self, entry: Entry<P>) -> (Handle, P, &T) {
+        let handle = Handle {
+            slot: entry.slot,
+            generation: self.generations[entry.slot as usize],
+        };
+        (handle, entry.priority, unsafe { self.value(entry.slot) })
+    }
+
+    #[inline(always)]
+    fn max_index(&self) -> Option<usize> {
+        match self.heap.len() {
+            0 => None,
+            1 => Some(0),
+            2 => Some(1),
+            _ => Some(if self.heap[1] > self.heap[2] { 1 } else { 2 }),
+        }
+    }
+
+    /// # Safety
+    /// `index` must be in bounds
+    #[inline(always)]
+    unsafe fn remove_at(&mut self, index: usize) -> (P, T) {
+        let last = self.heap.len() - 1;
+        self.swap(index, last);
+        let entry = self.heap.pop().unwrap_unchecked();
+        if index < last {
+            self.restore(index);
+        }
+
+        let slot = entry.slot as usize;
+        self.generations[slot] = self.generations[slot].wrapping_add(1);
+        self.free.push(entry.slot);
+        (entry.priority, self.slab[slot].assume_init_read())
+    }
+
+    /// # Safety
+    /// `slot` must be referenced by a heap entry
+    #[inline(always)]
+    unsafe fn value(&self, slot: u32) -> &T {
+        self.slab
+            .get_unchecked(slot as usize)
+            .assume_init_ref()
+    }
+
+    #[inline(always)]
+    fn swap(&mut self, a: usize, b: usize) {
+        self.heap.swap(a, b);
+        self.positions[self.heap[a].slot as usize] = a as u32;
+        self.positions[self.heap[b].slot as usize] = b as u32;
+    }
+
+    /// Restores heap order after the entry at `index` was replaced or
+    /// reprioritized
+    #[inline(always)]
+    fn restore(&mut self, index: usize) {
+        let slot = self.heap[index].slot as usize;
+        self.trickle_down(index);
+        self.bubble_up(self.positions[slot] as usize);
+    }
+
+    #[inline(always)]
+    fn bubble_up(&mut self, index: usize) {
+        if index == 0 {
+            return;
+        }
+
+        let parent = (index - 1) / 2;
+        if is_min_level(index) {
+            if self.heap[index] > self.heap[parent] {
+                self.swap(index, parent);
+                self.bubble_up_by(parent, |a, b| a > b);
+            } else {
+                self.bubble_up_by(index, |a, b| a < b);
+            }
+        } else if self.heap[index] < self.heap[parent] {
+            self.swap(index, parent);
+            self.bubble_up_by(parent, |a, b| a < b);
+        } else {
+            self.bubble_up_by(index, |a, b| a > b);
+        }
+    }
+
+    /// Moves an entry up through grandparents while it is `before`
+    /// them
+    #[inline(always)]
+    fn bubble_up_by(
+        &mut self,
+        mut index: usize,
+        before: impl Fn(&Entry<P>, &Entry<P>) -> bool,
+    ) {
+        while index > 2 {
+            let grandparent = (index - 3) / 4;
+            if !before(&self.heap[index], &self.heap[grandparent]) {
+                break;
+            }
+            self.swap(index, grandparent);
+            index = grandparent;
+        }
+    }
+
+    #[inline(always)]
+    fn trickle_down(&mut self, index: usize) {
+        if is_min_level(index) {
+            self.trickle_down_by(index, |a, b| a < b);
+        } else {
+            self.trickle_down_by(index, |a, b| a > b);
+        }
+    }
+
+    /// Moves an entry down while a child or grandchild is `before` it.
+    /// For min levels `before` is less than, for max levels greater.
+    #[inline(always)]
+    fn trickle_down_by(
+        &mut self,
+        mut index: usize,
+        before: impl Fn(&Entry<P>, &Entry<P>) -> bool,
+    ) {
+        let len = self.heap.len();
+        loop {
+            let first_child = 2 * index + 1;
+            if first_child >= len {
+                return;
+            }
+
+            // Find the first in order among children and grandchildren
+            let first_grandchild = 2 * first_child + 1;
+            let mut next = first_child;
+            for candidate in [first_child + 1]
+                .into_iter()
+                .chain(first_grandchild..first_grandchild + 4)
+                .filter(|&candidate| candidate < len)
+            {
+                if before(&self.heap[candidate], &self.heap[next]) {
+                    next = candidate;
+                }
+            }
+
+            if !before(&self.heap[next], &self.heap[index]) {
+                return;
+            }
+            self.swap(index, next);
+            if next < first_grandchild {
+                return;
+            }
+
+            // Grandchild. Keep the level between in order too.
+            let parent = (next - 1) / 2;
+            if before(&self.heap[parent], &self.heap[next]) {
+                self.swap(next, parent);
+            }
+            index = next;
+        }
+    }
+}
+
+#[inline(always)]
+fn is_min_level(index: usize) -> bool {
+    (index + 1).ilog2() & 1 == 0
+}
+
+impl<P: Ord + Copy, T, const N: usize> Default
+    for IndexedMinMaxHeap<P, T, N>
+{
+    fn default() -> Self {
+        IndexedMinMaxHeap::new()
+    }
+}
+
+impl<P, T, const N: usize> Drop for IndexedMinMaxHeap<P, T, N> {
+    fn drop(&mut self) {
+        if !core::mem::needs_drop::<T>() {
+            return;
+        }
+
+        for entry in self.heap.iter() {
+            unsafe {
+                self.slab[entry.slot as usize].assume_init_drop()
+            };
+        }
+    }
+}
+
+pub struct IndexedPopDesc<'a, P, T, const N: usize> {
+    inner: &'a mut IndexedMinMaxHeap<P, T, N>,
+}
+
+impl<P: Ord + Copy, T, const N: usize> Iterator
+    for IndexedPopDesc<'_, P, T, N>
+{
+    type Item = (P, T);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.pop_max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, rc::Rc};
+
+    use super::*;
+
+    #[test]
+    fn max_capacity() {
+        let mut heap = IndexedMinMaxHeap::<u32, &str, 4>::new();
+
+        assert!(heap.push(3, "three").1.is_none());
+        assert!(heap.push(1, "one").1.is_none());
+        assert!(heap.push(2, "two").1.is_none());
+
+        // When filled, should return smallest value
+        assert_eq!(heap.push(4, "four").1, Some((1, "one")));
+        assert_eq!(heap.len(), 3);
+
+        // Including the value just pushed
+        let (handle, evicted) = heap.push(0, "zero");
+        assert_eq!(evicted, Some((0, "zero")));
+        assert!(!heap.contains(handle));
+    }
+
+    #[test]
+    fn get_max_values() {
+        let mut heap = IndexedMinMaxHeap::<u32, &str, 4>::new();
+
+        assert!(heap.push(3, "three").1.is_none());
+        assert!(heap.push(1, "one").1.is_none());
+        assert!(heap.push(2, "two").1.is_none());
+
+        // Ensure values are returned in descending order, and slots
+        // are reused
+        {
+            let mut iterator = heap.get_max_values();
+            assert_eq!(iterator.next(), Some((3, "three")));
+            assert_eq!(iterator.next(), Some((2, "two")));
+        }
+        assert!(heap.push(5, "five").1.is_none());
+        assert!(heap.push(0, "zero").1.is_none());
+        assert_eq!(heap.peek_min(), Some((0, &"zero")));
+        assert_eq!(heap.peek_max(), Some((5, &"five")));
+        assert_eq!(heap.push(4, "four").1, Some((0, "zero")));
+    }
+
+    #[test]
+    fn remove_and_update() {
+        let mut heap = IndexedMinMaxHeap::<u32, &str, 8>::new();
+        let (one, _) = heap.push(1, "one");
+        let (two, _) = heap.push(2, "two");
+        let (three, _) = heap.push(3, "three");
+
+        assert_eq!(heap.update_priority(one, 10), Some(1));
+        assert_eq!(heap.peek_max(), Some((10, &"one")));
+        assert_eq!(heap.remove(three), Some((3, "three")));
+        assert_eq!(heap.remove(three), None);
+        assert_eq!(heap.update_priority(three, 0), None);
+
+        // A reused slot does not revive old handles
+        let (four, _) = heap.push(4, "four");
+        assert!(!heap.contains(three));
+        assert_eq!(heap.get(four), Some((4, &"four")));
+
+        let top: Vec<_> = heap
+            .peek_top_k(2)
+            .into_iter()
+            .map(|(handle, priority, _)| (handle, priority))
+            .collect();
+        assert_eq!(top, vec![(one, 10), (four, 4)]);
+        assert_eq!(heap.len(), 3);
+        assert_eq!(heap.get(two), Some((2, &"two")));
+
+        *heap.get_mut(two).unwrap() = "deux";
+        assert_eq!(heap.get(two), Some((2, &"deux")));
+        assert_eq!(heap.nth(3), None);
+        let mut all: Vec<_> = (0..3)
+            .filter_map(|index| heap.nth(index))
+            .map(|(handle, ..)| handle)
+            .collect();
+        all.sort_by_key(|handle| heap.get(*handle).unwrap().0);
+        assert_eq!(all, vec![two, four, one]);
+    }
+
+    #[test]
+    fn matches_reference() {
+        const N: usize = 64;
+        let mut heap = IndexedMinMaxHeap::<u64, u64, N>::new();
+        let mut reference = BTreeSet::new();
+        let mut handles = vec![];
+
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for i in 0..100_000 {
+            // Distinct priorities, so ties can't order differently
+            let priority = ((next() % 1024) << 20) | i;
+            match next() % 6 {
+                0 => assert_eq!(heap.pop_max(), reference.pop_last()),
+                1 => assert_eq!(heap.pop_min(), reference.pop_first()),
+                2 if !handles.is_empty() => {
+                    let handle = handles
+                        .swap_remove(next() as usize % handles.len());
+                    if let Some(entry) = heap.remove(handle) {
+                        assert!(reference.remove(&entry));
+                    }
+                }
+                3 if !handles.is_empty() => {
+                    let handle =
+                        handles[next() as usize % handles.len()];
+                    if let Some((old, &value)) = heap.get(handle) {
+                        assert_eq!(
+                            heap.update_priority(handle, priority),
+                            Some(old)
+                        );
+                        assert!(reference.remove(&(old, value)));
+                        reference.insert((priority, value));
+                    }
+                }
+                _ => {
+                    let (handle, evicted) = heap.push(priority, i);
+                    reference.insert((priority, i));
+                    if reference.len() == N {
+                        assert_eq!(evicted, reference.pop_first());
+                    } else {
+                        assert_eq!(evicted, None);
+                    }
+                    handles.push(handle);
+                }
+            }
+            assert_eq!(heap.len(), reference.len());
+            assert_eq!(
+                heap.peek_max().map(|(p, &v)| (p, v)),
+                reference.last().copied()
+            );
+            assert_eq!(
+                heap.peek_min().map(|(p, &v)| (p, v)),
+                reference.first().copied()
+            );
+        }
+
+        let top: Vec<_> = heap
+            .peek_top_k(10)
+            .into_iter()
+            .map(|(_, p, &v)| (p, v))
+            .collect();
+        let expected: Vec<_> = reference
+            .iter()
+            .rev()
+            .take(10)
+            .copied()
+            .collect();
+        assert_eq!(top, expected);
+    }
+
+    #[test]
+    fn drops_values() {
+        let value = Rc::new(());
+        let mut heap = IndexedMinMaxHeap::<u32, Rc<()>, 8>::new();
+        for i in 0..20 {
+            heap.push(i, Rc::clone(&value));
+        }
+        heap.pop_max();
+        assert_eq!(Rc::strong_count(&value), 1 + 7 - 1);
+        drop(heap);
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+}
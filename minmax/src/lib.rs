@@ -1,3 +1,6 @@
+mod indexed;
+pub use indexed::{Handle, IndexedMinMaxHeap, IndexedPopDesc};
+
 pub struct MinMaxHeap<T, const N: usize> {
     inner: min_max_heap::MinMaxHeap<T>,
 }
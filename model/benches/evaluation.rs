@@ -5,24 +5,31 @@ use criterion::{criterion_group, criterion_main, Criterion};
 use qos_model::{
     interface::QoSModel,
     models::{
-        ip_signer::IpSignerModel, ip_signer_stake::IpSignerStakeModel,
+        ensemble::{Combiner, EnsembleModel},
+        ip_signer::IpSignerModel,
+        ip_signer_stake::IpSignerStakeModel,
     },
 };
 use rand::{seq::SliceRandom, thread_rng};
-use solana_qos_internal_common::transaction_meta::QoSTransactionMeta;
+use solana_qos_internal_common::{
+    transaction_features::TransactionFeatures,
+    transaction_meta::QoSTransactionMeta,
+};
 
 fn ip_signer(c: &mut Criterion) {
     // Fetch mock ip signer model
     let (model, ips, signers) =
         mock_ip_signer_model(100_000_000, 3_000, 10_000);
-    let some_ip = choose(&ips);
-    let some_signer = choose(&signers);
+    let features = TransactionFeatures::new_for_tests(
+        choose(&ips),
+        choose(&signers),
+    );
 
     let mut ip_signer = c.benchmark_group("IpSigner");
     ip_signer.throughput(criterion::Throughput::Elements(1));
 
     ip_signer.bench_function("IpSigner", |b| {
-        b.iter(|| black_box(model.forward(some_ip, &some_signer, &())));
+        b.iter(|| black_box(model.forward(&features, &())));
     });
 }
 
@@ -30,18 +37,48 @@ fn ip_signer_stake(c: &mut Criterion) {
     // Fetch mock ip signer model
     let (model, ips, signers) =
         mock_ip_signer_stake_model(100_000_000, 3_000, 10_000);
-    let some_ip = choose(&ips);
-    let some_signer = choose(&signers);
+    let features = TransactionFeatures::new_for_tests(
+        choose(&ips),
+        choose(&signers),
+    );
 
     let mut ip_signer = c.benchmark_group("IpSignerStake");
     ip_signer.throughput(criterion::Throughput::Elements(1));
 
     ip_signer.bench_function("IpSignerStake", |b| {
-        b.iter(|| black_box(model.forward(some_ip, &some_signer, &())));
+        b.iter(|| black_box(model.forward(&features, &())));
+    });
+}
+
+fn ip_signer_ensemble(c: &mut Criterion) {
+    // Fetch mock models
+    let (ip_signer_model, ips, signers) =
+        mock_ip_signer_model(100_000_000, 3_000, 10_000);
+    let (ip_signer_stake_model, _, _) =
+        mock_ip_signer_stake_model(100_000_000, 3_000, 10_000);
+    let model = EnsembleModel::new(
+        (ip_signer_model, ip_signer_stake_model),
+        Combiner::GeometricMean([1.0, 1.0]),
+    );
+    let features = TransactionFeatures::new_for_tests(
+        choose(&ips),
+        choose(&signers),
+    );
+
+    let mut ensemble = c.benchmark_group("IpSignerEnsemble");
+    ensemble.throughput(criterion::Throughput::Elements(1));
+
+    ensemble.bench_function("IpSignerEnsemble", |b| {
+        b.iter(|| black_box(model.forward(&features, &((), ()))));
     });
 }
 
-criterion_group!(evaluation, ip_signer, ip_signer_stake);
+criterion_group!(
+    evaluation,
+    ip_signer,
+    ip_signer_stake,
+    ip_signer_ensemble
+);
 
 criterion_main!(evaluation);
 
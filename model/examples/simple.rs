@@ -2,6 +2,7 @@ use fd_bs58::encode_32;
 use qos_model::{
     interface::QoSModel, models::ip_signer::IpSignerModel,
 };
+use solana_qos_internal_common::transaction_features::TransactionFeatures;
 
 fn main() {
     let ip_scores: Vec<(u32, f64)> = vec![
@@ -31,7 +32,8 @@ fn main() {
     ];
 
     for (ip, signer) in queries {
-        let score = model.forward(ip, &signer, &());
+        let features = TransactionFeatures::new_for_tests(ip, signer);
+        let score = model.forward(&features, &());
         println!(
             "score {score} for ip {ip} signer {}",
             encode_32(signer)
@@ -0,0 +1,233 @@
+//! Online model of transaction execution cost, used to estimate value
+//! per nanosecond before a transaction reaches the scheduler.
+
+/// Number of program weights. Program keys are hashed into these, so
+/// memory is bounded regardless of how many programs are seen.
+const PROGRAM_BUCKETS: usize = 1 << 12;
+
+/// Estimates are not used until the model has trained on this many
+/// executed transactions
+// TODO: hard coded parameter
+const WARMUP_SAMPLES: u64 = 10_000;
+
+/// Normalized LMS step size
+// TODO: hard coded parameter
+const LEARNING_RATE: f64 = 0.05;
+
+/// Execution nanos per requested compute unit assumed while warming
+/// up. Agave's cost model budgets 30 compute units per microsecond.
+// TODO: hard coded parameter
+const WARMUP_NANOS_PER_CU: f64 = 1_000.0 / 30.0;
+
+/// Floor for estimates, so a poorly trained model cannot produce
+/// unbounded value rates
+// TODO: hard coded parameter
+const MIN_ESTIMATE_NANOS: f64 = 1_000.0;
+
+/// Linear model of execution nanos over invoked programs and
+/// instruction count:
+///
+///   nanos = bias + ix_weight * num_instructions + sum(program weights)
+///
+/// trained online with normalized least mean squares on execution
+/// feedback from the scheduler.
+pub struct ExecutionCostModel {
+    program_weights: Box<[f64; PROGRAM_BUCKETS]>,
+    ix_weight: f64,
+    bias: f64,
+    samples: u64,
+}
+
+impl ExecutionCostModel {
+    pub fn new() -> ExecutionCostModel {
+        ExecutionCostModel {
+            program_weights: vec![0.0; PROGRAM_BUCKETS]
+                .into_boxed_slice()
+                .try_into()
+                .unwrap_or_else(|_| unreachable!("length is fixed")),
+            ix_weight: 0.0,
+            bias: 0.0,
+            samples: 0,
+        }
+    }
+
+    /// Expected execution nanos. While warming up, this is derived
+    /// from the requested compute units instead, so that estimates are
+    /// in nanos either way and value rates before and after warmup are
+    /// comparable.
+    #[inline(always)]
+    pub fn estimate_nanos(
+        &self,
+        program_keys: &[u64],
+        num_instructions: u16,
+        requested_cus: u32,
+    ) -> f64 {
+        let nanos = if self.is_warm() {
+            self.predict(program_keys, num_instructions)
+        } else {
+            requested_cus as f64 * WARMUP_NANOS_PER_CU
+        };
+
+        nanos.max(MIN_ESTIMATE_NANOS)
+    }
+
+    /// Trains the model on the observed execution time of a
+    /// transaction. Transactions that were not executed should not be
+    /// passed here.
+    pub fn train(
+        &mut self,
+        program_keys: &[u64],
+        num_instructions: u16,
+        execution_nanos: u64,
+    ) {
+        let error = execution_nanos as f64
+            - self.predict(program_keys, num_instructions);
+        let num_instructions = num_instructions as f64;
+
+        // Normalize by the squared norm of the input vector
+        // (bias + one per program + instruction count)
+        let norm = 1.0
+            + program_keys.len() as f64
+            + num_instructions * num_instructions;
+        let step = LEARNING_RATE * error / norm;
+
+        self.bias += step;
+        self.ix_weight += step * num_instructions;
+        for key in program_keys {
+            self.program_weights[bucket(*key)] += step;
+        }
+
+        self.samples += 1;
+    }
+
+    #[inline(always)]
+    pub fn is_warm(&self) -> bool {
+        self.samples >= WARMUP_SAMPLES
+    }
+
+    #[inline(always)]
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    #[inline(always)]
+    fn predict(
+        &self,
+        program_keys: &[u64],
+        num_instructions: u16,
+    ) -> f64 {
+        self.bias
+            + self.ix_weight * num_instructions as f64
+            + program_keys
+                .iter()
+                .map(|key| self.program_weights[bucket(*key)])
+                .sum::<f64>()
+    }
+}
+
+impl Default for ExecutionCostModel {
+    fn default() -> Self {
+        ExecutionCostModel::new()
+    }
+}
+
+#[inline(always)]
+fn bucket(key: u64) -> usize {
+    // Map to [0, PROGRAM_BUCKETS) without a modulo
+    ((key as u128 * PROGRAM_BUCKETS as u128) >> 64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM_A: u64 = 0x1111_1111_1111_1111;
+    const PROGRAM_B: u64 = 0xdddd_dddd_dddd_dddd;
+
+    /// Synthetic execution time: 10us base, 2us per instruction, 20us
+    /// for program A and 100us for program B
+    fn nanos(program_keys: &[u64], num_instructions: u16) -> u64 {
+        10_000
+            + 2_000 * num_instructions as u64
+            + program_keys
+                .iter()
+                .map(
+                    |&key| {
+                        if key == PROGRAM_A {
+                            20_000
+                        } else {
+                            100_000
+                        }
+                    },
+                )
+                .sum::<u64>()
+    }
+
+    fn trained(samples: u64) -> ExecutionCostModel {
+        let inputs: [(&[u64], u16); 4] = [
+            (&[PROGRAM_A], 1),
+            (&[PROGRAM_B], 2),
+            (&[PROGRAM_A, PROGRAM_B], 3),
+            (&[PROGRAM_A], 4),
+        ];
+
+        let mut model = ExecutionCostModel::new();
+        for (program_keys, num_instructions) in inputs
+            .iter()
+            .cycle()
+            .take(samples as usize)
+        {
+            model.train(
+                program_keys,
+                *num_instructions,
+                nanos(program_keys, *num_instructions),
+            );
+        }
+        model
+    }
+
+    #[test]
+    fn test_bucket_range() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(u64::MAX), PROGRAM_BUCKETS - 1);
+        assert_ne!(bucket(PROGRAM_A), bucket(PROGRAM_B));
+    }
+
+    #[test]
+    fn test_warmup_estimates_from_requested_cus() {
+        let model = trained(WARMUP_SAMPLES - 1);
+        assert!(!model.is_warm());
+
+        // 30 cus per microsecond
+        let estimate = model.estimate_nanos(&[PROGRAM_A], 1, 300_000);
+        assert!((estimate - 10_000_000.0).abs() < 1e-6);
+
+        // Floored
+        assert_eq!(model.estimate_nanos(&[PROGRAM_A], 1, 0), 1_000.0);
+    }
+
+    #[test]
+    fn test_converges_once_warm() {
+        let model = trained(WARMUP_SAMPLES);
+        assert!(model.is_warm());
+        assert_eq!(model.samples(), WARMUP_SAMPLES);
+
+        // Requested cus are ignored once warm
+        for (program_keys, num_instructions) in [
+            (&[PROGRAM_A][..], 1),
+            (&[PROGRAM_B][..], 2),
+            (&[PROGRAM_A, PROGRAM_B][..], 3),
+        ] {
+            let expected = nanos(program_keys, num_instructions) as f64;
+            let estimate = model.estimate_nanos(
+                program_keys,
+                num_instructions,
+                u32::MAX,
+            );
+            assert!(
+                (estimate - expected).abs() / expected < 0.05,
+                "{estimate} != {expected}"
+            );
+        }
+    }
+}
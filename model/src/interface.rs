@@ -1,24 +1,34 @@
-use solana_qos_internal_common::transaction_meta::{
-    QoSTransactionMeta, F64,
+use solana_qos_internal_common::{
+    transaction_features::TransactionFeatures,
+    transaction_meta::{QoSTransactionMeta, F64},
 };
 
 pub trait QoSModel {
     type AdditionalArgs;
     type AdditionalTransactionMeta;
     type AdditionalUpdateMeta;
+    /// Scores a transaction given the features extracted from its
+    /// packet. Models are free to read any subset of the features.
     fn forward(
         &self,
-        ip: u32,
-        signer: &[u8; 32],
+        features: &TransactionFeatures,
         args: &Self::AdditionalArgs,
     ) -> F64;
+
+    /// Called once for every packet prior to [QoSModel::forward], e.g.
+    /// to track traffic volume. Does nothing by default.
+    #[inline(always)]
+    fn observe(&mut self, _features: &TransactionFeatures) {}
+
+    /// The iterator is cheap to clone, so that ensembles can pass the
+    /// same batch to each member without collecting it
     fn update_model<'a>(
         &'a mut self,
         transactions: impl Iterator<
-            Item = &'a QoSTransactionMeta<
-                Self::AdditionalTransactionMeta,
-            >,
-        >,
+                Item = &'a QoSTransactionMeta<
+                    Self::AdditionalTransactionMeta,
+                >,
+            > + Clone,
         update_meta: Self::AdditionalUpdateMeta,
     );
 
@@ -26,4 +36,11 @@ pub trait QoSModel {
     /// Could be invalid signer feedback from sigverify stage, or some
     /// other form of feedback
     fn ip_feedback(&mut self, feedback: Self::IpFeedback);
+
+    /// Changes whenever previously computed scores may be stale, e.g.
+    /// after [QoSModel::update_model]. Constant by default.
+    #[inline(always)]
+    fn version(&self) -> u64 {
+        0
+    }
 }
@@ -1,5 +1,7 @@
+pub mod cost;
 pub mod interface;
 pub mod models;
+pub mod sketch;
 
 use bytemuck::{Pod, Zeroable};
 use ordered_float::OrderedFloat;
@@ -8,6 +10,10 @@ use solana_qos_internal_common::transaction_meta::F64;
 pub const ONE: F64 = OrderedFloat(1.0);
 pub const ZERO: F64 = OrderedFloat(0.0);
 
+/// Least time between version bumps caused by ip feedback
+// TODO: hard coded parameter
+pub(crate) const FEEDBACK_VERSION_INTERVAL_MS: u64 = 100;
+
 macro_rules! declare_inverse_score_entry {
     ($name:tt, $field:ident, $type:ty, $pad:literal) => {
         #[derive(
@@ -0,0 +1,348 @@
+use crate::interface::QoSModel;
+
+use solana_qos_internal_common::{
+    transaction_features::TransactionFeatures,
+    transaction_meta::{QoSTransactionMeta, F64},
+};
+
+/// How member scores are blended into a single score
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Combiner<const N: usize> {
+    /// sum(w_i * s_i) / sum(w_i)
+    WeightedMean([f64; N]),
+
+    /// prod(s_i ^ w_i) ^ (1 / sum(w_i)). Any member with nonzero
+    /// weight scoring zero yields zero.
+    GeometricMean([f64; N]),
+}
+
+impl<const N: usize> Combiner<N> {
+    fn weights(&self) -> &[f64; N] {
+        match self {
+            Combiner::WeightedMean(weights) => weights,
+            Combiner::GeometricMean(weights) => weights,
+        }
+    }
+
+    #[inline(always)]
+    pub fn combine(&self, scores: &[F64; N]) -> F64 {
+        let weights = self.weights();
+        let total_weight = weights.iter().sum::<f64>();
+
+        match self {
+            Combiner::WeightedMean(_) => {
+                let weighted_sum = scores
+                    .iter()
+                    .zip(weights)
+                    .map(|(score, weight)| **score * weight)
+                    .sum::<f64>();
+
+                F64::from(weighted_sum / total_weight)
+            }
+            Combiner::GeometricMean(_) => {
+                // Computed in log space. Members with zero weight are
+                // skipped so their zero scores can't produce NaN.
+                let weighted_log_sum = scores
+                    .iter()
+                    .zip(weights)
+                    .filter(|(_, &weight)| weight != 0.0)
+                    .map(|(score, weight)| score.ln() * weight)
+                    .sum::<f64>();
+
+                F64::from((weighted_log_sum / total_weight).exp())
+            }
+        }
+    }
+}
+
+/// Runs several models together, blending their scores with a
+/// [Combiner]. Members are held in a tuple and receive every update
+/// and feedback call.
+///
+/// Members must agree on the scheduler feedback and ip feedback types.
+/// Their additional args and update metas are passed as tuples.
+pub struct EnsembleModel<M, const N: usize> {
+    members: M,
+    combiner: Combiner<N>,
+}
+
+impl<M, const N: usize> EnsembleModel<M, N> {
+    /// Panics if any weight is negative or all weights are zero
+    pub fn new(members: M, combiner: Combiner<N>) -> Self {
+        let weights = combiner.weights();
+        assert!(
+            weights
+                .iter()
+                .all(|&weight| weight >= 0.0),
+            "ensemble weights must be nonnegative"
+        );
+        assert!(
+            weights.iter().sum::<f64>() > 0.0,
+            "at least one ensemble weight must be positive"
+        );
+
+        EnsembleModel { members, combiner }
+    }
+
+    pub fn members(&self) -> &M {
+        &self.members
+    }
+
+    pub fn members_mut(&mut self) -> &mut M {
+        &mut self.members
+    }
+
+    pub fn combiner(&self) -> &Combiner<N> {
+        &self.combiner
+    }
+}
+
+macro_rules! impl_ensemble {
+    ($n:literal; $first:ident $(, $rest:ident)*; $($idx:tt),+) => {
+        impl<$first $(, $rest)*> EnsembleModel<($first, $($rest,)*), $n>
+        where
+            $first: QoSModel,
+            $first::IpFeedback: Clone,
+            $(
+                $rest: QoSModel<
+                    AdditionalTransactionMeta =
+                        $first::AdditionalTransactionMeta,
+                    IpFeedback = $first::IpFeedback,
+                >,
+            )*
+        {
+            /// Unblended score of each member, for debugging
+            #[inline(always)]
+            pub fn contributions(
+                &self,
+                features: &TransactionFeatures,
+                args: &<Self as QoSModel>::AdditionalArgs,
+            ) -> [F64; $n] {
+                [$(self.members.$idx.forward(features, &args.$idx)),+]
+            }
+        }
+
+        impl<$first $(, $rest)*> QoSModel
+            for EnsembleModel<($first, $($rest,)*), $n>
+        where
+            $first: QoSModel,
+            $first::IpFeedback: Clone,
+            $(
+                $rest: QoSModel<
+                    AdditionalTransactionMeta =
+                        $first::AdditionalTransactionMeta,
+                    IpFeedback = $first::IpFeedback,
+                >,
+            )*
+        {
+            type AdditionalArgs =
+                ($first::AdditionalArgs, $($rest::AdditionalArgs,)*);
+            type AdditionalTransactionMeta =
+                $first::AdditionalTransactionMeta;
+            type AdditionalUpdateMeta = (
+                $first::AdditionalUpdateMeta,
+                $($rest::AdditionalUpdateMeta,)*
+            );
+
+            #[inline(always)]
+            fn forward(
+                &self,
+                features: &TransactionFeatures,
+                args: &Self::AdditionalArgs,
+            ) -> F64 {
+                self.combiner
+                    .combine(&self.contributions(features, args))
+            }
+
+            #[inline(always)]
+            fn observe(&mut self, features: &TransactionFeatures) {
+                $(self.members.$idx.observe(features);)+
+            }
+
+            fn update_model<'a>(
+                &'a mut self,
+                transactions: impl Iterator<
+                        Item = &'a QoSTransactionMeta<
+                            Self::AdditionalTransactionMeta,
+                        >,
+                    > + Clone,
+                update_meta: Self::AdditionalUpdateMeta,
+            ) {
+                // Every member sees the same batch
+                $(
+                    self.members.$idx.update_model(
+                        transactions.clone(),
+                        update_meta.$idx,
+                    );
+                )+
+            }
+
+            type IpFeedback = $first::IpFeedback;
+            fn ip_feedback(&mut self, feedback: Self::IpFeedback) {
+                $(self.members.$idx.ip_feedback(feedback.clone());)+
+            }
+
+            /// Changes whenever any member's version does
+            #[inline(always)]
+            fn version(&self) -> u64 {
+                0_u64 $(.wrapping_add(self.members.$idx.version()))+
+            }
+        }
+    };
+}
+
+impl_ensemble!(2; M0, M1; 0, 1);
+impl_ensemble!(3; M0, M1, M2; 0, 1, 2);
+impl_ensemble!(4; M0, M1, M2, M3; 0, 1, 2, 3);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records every call it receives
+    #[derive(Default)]
+    struct Member {
+        score: f64,
+        observed: Vec<u32>,
+        updated: Vec<u32>,
+        feedback: Vec<u32>,
+        version: u64,
+    }
+
+    impl Member {
+        fn new(score: f64) -> Member {
+            Member {
+                score,
+                ..Member::default()
+            }
+        }
+    }
+
+    impl QoSModel for Member {
+        type AdditionalArgs = f64;
+        type AdditionalTransactionMeta = ();
+        /// Added to the version
+        type AdditionalUpdateMeta = u64;
+
+        fn forward(
+            &self,
+            _features: &TransactionFeatures,
+            scale: &Self::AdditionalArgs,
+        ) -> F64 {
+            F64::from(self.score * scale)
+        }
+
+        fn observe(&mut self, features: &TransactionFeatures) {
+            self.observed.push(features.ip);
+        }
+
+        fn update_model<'a>(
+            &'a mut self,
+            transactions: impl Iterator<Item = &'a QoSTransactionMeta<()>>
+                + Clone,
+            update_meta: Self::AdditionalUpdateMeta,
+        ) {
+            self.updated
+                .extend(transactions.map(|meta| meta.ip));
+            self.version += update_meta;
+        }
+
+        type IpFeedback = u32;
+        fn ip_feedback(&mut self, ip: Self::IpFeedback) {
+            self.feedback.push(ip);
+        }
+
+        fn version(&self) -> u64 {
+            self.version
+        }
+    }
+
+    fn assert_close(actual: F64, expected: f64) {
+        assert!(
+            (*actual - expected).abs() < 1e-9,
+            "{actual} != {expected}"
+        );
+    }
+
+    #[test]
+    fn test_weighted_mean() {
+        let combiner = Combiner::WeightedMean([1.0, 3.0]);
+        assert_close(combiner.combine(&[2.0.into(), 4.0.into()]), 3.5);
+
+        // A zero weight member doesn't contribute
+        let combiner = Combiner::WeightedMean([0.0, 2.0]);
+        assert_close(combiner.combine(&[9.0.into(), 4.0.into()]), 4.0);
+    }
+
+    #[test]
+    fn test_geometric_mean() {
+        let combiner = Combiner::GeometricMean([1.0, 1.0]);
+        assert_close(combiner.combine(&[4.0.into(), 1.0.into()]), 2.0);
+
+        // 8^(2/3) * 1^(1/3)
+        let combiner = Combiner::GeometricMean([2.0, 1.0]);
+        assert_close(combiner.combine(&[8.0.into(), 1.0.into()]), 4.0);
+
+        // Zero score with nonzero weight vetoes
+        let combiner = Combiner::GeometricMean([1.0, 1.0]);
+        assert_close(combiner.combine(&[0.0.into(), 4.0.into()]), 0.0);
+
+        // Zero score with zero weight is ignored
+        let combiner = Combiner::GeometricMean([0.0, 1.0]);
+        assert_close(combiner.combine(&[0.0.into(), 4.0.into()]), 4.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "nonnegative")]
+    fn test_negative_weight() {
+        EnsembleModel::new((), Combiner::WeightedMean([1.0, -1.0]));
+    }
+
+    #[test]
+    #[should_panic(expected = "positive")]
+    fn test_zero_weights() {
+        EnsembleModel::new((), Combiner::GeometricMean([0.0, 0.0]));
+    }
+
+    #[test]
+    fn test_forward_passes_member_args() {
+        let model = EnsembleModel::new(
+            (Member::new(1.0), Member::new(2.0)),
+            Combiner::WeightedMean([1.0, 1.0]),
+        );
+        let features = TransactionFeatures::new_for_tests(1, [1; 32]);
+
+        let contributions = model.contributions(&features, &(3.0, 5.0));
+        assert_eq!(contributions, [F64::from(3.0), F64::from(10.0)]);
+        assert_close(model.forward(&features, &(3.0, 5.0)), 6.5);
+    }
+
+    #[test]
+    fn test_feedback_reaches_every_member() {
+        let mut model = EnsembleModel::new(
+            (Member::new(1.0), Member::new(1.0), Member::new(1.0)),
+            Combiner::WeightedMean([1.0, 1.0, 1.0]),
+        );
+
+        model.observe(&TransactionFeatures::new_for_tests(7, [1; 32]));
+        model.ip_feedback(8);
+        let metas: Vec<_> = [9, 10]
+            .into_iter()
+            .map(|ip| {
+                QoSTransactionMeta::new_for_tests(ip, [1; 32], 1, 1, ())
+            })
+            .collect();
+        model.update_model(metas.iter(), (1, 10, 100));
+
+        let (m0, m1, m2) = model.members();
+        for member in [m0, m1, m2] {
+            assert_eq!(member.observed, [7]);
+            assert_eq!(member.feedback, [8]);
+            assert_eq!(member.updated, [9, 10]);
+        }
+
+        // Update metas are routed by position
+        assert_eq!([m0.version, m1.version, m2.version], [1, 10, 100]);
+        assert_eq!(model.version(), 111);
+    }
+}
@@ -1,36 +1,48 @@
 use crate::{
-    interface::QoSModel, InverseScoreEntryIp, InverseScoreEntrySigner,
-    ONE,
+    interface::QoSModel,
+    sketch::{ip_key, signer_key, HeavyHitterSketch},
+    InverseScoreEntryIp, InverseScoreEntrySigner,
+    FEEDBACK_VERSION_INTERVAL_MS, ONE,
 };
 
 use ordered_float::OrderedFloat;
 use sokoban::{NodeAllocatorMap, RedBlackTree};
-use solana_qos_internal_common::transaction_meta::{
-    QoSTransactionMeta, F64,
+use solana_qos_common::remaining_meta::Outcome;
+use solana_qos_internal_common::{
+    transaction_features::TransactionFeatures,
+    transaction_meta::{QoSTransactionMeta, F64},
 };
 
 use std::{
     borrow::Borrow, collections::BTreeMap, io::Write, net::Ipv4Addr,
 };
+use timer::{Clock, RealClock};
 
-impl<const MAX_SIGNERS: usize, const MAX_IPS: usize> QoSModel
-    for IpSignerModel<MAX_SIGNERS, MAX_IPS>
+impl<const MAX_SIGNERS: usize, const MAX_IPS: usize, C: Clock> QoSModel
+    for IpSignerModel<MAX_SIGNERS, MAX_IPS, C>
 {
     type AdditionalArgs = ();
     type AdditionalTransactionMeta = ();
     type AdditionalUpdateMeta = ();
+    /// Only reads the ip and signer
     fn forward(
         &self,
-        ip: u32,
-        signer: &[u8; 32],
+        features: &TransactionFeatures,
         _args: &Self::AdditionalArgs,
     ) -> F64 {
-        self._forward(ip, signer)
+        self._forward(features.ip, &features.signer)
+    }
+
+    #[inline(always)]
+    fn observe(&mut self, features: &TransactionFeatures) {
+        self.sketch
+            .record_packet(features.ip, &features.signer);
     }
 
     fn update_model<'a>(
         &'a mut self,
-        transactions: impl Iterator<Item = &'a QoSTransactionMeta<()>>,
+        transactions: impl Iterator<Item = &'a QoSTransactionMeta<()>>
+            + Clone,
         _update_meta: Self::AdditionalUpdateMeta,
     ) {
         self.update_model(transactions, 5, 5)
@@ -39,6 +51,13 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize> QoSModel
     /// The ip that sent in a transaction with invalid signature
     type IpFeedback = u32;
     fn ip_feedback(&mut self, ip: Self::IpFeedback) {
+        let key = ip_key(ip);
+        let unknown_penalty = self
+            .sketch
+            .ips
+            .unknown_source_penalty(key);
+        self.sketch.ips.record_failure(key);
+
         if let Some(score) = self.ip_score.get_mut(&ip) {
             // First update score in inverse map
             self.ip_score_inverse
@@ -50,18 +69,62 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize> QoSModel
 
             // Then update score in map
             **score *= 0.01;
+            self.feedback_pending = true;
+        } else if self
+            .sketch
+            .ips
+            .unknown_source_penalty(key)
+            != unknown_penalty
+        {
+            self.feedback_pending = true;
         }
+
+        self.flush_feedback();
+    }
+
+    #[inline(always)]
+    fn version(&self) -> u64 {
+        self.version
     }
 }
 
-pub struct IpSignerModel<const MAX_SIGNERS: usize, const MAX_IPS: usize>
-{
+/// Time dependent logic reads `C`, so tests can drive it with a
+/// [timer::ManualClock]
+pub struct IpSignerModel<
+    const MAX_SIGNERS: usize,
+    const MAX_IPS: usize,
+    C: Clock = RealClock,
+> {
     signer_score: RedBlackTree<[u8; 32], F64, MAX_SIGNERS>,
     ip_score: RedBlackTree<u32, F64, MAX_IPS>,
 
     signer_score_inverse:
         RedBlackTree<InverseScoreEntrySigner, (), MAX_SIGNERS>,
     ip_score_inverse: RedBlackTree<InverseScoreEntryIp, (), MAX_IPS>,
+
+    /// Average fraction of requested compute units actually consumed by
+    /// executed transactions, for signers with enough samples and a
+    /// table score.
+    signer_cu_utilization: RedBlackTree<[u8; 32], F64, MAX_SIGNERS>,
+
+    /// Traffic volume and failures of all ips and signers, including
+    /// those without a table entry.
+    sketch: HeavyHitterSketch,
+
+    clock: C,
+
+    /// Records the time the sketch was last decayed
+    last_decay_ms: u64,
+
+    /// Bumped whenever a table score changes, though at most once per
+    /// interval for changes from [QoSModel::ip_feedback]
+    version: u64,
+
+    /// Whether ip feedback changed a score since the last bump
+    feedback_pending: bool,
+
+    /// Earliest time ip feedback may bump the version again
+    next_feedback_version_ms: u64,
 }
 
 impl<const MAX_SIGNERS: usize, const MAX_IPS: usize>
@@ -71,6 +134,22 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize>
         ip_scores: impl IntoIterator<Item = (u32, f64)>,
         signer_scores: impl IntoIterator<Item = ([u8; 32], f64)>,
     ) -> IpSignerModel<MAX_SIGNERS, MAX_IPS> {
+        IpSignerModel::with_clock(
+            ip_scores,
+            signer_scores,
+            RealClock::new(),
+        )
+    }
+}
+
+impl<const MAX_SIGNERS: usize, const MAX_IPS: usize, C: Clock>
+    IpSignerModel<MAX_SIGNERS, MAX_IPS, C>
+{
+    pub fn with_clock(
+        ip_scores: impl IntoIterator<Item = (u32, f64)>,
+        signer_scores: impl IntoIterator<Item = ([u8; 32], f64)>,
+        clock: C,
+    ) -> IpSignerModel<MAX_SIGNERS, MAX_IPS, C> {
         let mut signer_score = RedBlackTree::new();
         let mut signer_score_inverse = RedBlackTree::new();
         for (signer, score) in signer_scores {
@@ -96,9 +175,50 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize>
             ip_score,
             signer_score_inverse,
             ip_score_inverse,
+            signer_cu_utilization: RedBlackTree::new(),
+            sketch: HeavyHitterSketch::new(),
+            last_decay_ms: clock.now_ms(),
+            clock,
+            version: 0,
+            feedback_pending: false,
+            next_feedback_version_ms: 0,
         }
     }
 
+    /// Number of ips with a table score
+    /// Bumps the version for ip feedback received since the last bump,
+    /// unless the last one was less than an interval ago. Queued
+    /// transactions are rescored from scratch whenever the version
+    /// changes, so a flood of failures must not bump it on every one.
+    /// Called on every feedback and should be called once per loop, so
+    /// that feedback in between is picked up once the interval passes.
+    pub fn flush_feedback(&mut self) {
+        if !self.feedback_pending {
+            return;
+        }
+        let now_ms = self.clock.now_ms();
+        if now_ms >= self.next_feedback_version_ms {
+            self.version += 1;
+            self.feedback_pending = false;
+            self.next_feedback_version_ms =
+                now_ms + FEEDBACK_VERSION_INTERVAL_MS;
+        }
+    }
+
+    pub fn num_ips(&self) -> usize {
+        self.ip_score.len()
+    }
+
+    /// Number of signers with a table score
+    pub fn num_signers(&self) -> usize {
+        self.signer_score.len()
+    }
+
+    /// Number of signers with a compute unit utilization average
+    pub fn num_signer_utilizations(&self) -> usize {
+        self.signer_cu_utilization.len()
+    }
+
     /// Returns combined score for this ip + signer.
     /// Panics if there are no scores!
     pub fn _forward(&self, ip: u32, signer: &[u8; 32]) -> F64 {
@@ -107,19 +227,53 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize>
         // We use median score for null queries because that is the most
         // neutral score. Recall that pruning removes elements
         // close to the median, leaving the most discriminating
-        // scores (i.e. least and most valuable sources).
+        // scores (i.e. least and most valuable sources). Unknown
+        // sources that the sketch deems suspiciously heavy are
+        // penalized.
         let ip_score = self
             .ip_score
             .get(&ip)
             .copied()
-            .unwrap_or_else(|| self.approximate_median_ip_score());
+            .unwrap_or_else(|| {
+                self.approximate_median_ip_score()
+                    * self
+                        .sketch
+                        .ips
+                        .unknown_source_penalty(ip_key(ip))
+            });
         let signer_score = self
             .signer_score
             .get(signer)
             .copied()
-            .unwrap_or_else(|| self.approximate_median_signer_score());
+            .unwrap_or_else(|| {
+                self.approximate_median_signer_score()
+                    * self
+                        .sketch
+                        .signers
+                        .unknown_source_penalty(signer_key(signer))
+            });
+
+        ip_score * signer_score * self.over_request_penalty(signer)
+    }
+
+    /// Lowers the score of signers that chronically request far more
+    /// compute units than they consume, as over-requesting hurts block
+    /// packing. Signers without enough executed transactions are not
+    /// penalized.
+    fn over_request_penalty(&self, signer: &[u8; 32]) -> F64 {
+        // TODO: hard coded parameters
+        const FULL_CREDIT_UTILIZATION: f64 = 0.5;
+        const MIN_PENALTY: f64 = 0.1;
 
-        ip_score * signer_score
+        self.signer_cu_utilization
+            .get(signer)
+            .map(|utilization| {
+                F64::from(
+                    (**utilization / FULL_CREDIT_UTILIZATION)
+                        .clamp(MIN_PENALTY, 1.0),
+                )
+            })
+            .unwrap_or(ONE)
     }
 
     fn approximate_median_ip_score(&self) -> F64 {
@@ -172,6 +326,8 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize>
                 .unwrap();
             self.signer_score
                 .remove(&root_node.key.signer);
+            self.signer_cu_utilization
+                .remove(&root_node.key.signer);
         }
     }
 
@@ -209,6 +365,10 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize>
         prune_signers: usize,
         prune_ips: usize,
     ) {
+        // Table scores are replaced below
+        self.version += 1;
+        self.feedback_pending = false;
+
         struct ScoreUpdateCandidate {
             score_sum: F64,
             count: u32,
@@ -235,14 +395,45 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize>
             BTreeMap::<[u8; 32], ScoreUpdateCandidate>::new();
         let mut ip_score_candidates =
             BTreeMap::<u32, ScoreUpdateCandidate>::new();
+        let mut signer_utilization_candidates =
+            BTreeMap::<[u8; 32], ScoreUpdateCandidate>::new();
         for transaction in transactions {
             let &QoSTransactionMeta {
                 ip,
                 signer,
                 value: score,
+                requested_cus,
+                outcome,
+                consumed_cus,
                 additional_metadata: _,
             } = transaction.borrow();
 
+            // Unscheduled and expired transactions are not the
+            // signer's fault
+            if outcome == Outcome::Failed {
+                self.sketch
+                    .signers
+                    .record_failure(signer_key(&signer));
+            }
+
+            // Only executed transactions with known requested cus tell
+            // us how much of their request was used
+            if outcome.executed()
+                && consumed_cus > 0
+                && requested_cus > 0
+            {
+                let utilization = F64::from(
+                    (consumed_cus as f64 / requested_cus as f64)
+                        .min(1.0),
+                );
+                signer_utilization_candidates
+                    .entry(signer)
+                    .and_modify(|s| s.update(utilization))
+                    .or_insert_with(|| {
+                        ScoreUpdateCandidate::new(utilization)
+                    });
+            }
+
             signer_score_candidates
                 .entry(signer)
                 .and_modify(|s| s.update(score))
@@ -275,8 +466,16 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize>
         }
 
         for (ip, score_candidate) in ip_score_candidates {
+            // Once the table is full, only admit ips with enough traffic
+            // to deserve a slot
+            let has_slot = self.ip_score.len() < prune_ips
+                || self
+                    .sketch
+                    .ips
+                    .deserves_slot(ip_key(ip), prune_ips);
+
             // TODO: hard coded parameter
-            if score_candidate.count >= 5 {
+            if score_candidate.count >= 5 && has_slot {
                 let score = score_candidate.finalize();
                 self.ip_score.insert(ip, score);
                 self.ip_score_inverse
@@ -308,8 +507,16 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize>
         }
 
         for (signer, score_candidate) in signer_score_candidates {
+            // Once the table is full, only admit signers with enough
+            // traffic to deserve a slot
+            let has_slot = self.signer_score.len() < prune_signers
+                || self
+                    .sketch
+                    .signers
+                    .deserves_slot(signer_key(&signer), prune_signers);
+
             // TODO: hard coded parameter
-            if score_candidate.count >= 5 {
+            if score_candidate.count >= 5 && has_slot {
                 let score = score_candidate.finalize();
                 self.signer_score.insert(signer, score);
                 self.signer_score_inverse.insert(
@@ -319,7 +526,39 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize>
             }
         }
 
+        // Only signers with a table score keep an average, so that
+        // pruning the scores bounds the averages too
+        for (signer, utilization_candidate) in
+            signer_utilization_candidates
+        {
+            // TODO: hard coded parameter
+            if utilization_candidate.count >= 5
+                && self.signer_score.get(&signer).is_some()
+            {
+                let utilization = utilization_candidate.finalize();
+                if let Some(old_utilization) = self
+                    .signer_cu_utilization
+                    .get_mut(&signer)
+                {
+                    *old_utilization =
+                        ema(*old_utilization, utilization);
+                } else {
+                    self.signer_cu_utilization
+                        .insert(signer, utilization);
+                }
+            }
+        }
+
         self.prune(prune_ips, prune_signers);
+
+        // Periodically decay the sketch so it reflects recent traffic
+        // TODO: hard coded parameter
+        const SKETCH_DECAY_INTERVAL_MS: u64 = 10_000;
+        let now_ms = self.clock.now_ms();
+        if now_ms - self.last_decay_ms >= SKETCH_DECAY_INTERVAL_MS {
+            self.sketch.decay();
+            self.last_decay_ms = now_ms;
+        }
     }
 
     pub fn save_ip_scores(&self, arg: &str) {
@@ -348,3 +587,213 @@ fn ema(old_score: F64, new_score: F64) -> F64 {
 
     old_score * (ONE - ALPHA) + new_score * ALPHA
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use timer::ManualClock;
+
+    type Model = IpSignerModel<16, 16>;
+
+    const IP: u32 = 7;
+
+    fn meta(
+        signer: [u8; 32],
+        outcome: Outcome,
+        requested_cus: u32,
+        consumed_cus: u64,
+    ) -> QoSTransactionMeta<()> {
+        QoSTransactionMeta {
+            ip: IP,
+            signer,
+            value: ONE,
+            requested_cus,
+            outcome,
+            consumed_cus,
+            additional_metadata: (),
+        }
+    }
+
+    fn assert_close(score: F64, expected: f64) {
+        assert!(
+            (*score - expected).abs() < 1e-9,
+            "{score} != {expected}"
+        );
+    }
+
+    #[test]
+    fn test_over_request_penalty() {
+        let over = [1; 32];
+        let fair = [2; 32];
+        let unsampled = [3; 32];
+        let mut model = Model::new(
+            [(IP, 1.0)],
+            [(over, 1.0), (fair, 1.0), (unsampled, 1.0)],
+        );
+
+        // Uses 10% and 90% of the requested compute units. Too few
+        // samples are ignored.
+        let metas: Vec<_> = (0..5)
+            .map(|_| meta(over, Outcome::Succeeded, 100_000, 10_000))
+            .chain((0..5).map(|_| {
+                meta(fair, Outcome::Succeeded, 100_000, 90_000)
+            }))
+            .chain((0..4).map(|_| {
+                meta(unsampled, Outcome::Succeeded, 100_000, 10_000)
+            }))
+            .collect();
+        model.update_model(&metas, 16, 16);
+
+        // Full credit from 50% utilization
+        assert_close(model._forward(IP, &over), 0.2);
+        assert_close(model._forward(IP, &fair), 1.0);
+        assert_close(model._forward(IP, &unsampled), 1.0);
+    }
+
+    #[test]
+    fn test_only_failed_outcomes_count_as_failures() {
+        let signer = [1; 32];
+        for (outcome, failure_rate) in [
+            (Outcome::NotScheduled, 0.0),
+            (Outcome::Succeeded, 0.0),
+            (Outcome::Failed, 1.0),
+            (Outcome::Expired, 0.0),
+        ] {
+            let mut model = Model::new([(IP, 1.0)], [(signer, 1.0)]);
+            model.sketch.record_packet(IP, &signer);
+            model.update_model(
+                &[meta(signer, outcome, 100_000, 0)],
+                16,
+                16,
+            );
+
+            assert_eq!(
+                model
+                    .sketch
+                    .signers
+                    .failure_rate(signer_key(&signer)),
+                failure_rate,
+                "{outcome:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_over_request_penalty_ignores_unexecuted() {
+        let signer = [1; 32];
+        let mut model = Model::new([(IP, 1.0)], [(signer, 1.0)]);
+
+        // Not executed, or consumed cus unknown
+        let metas: Vec<_> =
+            (0..5)
+                .map(|_| {
+                    meta(signer, Outcome::NotScheduled, 100_000, 0)
+                })
+                .chain((0..5).map(|_| {
+                    meta(signer, Outcome::Expired, 100_000, 0)
+                }))
+                .chain((0..5).map(|_| {
+                    meta(signer, Outcome::Succeeded, 0, 10_000)
+                }))
+                .collect();
+        model.update_model(&metas, 16, 16);
+
+        assert_eq!(model.num_signer_utilizations(), 0);
+        assert_close(model._forward(IP, &signer), 1.0);
+    }
+
+    #[test]
+    fn test_utilizations_follow_signer_table() {
+        // A full signer table of heavy signers, so that new signers
+        // without traffic in the sketch get no slot
+        let incumbents = (0..4_u8).map(|i| [u8::MAX - i; 32]);
+        let mut model = Model::new(
+            [(IP, 1.0)],
+            incumbents.clone().map(|s| (s, 1.0)),
+        );
+        for signer in incumbents {
+            model.sketch.record_packet(IP, &signer);
+        }
+
+        // Many more signers than the tables hold, each with enough
+        // samples for an average
+        for round in 0..10_u8 {
+            let metas: Vec<_> = (0..8_u8)
+                .flat_map(|i| {
+                    let signer = [round * 8 + i; 32];
+                    (0..5).map(move |_| {
+                        meta(
+                            signer,
+                            Outcome::Succeeded,
+                            100_000,
+                            10_000,
+                        )
+                    })
+                })
+                .collect();
+            model.update_model(&metas, 4, 16);
+
+            assert!(model.num_signers() <= 4);
+            assert!(
+                model.num_signer_utilizations() <= model.num_signers()
+            );
+            assert!(model.signer_cu_utilization.iter().all(
+                |(signer, _)| model.signer_score.get(signer).is_some()
+            ));
+        }
+    }
+
+    #[test]
+    fn test_ip_feedback_bumps_version_once_per_interval() {
+        let clock = ManualClock::new();
+        let mut model = IpSignerModel::<16, 16, _>::with_clock(
+            [(IP, 1.0)],
+            [],
+            clock.clone(),
+        );
+
+        // A flood of failures bumps once
+        for _ in 0..100 {
+            model.ip_feedback(IP);
+        }
+        assert_eq!(model.version(), 1);
+
+        // Changes since are picked up after the interval
+        clock.advance(Duration::from_millis(100));
+        model.ip_feedback(IP);
+        assert_eq!(model.version(), 2);
+
+        // A light unknown ip has no score to change
+        clock.advance(Duration::from_millis(100));
+        model.ip_feedback(IP + 1);
+        assert_eq!(model.version(), 2);
+    }
+
+    #[test]
+    fn test_flush_feedback_picks_up_feedback_inside_interval() {
+        let clock = ManualClock::new();
+        let mut model = IpSignerModel::<16, 16, _>::with_clock(
+            [(IP, 1.0)],
+            [],
+            clock.clone(),
+        );
+        model.ip_feedback(IP);
+        assert_eq!(model.version(), 1);
+
+        // Feedback inside the interval waits without further feedback
+        clock.advance(Duration::from_millis(50));
+        model.ip_feedback(IP);
+        model.flush_feedback();
+        assert_eq!(model.version(), 1);
+
+        clock.advance(Duration::from_millis(50));
+        model.flush_feedback();
+        assert_eq!(model.version(), 2);
+
+        // Nothing pending, nothing to bump
+        clock.advance(Duration::from_millis(100));
+        model.flush_feedback();
+        assert_eq!(model.version(), 2);
+    }
+}
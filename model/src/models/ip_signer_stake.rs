@@ -1,41 +1,44 @@
 use crate::{
     interface::QoSModel, InverseScoreEntryIp, InverseScoreEntrySigner,
-    ONE,
+    FEEDBACK_VERSION_INTERVAL_MS, ONE,
 };
 
 use ordered_float::OrderedFloat;
 use sokoban::{NodeAllocatorMap, RedBlackTree};
-use solana_qos_internal_common::transaction_meta::{
-    QoSTransactionMeta, F64,
+use solana_qos_internal_common::{
+    transaction_features::TransactionFeatures,
+    transaction_meta::{QoSTransactionMeta, F64},
 };
 
 use std::{
     borrow::Borrow,
     collections::{BTreeMap, HashMap},
 };
+use timer::{Clock, RealClock};
 
 type Stake = u64;
 type TotalStake = Stake;
 type Ip4 = u32;
 
-impl<const MAX_SIGNERS: usize, const MAX_IPS: usize> QoSModel
-    for IpSignerStakeModel<MAX_SIGNERS, MAX_IPS>
+impl<const MAX_SIGNERS: usize, const MAX_IPS: usize, C: Clock> QoSModel
+    for IpSignerStakeModel<MAX_SIGNERS, MAX_IPS, C>
 {
     type AdditionalArgs = ();
     type AdditionalTransactionMeta = ();
     type AdditionalUpdateMeta = (TotalStake, HashMap<Ip4, Stake>);
+    /// Only reads the ip and signer
     fn forward(
         &self,
-        ip: u32,
-        signer: &[u8; 32],
+        features: &TransactionFeatures,
         _args: &Self::AdditionalArgs,
     ) -> F64 {
-        self._forward(ip, signer)
+        self._forward(features.ip, &features.signer)
     }
 
     fn update_model<'a>(
         &'a mut self,
-        transactions: impl Iterator<Item = &'a QoSTransactionMeta<()>>,
+        transactions: impl Iterator<Item = &'a QoSTransactionMeta<()>>
+            + Clone,
         update_meta: Self::AdditionalUpdateMeta,
     ) {
         self.update_model(transactions, 5, 5, update_meta)
@@ -55,14 +58,25 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize> QoSModel
 
             // Then update score in map
             **score *= 0.01;
+            self.feedback_pending = true;
         }
+
+        self.flush_feedback();
+    }
+
+    #[inline(always)]
+    fn version(&self) -> u64 {
+        self.version
     }
 }
 
+/// Time dependent logic reads `C`, so tests can drive it with a
+/// [timer::ManualClock]
 #[derive(Clone)]
 pub struct IpSignerStakeModel<
     const MAX_SIGNERS: usize,
     const MAX_IPS: usize,
+    C: Clock = RealClock,
 > {
     signer_score: Box<RedBlackTree<[u8; 32], F64, MAX_SIGNERS>>,
     ip_score: Box<RedBlackTree<u32, F64, MAX_IPS>>,
@@ -73,6 +87,18 @@ pub struct IpSignerStakeModel<
         Box<RedBlackTree<InverseScoreEntryIp, (), MAX_IPS>>,
     stake_lookup: HashMap<Ip4, Stake>,
     total_stake: u64,
+
+    clock: C,
+
+    /// Bumped whenever a table score changes, though at most once per
+    /// interval for changes from [QoSModel::ip_feedback]
+    version: u64,
+
+    /// Whether ip feedback changed a score since the last bump
+    feedback_pending: bool,
+
+    /// Earliest time ip feedback may bump the version again
+    next_feedback_version_ms: u64,
 }
 
 impl<const MAX_SIGNERS: usize, const MAX_IPS: usize>
@@ -84,6 +110,26 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize>
         stake_lookup: HashMap<Ip4, Stake>,
         total_stake: u64,
     ) -> IpSignerStakeModel<MAX_SIGNERS, MAX_IPS> {
+        IpSignerStakeModel::with_clock(
+            ip_scores,
+            signer_scores,
+            stake_lookup,
+            total_stake,
+            RealClock::new(),
+        )
+    }
+}
+
+impl<const MAX_SIGNERS: usize, const MAX_IPS: usize, C: Clock>
+    IpSignerStakeModel<MAX_SIGNERS, MAX_IPS, C>
+{
+    pub fn with_clock(
+        ip_scores: impl IntoIterator<Item = (u32, f64)>,
+        signer_scores: impl IntoIterator<Item = ([u8; 32], f64)>,
+        stake_lookup: HashMap<Ip4, Stake>,
+        total_stake: u64,
+        clock: C,
+    ) -> IpSignerStakeModel<MAX_SIGNERS, MAX_IPS, C> {
         let mut signer_score = Box::new(RedBlackTree::new());
         let mut signer_score_inverse = Box::new(RedBlackTree::new());
         for (signer, score) in signer_scores {
@@ -111,6 +157,29 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize>
             ip_score_inverse,
             stake_lookup,
             total_stake,
+            clock,
+            version: 0,
+            feedback_pending: false,
+            next_feedback_version_ms: 0,
+        }
+    }
+
+    /// Bumps the version for ip feedback received since the last bump,
+    /// unless the last one was less than an interval ago. Queued
+    /// transactions are rescored from scratch whenever the version
+    /// changes, so a flood of failures must not bump it on every one.
+    /// Called on every feedback and should be called once per loop, so
+    /// that feedback in between is picked up once the interval passes.
+    pub fn flush_feedback(&mut self) {
+        if !self.feedback_pending {
+            return;
+        }
+        let now_ms = self.clock.now_ms();
+        if now_ms >= self.next_feedback_version_ms {
+            self.version += 1;
+            self.feedback_pending = false;
+            self.next_feedback_version_ms =
+                now_ms + FEEDBACK_VERSION_INTERVAL_MS;
         }
     }
 
@@ -232,6 +301,10 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize>
         prune_ips: usize,
         (total_stake, stake_lookup): <Self as QoSModel>::AdditionalUpdateMeta,
     ) {
+        // Table scores are replaced below
+        self.version += 1;
+        self.feedback_pending = false;
+
         struct ScoreUpdateCandidate {
             score_sum: F64,
             count: u32,
@@ -264,6 +337,9 @@ impl<const MAX_SIGNERS: usize, const MAX_IPS: usize>
                 ip,
                 signer,
                 value: score,
+                requested_cus: _,
+                outcome: _,
+                consumed_cus: _,
                 additional_metadata: _,
             } = transaction.borrow();
 
@@ -362,3 +438,86 @@ fn ema(old_score: F64, new_score: F64) -> F64 {
 fn stake_score(stake: u64, total_stake: u64) -> F64 {
     F64::from((stake + total_stake) as f64 / total_stake as f64)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use timer::ManualClock;
+
+    use super::*;
+
+    const IP: u32 = 7;
+
+    #[test]
+    fn test_ip_feedback_bumps_version_once_per_interval() {
+        let clock = ManualClock::new();
+        let mut model = IpSignerStakeModel::<16, 16, _>::with_clock(
+            [(IP, 1.0)],
+            [],
+            HashMap::new(),
+            1,
+            clock.clone(),
+        );
+
+        // A flood of failures bumps once
+        for _ in 0..100 {
+            model.ip_feedback(IP);
+        }
+        assert_eq!(model.version(), 1);
+
+        // Changes since are picked up after the interval
+        clock.advance(Duration::from_millis(100));
+        model.ip_feedback(IP);
+        assert_eq!(model.version(), 2);
+
+        // An unknown ip has no score to change
+        clock.advance(Duration::from_millis(100));
+        model.ip_feedback(IP + 1);
+        assert_eq!(model.version(), 2);
+
+        // Pending changes are folded into a model update
+        model.ip_feedback(IP);
+        model.ip_feedback(IP);
+        assert_eq!(model.version(), 3);
+        model.update_model(
+            std::iter::empty::<QoSTransactionMeta<()>>(),
+            16,
+            16,
+            (1, HashMap::new()),
+        );
+        assert_eq!(model.version(), 4);
+        clock.advance(Duration::from_millis(100));
+        model.ip_feedback(IP + 1);
+        assert_eq!(model.version(), 4);
+    }
+
+    #[test]
+    fn test_flush_feedback_picks_up_feedback_inside_interval() {
+        let clock = ManualClock::new();
+        let mut model = IpSignerStakeModel::<16, 16, _>::with_clock(
+            [(IP, 1.0)],
+            [],
+            HashMap::new(),
+            1,
+            clock.clone(),
+        );
+        model.ip_feedback(IP);
+        assert_eq!(model.version(), 1);
+
+        // Feedback inside the interval waits without further feedback
+        clock.advance(Duration::from_millis(50));
+        model.ip_feedback(IP);
+        model.flush_feedback();
+        assert_eq!(model.version(), 1);
+
+        clock.advance(Duration::from_millis(50));
+        model.flush_feedback();
+        assert_eq!(model.version(), 2);
+
+        // Nothing pending, nothing to bump
+        clock.advance(Duration::from_millis(100));
+        model.flush_feedback();
+        assert_eq!(model.version(), 2);
+    }
+}
@@ -1,2 +1,3 @@
+pub mod ensemble;
 pub mod ip_signer;
 pub mod ip_signer_stake;
@@ -0,0 +1,316 @@
+//! Bounded-memory traffic sketches used to reason about sources that do
+//! not (or not yet) have an entry in a model's score tables.
+
+use crate::ONE;
+use solana_qos_internal_common::{
+    transaction_features::account_key, transaction_meta::F64,
+};
+
+/// A Count-Min sketch with conservative updates. Estimates never
+/// undercount, and overcount by at most `e * total / WIDTH` with
+/// probability `1 - exp(-DEPTH)`.
+pub struct CountMinSketch<const WIDTH: usize, const DEPTH: usize> {
+    counters: Box<[[u32; WIDTH]; DEPTH]>,
+    total: u64,
+}
+
+impl<const WIDTH: usize, const DEPTH: usize>
+    CountMinSketch<WIDTH, DEPTH>
+{
+    const _ASSERT_NONZERO: () = assert!(WIDTH > 0 && DEPTH > 0);
+    const _ASSERT_SEEDS: () = assert!(DEPTH <= ROW_SEEDS.len());
+
+    pub fn new() -> CountMinSketch<WIDTH, DEPTH> {
+        // Avoid building the (potentially large) array on the stack
+        let counters = vec![[0_u32; WIDTH]; DEPTH]
+            .into_boxed_slice()
+            .try_into()
+            .unwrap_or_else(|_| unreachable!("length is DEPTH"));
+
+        CountMinSketch { counters, total: 0 }
+    }
+
+    /// Adds `count` occurrences of `key`, returning the new estimate
+    #[inline(always)]
+    pub fn add(&mut self, key: u64, count: u32) -> u32 {
+        let estimate = self.estimate(key).saturating_add(count);
+
+        // Conservative update: only raise counters that are below the
+        // new estimate
+        for (row, counters) in self.counters.iter_mut().enumerate() {
+            let counter = unsafe {
+                counters.get_unchecked_mut(column::<WIDTH>(key, row))
+            };
+            *counter = (*counter).max(estimate);
+        }
+        self.total += count as u64;
+
+        estimate
+    }
+
+    #[inline(always)]
+    pub fn estimate(&self, key: u64) -> u32 {
+        self.counters
+            .iter()
+            .enumerate()
+            .map(|(row, counters)| unsafe {
+                *counters.get_unchecked(column::<WIDTH>(key, row))
+            })
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Total count added since creation, subject to decay
+    #[inline(always)]
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Halves all counters so that estimates favor recent traffic
+    pub fn decay(&mut self) {
+        for counters in self.counters.iter_mut() {
+            for counter in counters.iter_mut() {
+                *counter >>= 1;
+            }
+        }
+        self.total >>= 1;
+    }
+}
+
+impl<const WIDTH: usize, const DEPTH: usize> Default
+    for CountMinSketch<WIDTH, DEPTH>
+{
+    fn default() -> Self {
+        CountMinSketch::new()
+    }
+}
+
+const ROW_SEEDS: [u64; 8] = [
+    0x9e3779b97f4a7c15,
+    0xbf58476d1ce4e5b9,
+    0x94d049bb133111eb,
+    0x2545f4914f6cdd1d,
+    0xd6e8feb86659fd93,
+    0xa0761d6478bd642f,
+    0xe7037ed1a0b428db,
+    0x8ebc6af09c88c6e3,
+];
+
+#[inline(always)]
+fn column<const WIDTH: usize>(key: u64, row: usize) -> usize {
+    // splitmix64 finalizer
+    let mut x = key ^ ROW_SEEDS[row];
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^= x >> 31;
+
+    // Map to [0, WIDTH) without a modulo
+    ((x as u128 * WIDTH as u128) >> 64) as usize
+}
+
+/// Sketch width. With a depth of 4, each sketch uses 256KB.
+const WIDTH: usize = 1 << 14;
+const DEPTH: usize = 4;
+
+/// Sources whose share of recent traffic exceeds this are considered
+/// suspiciously heavy if the model knows nothing about them.
+// TODO: hard coded parameter
+const SUSPICIOUS_SHARE: f64 = 1e-3;
+
+/// Sketches are not trusted until they have seen this much traffic
+// TODO: hard coded parameter
+const MIN_TOTAL: u64 = 10_000;
+
+/// Penalty applied to unknown heavy sources with no failures
+// TODO: hard coded parameter
+const HEAVY_UNKNOWN_PENALTY: f64 = 0.5;
+const MIN_PENALTY: f64 = 0.01;
+
+/// Traffic volume and failure counts for one kind of source
+pub struct SourceSketch {
+    volume: CountMinSketch<WIDTH, DEPTH>,
+    failures: CountMinSketch<WIDTH, DEPTH>,
+}
+
+impl SourceSketch {
+    pub fn new() -> SourceSketch {
+        SourceSketch {
+            volume: CountMinSketch::new(),
+            failures: CountMinSketch::new(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn record(&mut self, key: u64) {
+        self.volume.add(key, 1);
+    }
+
+    #[inline(always)]
+    pub fn record_failure(&mut self, key: u64) {
+        self.failures.add(key, 1);
+    }
+
+    /// Estimated fraction of recent traffic sent by this source
+    #[inline(always)]
+    pub fn share(&self, key: u64) -> f64 {
+        self.volume.estimate(key) as f64
+            / self.volume.total().max(1) as f64
+    }
+
+    /// Estimated fraction of this source's recent traffic that failed
+    #[inline(always)]
+    pub fn failure_rate(&self, key: u64) -> f64 {
+        let volume = self.volume.estimate(key).max(1);
+        (self.failures.estimate(key) as f64 / volume as f64).min(1.0)
+    }
+
+    #[inline(always)]
+    pub fn is_heavy(&self, key: u64) -> bool {
+        self.volume.total() >= MIN_TOTAL
+            && self.share(key) >= SUSPICIOUS_SHARE
+    }
+
+    /// A source deserves a slot in a full table of `capacity` entries if
+    /// its share of traffic is above that of an average slot. At most
+    /// `capacity` sources can pass this check.
+    #[inline(always)]
+    pub fn deserves_slot(&self, key: u64, capacity: usize) -> bool {
+        self.volume.estimate(key) as u128 * capacity as u128
+            >= self.volume.total() as u128
+    }
+
+    /// Score multiplier for a source without a table entry. Light
+    /// sources are left alone while heavy sources are penalized, more
+    /// so if they fail often.
+    #[inline(always)]
+    pub fn unknown_source_penalty(&self, key: u64) -> F64 {
+        if !self.is_heavy(key) {
+            return ONE;
+        }
+
+        F64::from(
+            (HEAVY_UNKNOWN_PENALTY * (1.0 - self.failure_rate(key)))
+                .max(MIN_PENALTY),
+        )
+    }
+
+    pub fn decay(&mut self) {
+        self.volume.decay();
+        self.failures.decay();
+    }
+}
+
+impl Default for SourceSketch {
+    fn default() -> Self {
+        SourceSketch::new()
+    }
+}
+
+/// Tracks traffic volume and failures for all ips and signers in
+/// bounded memory, including those evicted from or never admitted to a
+/// model's score tables.
+#[derive(Default)]
+pub struct HeavyHitterSketch {
+    pub ips: SourceSketch,
+    pub signers: SourceSketch,
+}
+
+impl HeavyHitterSketch {
+    pub fn new() -> HeavyHitterSketch {
+        HeavyHitterSketch::default()
+    }
+
+    #[inline(always)]
+    pub fn record_packet(&mut self, ip: u32, signer: &[u8; 32]) {
+        self.ips.record(ip_key(ip));
+        self.signers.record(signer_key(signer));
+    }
+
+    pub fn decay(&mut self) {
+        self.ips.decay();
+        self.signers.decay();
+    }
+}
+
+#[inline(always)]
+pub fn ip_key(ip: u32) -> u64 {
+    ip as u64
+}
+
+/// Signers are accounts, so they are keyed like any other
+#[inline(always)]
+pub fn signer_key(signer: &[u8; 32]) -> u64 {
+    account_key(signer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_underestimates() {
+        let mut sketch = CountMinSketch::<64, 4>::new();
+        for key in 0..1_000_u64 {
+            sketch.add(key, (key % 7) as u32 + 1);
+        }
+
+        for key in 0..1_000_u64 {
+            assert!(sketch.estimate(key) > (key % 7) as u32);
+        }
+        assert_eq!(
+            sketch.total(),
+            (0..1_000_u64)
+                .map(|key| key % 7 + 1)
+                .sum::<u64>()
+        );
+    }
+
+    #[test]
+    fn decay_halves_counts() {
+        let mut sketch = CountMinSketch::<1024, 4>::new();
+        sketch.add(42, 100);
+        sketch.decay();
+        assert_eq!(sketch.estimate(42), 50);
+        assert_eq!(sketch.total(), 50);
+    }
+
+    #[test]
+    fn flags_heavy_sources() {
+        let mut sketch = HeavyHitterSketch::new();
+        let signer = [0; 32];
+
+        // Many light sources
+        for ip in 0..MIN_TOTAL as u32 {
+            sketch.record_packet(ip, &signer);
+        }
+
+        // One heavy source that fails half the time
+        let heavy = u32::MAX;
+        for i in 0..1_000 {
+            sketch.record_packet(heavy, &signer);
+            if i % 2 == 0 {
+                sketch.ips.record_failure(ip_key(heavy));
+            }
+        }
+
+        assert!(!sketch.ips.is_heavy(ip_key(1)));
+        assert_eq!(
+            sketch
+                .ips
+                .unknown_source_penalty(ip_key(1)),
+            ONE
+        );
+        assert!(sketch.ips.is_heavy(ip_key(heavy)));
+        assert!(
+            sketch
+                .ips
+                .unknown_source_penalty(ip_key(heavy))
+                <= F64::from(HEAVY_UNKNOWN_PENALTY * 0.5)
+        );
+
+        assert!(sketch
+            .ips
+            .deserves_slot(ip_key(heavy), 16));
+        assert!(!sketch.ips.deserves_slot(ip_key(1), 16));
+    }
+}
@@ -1,6 +1,9 @@
-//! A fast mpsc. Not fair, not fifo. It's simply a wrapper
-//! around multiple spsc with all the consumer ends held by a
-//! single consumer.
+//! A fast mpsc. It's simply a wrapper around multiple spsc with all the
+//! consumer ends held by a single consumer.
+//!
+//! Each spsc is a lane. Lanes are popped with weighted deficit round
+//! robin, so a lane with weight w receives w pops per round while it
+//! has data. Ordering is fifo within a lane but not across lanes.
 use bytemuck::Pod;
 use que::{
     error::QueError,
@@ -11,35 +14,253 @@ use que::{
     page_size::PageSize,
 };
 
+/// Identifies a lane for the lifetime of its [Consumer]. Ids are not
+/// reused.
+pub type LaneId = usize;
+
+/// Counters for a single lane
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LaneStats {
+    pub id: LaneId,
+    pub weight: u32,
+
+    /// Number of items popped from this lane
+    pub popped: u64,
+
+    /// Number of turns that ended because the lane was empty, as
+    /// opposed to exhausting its quantum
+    pub empty_turns: u64,
+
+    /// Number of turns that ended with the quantum exhausted, i.e.
+    /// the lane had more data than its share
+    pub saturated_turns: u64,
+}
+
+struct Lane<T, const CAP_PER_CHANNEL: usize> {
+    consumer: QueConsumer<T, CAP_PER_CHANNEL>,
+
+    /// Pops left in the current turn. Zero between turns.
+    deficit: u32,
+
+    /// Removed once empty
+    retiring: bool,
+
+    stats: LaneStats,
+}
+
 pub struct Consumer<T, const CAP_PER_CHANNEL: usize> {
-    pub consumers: Vec<QueConsumer<T, CAP_PER_CHANNEL>>,
-    pub last: usize,
+    lanes: Vec<Lane<T, CAP_PER_CHANNEL>>,
+
+    /// Index of the lane whose turn it is
+    current: usize,
+
+    next_id: LaneId,
 }
 
 impl<T: Pod, const CAP_PER_CHANNEL: usize>
     Consumer<T, CAP_PER_CHANNEL>
 {
-    /// This is an unfair (non-fifo) pop
+    /// A consumer without lanes
+    pub fn new() -> Consumer<T, CAP_PER_CHANNEL> {
+        Consumer {
+            lanes: vec![],
+            current: 0,
+            next_id: 0,
+        }
+    }
+
+    /// Adds a lane over an already joined channel. Panics if `weight`
+    /// is zero.
+    pub fn add_lane(
+        &mut self,
+        consumer: QueConsumer<T, CAP_PER_CHANNEL>,
+        weight: u32,
+    ) -> LaneId {
+        assert!(weight > 0, "lane weight must be positive");
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.lanes.push(Lane {
+            consumer,
+            deficit: 0,
+            retiring: false,
+            stats: LaneStats {
+                id,
+                weight,
+                ..LaneStats::default()
+            },
+        });
+
+        id
+    }
+
+    /// Creates (or joins) the channel `{base_name}_{id:03}` and adds a
+    /// lane for it, returning the producer end.
+    pub fn add_producer(
+        &mut self,
+        base_name: &str,
+        weight: u32,
+        #[cfg(target_os = "linux")] page_size: PageSize,
+    ) -> Result<(LaneId, QueProducer<T, CAP_PER_CHANNEL>), QueError>
+    {
+        let shmem_id = format!("{base_name}_{:03}", self.next_id);
+        let producer = unsafe {
+            QueProducer::join_or_create_shmem(
+                &shmem_id,
+                #[cfg(target_os = "linux")]
+                page_size,
+            )?
+        };
+        let consumer = unsafe {
+            QueConsumer::join_shmem(
+                &shmem_id,
+                #[cfg(target_os = "linux")]
+                page_size,
+            )?
+        };
+
+        Ok((self.add_lane(consumer, weight), producer))
+    }
+
+    /// Retires a lane. It keeps being popped until empty and is then
+    /// removed. Returns false if there is no such lane.
+    pub fn retire_lane(&mut self, id: LaneId) -> bool {
+        match self.lane_mut(id) {
+            Some(lane) => {
+                lane.retiring = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Changes the weight of a lane, taking effect on its next turn.
+    /// Panics if `weight` is zero.
+    pub fn set_weight(&mut self, id: LaneId, weight: u32) -> bool {
+        assert!(weight > 0, "lane weight must be positive");
+        match self.lane_mut(id) {
+            Some(lane) => {
+                lane.stats.weight = weight;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Pops the next item in weighted round robin order
+    #[inline(always)]
     pub fn pop(&mut self) -> Option<T> {
-        let len = self.consumers.len();
-        let (right, left) = self.consumers.split_at_mut(self.last);
+        self.pop_tagged()
+            .map(|(_id, item)| item)
+    }
+
+    /// Same as [Consumer::pop], also returning which lane the item
+    /// came from
+    pub fn pop_tagged(&mut self) -> Option<(LaneId, T)> {
+        // Every lane is visited at most once before giving up
+        for _ in 0..self.lanes.len() {
+            let lane = &mut self.lanes[self.current];
+            if lane.deficit == 0 {
+                // Start of this lane's turn
+                lane.deficit = lane.stats.weight;
+            }
 
-        let mut visited = 0;
+            match lane.consumer.pop() {
+                Some(item) => {
+                    let id = lane.stats.id;
+                    lane.deficit -= 1;
+                    lane.stats.popped += 1;
+                    if lane.deficit == 0 {
+                        lane.stats.saturated_turns += 1;
+                        self.advance();
+                    }
+                    return Some((id, item));
+                }
+                None => {
+                    // Empty lanes forfeit the rest of their quantum
+                    lane.deficit = 0;
+                    lane.stats.empty_turns += 1;
+                    if lane.retiring {
+                        self.lanes.remove(self.current);
+                        if self.current == self.lanes.len() {
+                            self.current = 0;
+                        }
+                    } else {
+                        self.advance();
+                    }
+                }
+            }
+        }
 
-        let opt = right
+        None
+    }
+
+    /// Pops up to `max` items into `out`, returning how many were
+    /// popped
+    pub fn pop_n(&mut self, max: usize, out: &mut Vec<T>) -> usize {
+        let mut popped = 0;
+        while popped < max {
+            let Some(item) = self.pop() else {
+                break;
+            };
+            out.push(item);
+            popped += 1;
+        }
+
+        popped
+    }
+
+    /// Signals liveness on every lane
+    pub fn beat(&self) {
+        for lane in &self.lanes {
+            lane.consumer.beat();
+        }
+    }
+
+    pub fn lane_stats(&self, id: LaneId) -> Option<LaneStats> {
+        self.lanes
+            .iter()
+            .find(|lane| lane.stats.id == id)
+            .map(|lane| lane.stats)
+    }
+
+    /// Counters of all lanes, including retiring ones that are not yet
+    /// drained
+    pub fn stats(&self) -> impl Iterator<Item = LaneStats> + '_ {
+        self.lanes.iter().map(|lane| lane.stats)
+    }
+
+    pub fn num_lanes(&self) -> usize {
+        self.lanes.len()
+    }
+
+    fn lane_mut(
+        &mut self,
+        id: LaneId,
+    ) -> Option<&mut Lane<T, CAP_PER_CHANNEL>> {
+        self.lanes
             .iter_mut()
-            .chain(left.iter_mut())
-            .find_map(|consumer| {
-                visited += 1;
-                consumer.pop()
-            });
+            .find(|lane| lane.stats.id == id)
+    }
 
-        self.last = (self.last + visited) % len;
+    #[inline(always)]
+    fn advance(&mut self) {
+        self.current += 1;
+        if self.current == self.lanes.len() {
+            self.current = 0;
+        }
+    }
+}
 
-        opt
+impl<T: Pod, const CAP_PER_CHANNEL: usize> Default
+    for Consumer<T, CAP_PER_CHANNEL>
+{
+    fn default() -> Self {
+        Consumer::new()
     }
 }
 
+/// Creates `senders` channels with equal weight
 pub fn bounded<T: Pod, const CAP_PER_CHANNEL: usize>(
     senders: usize,
     base_name: &str,
@@ -51,28 +272,143 @@ pub fn bounded<T: Pod, const CAP_PER_CHANNEL: usize>(
     ),
     QueError,
 > {
-    let mut consumers = vec![];
+    let mut consumer = Consumer::new();
     let mut producers = vec![];
 
-    for i in 0..senders {
-        let shmem_id = format!("{base_name}_{i:03}");
-        let p = unsafe {
-            QueProducer::join_or_create_shmem(
-                &shmem_id,
-                #[cfg(target_os = "linux")]
-                page_size,
-            )?
-        };
-        let c = unsafe {
-            QueConsumer::join_shmem(
-                &shmem_id,
-                #[cfg(target_os = "linux")]
-                page_size,
-            )?
-        };
-        producers.push(p);
-        consumers.push(c);
+    for _ in 0..senders {
+        let (_id, producer) = consumer.add_producer(
+            base_name,
+            1,
+            #[cfg(target_os = "linux")]
+            page_size,
+        )?;
+        producers.push(producer);
     }
 
-    Ok((producers, Consumer { consumers, last: 0 }))
+    Ok((producers, consumer))
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_qos_internal_common::test_utils::ShmRegion;
+
+    use super::*;
+
+    const CAP: usize = 1024;
+
+    /// Items are tagged `lane * 1000 + seq`
+    fn lanes(
+        region: &ShmRegion,
+        weights: &[u32],
+    ) -> (Vec<QueProducer<u64, CAP>>, Consumer<u64, CAP>) {
+        let mut consumer = Consumer::new();
+        let producers = weights
+            .iter()
+            .map(|&weight| {
+                consumer
+                    .add_producer(
+                        region.name(),
+                        weight,
+                        #[cfg(target_os = "linux")]
+                        PageSize::Standard,
+                    )
+                    .unwrap()
+                    .1
+            })
+            .collect();
+
+        (producers, consumer)
+    }
+
+    fn fill(
+        producers: &mut [QueProducer<u64, CAP>],
+        lane: usize,
+        count: u64,
+    ) {
+        for seq in 0..count {
+            producers[lane].push(&(lane as u64 * 1000 + seq));
+        }
+        producers[lane].sync();
+    }
+
+    #[test]
+    fn test_pop_ratios_follow_weights() {
+        let region = ShmRegion::new("mpsc_ratios");
+        let (mut producers, mut consumer) = lanes(&region, &[1, 2, 3]);
+        for lane in 0..3 {
+            fill(&mut producers, lane, 600);
+        }
+
+        // Every lane stays backlogged for 100 rounds
+        let mut popped = [0_u64; 3];
+        for _ in 0..600 {
+            let (id, item) = consumer.pop_tagged().unwrap();
+            assert_eq!(item, id as u64 * 1000 + popped[id]);
+            popped[id] += 1;
+        }
+        assert_eq!(popped, [100, 200, 300]);
+
+        for stats in consumer.stats() {
+            assert_eq!(stats.popped, popped[stats.id]);
+            assert_eq!(stats.saturated_turns, 100);
+            assert_eq!(stats.empty_turns, 0);
+        }
+    }
+
+    #[test]
+    fn test_retired_lane_drains_then_is_removed() {
+        let region = ShmRegion::new("mpsc_retire");
+        let (mut producers, mut consumer) = lanes(&region, &[1, 1]);
+        fill(&mut producers, 0, 3);
+        fill(&mut producers, 1, 1);
+
+        assert!(consumer.retire_lane(0));
+        let mut items = vec![];
+        while let Some(item) = consumer.pop() {
+            items.push(item);
+        }
+        assert_eq!(items, [0, 1000, 1, 2]);
+
+        assert_eq!(consumer.num_lanes(), 1);
+        assert!(consumer.lane_stats(0).is_none());
+        assert!(!consumer.retire_lane(0));
+        assert!(!consumer.set_weight(0, 1));
+
+        // The remaining lane is unaffected
+        fill(&mut producers, 1, 2);
+        assert_eq!(consumer.pop(), Some(1000));
+        assert_eq!(consumer.lane_stats(1).unwrap().popped, 2);
+    }
+
+    #[test]
+    fn test_pop_n_across_removal() {
+        for retired in 0..3 {
+            let region =
+                ShmRegion::new(&format!("mpsc_pop_n_{retired}"));
+            let (mut producers, mut consumer) =
+                lanes(&region, &[1, 1, 1]);
+            let live: Vec<usize> = (0..3)
+                .filter(|&lane| lane != retired)
+                .collect();
+            for &lane in &live {
+                fill(&mut producers, lane, 3);
+            }
+            assert!(consumer.retire_lane(retired));
+
+            // Lanes keep alternating whichever index was removed
+            let mut out = vec![];
+            assert_eq!(consumer.pop_n(4, &mut out), 4);
+            assert_eq!(consumer.num_lanes(), 2);
+            assert_eq!(consumer.pop_n(100, &mut out), 2);
+            assert_eq!(consumer.pop_n(100, &mut out), 0);
+
+            let expected: Vec<u64> = (0..3)
+                .flat_map(|seq| {
+                    live.iter()
+                        .map(move |&lane| lane as u64 * 1000 + seq)
+                })
+                .collect();
+            assert_eq!(out, expected);
+        }
+    }
 }
@@ -0,0 +1,73 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use que::page_size::PageSize;
+use solana_qos_core::{
+    backlog::Backlog,
+    banking::{TransactionContainer, QUEUE_CAPACITY},
+    ScoredTransaction, Stats, TransactionFeatures, F64,
+};
+use solana_qos_internal_common::test_utils::{packet, ShmRegion};
+
+fn scored(sig_key: u64) -> ScoredTransaction {
+    // Spread scores so that pushes land all over the heap
+    let score =
+        (sig_key.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 11) as f64;
+    ScoredTransaction {
+        score: F64::from(score),
+        sig_key,
+        packet: packet(sig_key as u8),
+        ipv4: 0,
+        packet_key: sig_key,
+        features: TransactionFeatures::new_for_tests(0, [0; 32]),
+        value_rate: F64::from(1.0),
+        model_version: 0,
+    }
+}
+
+/// Queues into a full container, with and without a backlog mirroring
+/// it. Each push evicts, so each writes one entry and clears another.
+fn queue(c: &mut Criterion) {
+    let region = ShmRegion::new("bench_backlog");
+    let mut g = c.benchmark_group("Queue");
+
+    for persisted in [false, true] {
+        let mut container = TransactionContainer::new(None, 1_000);
+        if persisted {
+            let mut backlog =
+                Backlog::<QUEUE_CAPACITY>::open_or_create(
+                    region.name(),
+                    PageSize::Standard,
+                )
+                .unwrap();
+            backlog.take();
+            container.persist(backlog);
+        }
+
+        let mut stats = Stats::default();
+        let mut sig_key = 0;
+        while container.len() < QUEUE_CAPACITY - 1 {
+            container.queue(scored(sig_key), &mut stats);
+            sig_key += 1;
+        }
+
+        let name = if persisted {
+            "full-queue-persisted"
+        } else {
+            "full-queue"
+        };
+        g.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    sig_key += 1;
+                    scored(sig_key)
+                },
+                |tx| container.queue(tx, &mut stats),
+                criterion::BatchSize::SmallInput,
+            );
+        });
+    }
+
+    g.finish();
+}
+
+criterion_group!(backlog, queue);
+criterion_main!(backlog);
@@ -0,0 +1,300 @@
+//! Transactions queued by qos, persisted in a named shared memory
+//! region so that the next instance can queue them again instead of
+//! dropping them.
+//!
+//! The region mirrors the queue entry by entry. Each transaction is
+//! written to the entry of its slab slot as it is queued, and that
+//! entry is marked invalid as soon as it is dequeued, before it is
+//! transmitted. Nothing is copied in bulk, and a crash at any point
+//! leaves the region holding exactly the untransmitted queue, less at
+//! most the one entry being written.
+
+use std::{
+    mem::size_of,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use que::{page_size::PageSize, shmem::Shmem};
+use solana_qos_common::packet_bytes::PacketBytes;
+use solana_qos_internal_common::{
+    packet_bytes, transaction_features::PacketSource,
+};
+use solana_sdk::packet::Packet;
+
+use crate::{error::SharedCacheError, ScoredTransaction};
+
+const MAGIC: u64 = u64::from_le_bytes(*b"QOS-BKLG");
+
+/// Bumped whenever the layout of the region changes
+const VERSION: u32 = 3;
+
+#[repr(C)]
+struct BacklogHeader {
+    magic: u64,
+    version: u32,
+    entry_size: u32,
+}
+
+impl BacklogHeader {
+    fn is_compatible(&self) -> bool {
+        self.magic == MAGIC
+            && self.version == VERSION
+            && self.entry_size == size_of::<BacklogEntry>() as u32
+    }
+}
+
+#[repr(C)]
+struct BacklogEntry {
+    packet_bytes: PacketBytes,
+    source: u8,
+
+    /// Set once the rest of the entry is written, and cleared when the
+    /// transaction is dequeued
+    valid: AtomicU8,
+    _padding: [u8; 6],
+}
+
+/// Holds up to `N` transactions, one per slab slot of the queue
+pub struct Backlog<const N: usize> {
+    shmem: Shmem,
+}
+
+impl<const N: usize> Backlog<N> {
+    const SIZE: usize =
+        size_of::<BacklogHeader>() + N * size_of::<BacklogEntry>();
+
+    pub fn open_or_create(
+        name: &str,
+        page_size: PageSize,
+    ) -> Result<Backlog<N>, SharedCacheError> {
+        let shmem = Shmem::open_or_create(
+            name,
+            Self::SIZE as i64,
+            page_size,
+        )
+        .map_err(|e| SharedCacheError::Shmem(format!("{e:?}")))?;
+
+        Ok(Backlog { shmem })
+    }
+
+    fn header(&mut self) -> &mut BacklogHeader {
+        // SAFETY: the region is page aligned and at least SIZE bytes.
+        // Any bit pattern is a valid header.
+        unsafe { &mut *self.shmem.get_mut_ptr().cast() }
+    }
+
+    /// Entry of the transaction in slab slot `slot`
+    fn entry(&mut self, slot: usize) -> &mut BacklogEntry {
+        assert!(slot < N, "slot {slot} out of {N}");
+        // SAFETY: slot < N, so this is within the region. Any bit
+        // pattern is a valid entry.
+        unsafe {
+            &mut *self
+                .shmem
+                .get_mut_ptr()
+                .add(size_of::<BacklogHeader>())
+                .cast::<BacklogEntry>()
+                .add(slot)
+        }
+    }
+
+    /// Persists a transaction queued in slab slot `slot`, replacing
+    /// whatever was there
+    #[inline(always)]
+    pub fn write(
+        &mut self,
+        slot: usize,
+        transaction: &ScoredTransaction,
+    ) {
+        let entry = self.entry(slot);
+        entry.valid.store(0, Ordering::Relaxed);
+        entry.packet_bytes = *transaction.packet_bytes();
+        entry.source = transaction.features.source as u8;
+
+        // Only now is the entry restored after a crash
+        entry.valid.store(1, Ordering::Release);
+    }
+
+    /// Drops the transaction in slab slot `slot`, as it was dequeued
+    #[inline(always)]
+    pub fn clear(&mut self, slot: usize) {
+        self.entry(slot)
+            .valid
+            .store(0, Ordering::Release);
+    }
+
+    /// Takes the persisted transactions in slot order, leaving the
+    /// backlog empty and ready for writes. Returns none if the region
+    /// holds no backlog or one written by an incompatible version.
+    ///
+    /// Must be called before any write, as writes to a region not yet
+    /// taken are dropped along with it if it is incompatible.
+    pub fn take(&mut self) -> Vec<(PacketSource, Packet)> {
+        let compatible = self.header().is_compatible();
+        let mut taken = vec![];
+        for slot in 0..N {
+            let entry = self.entry(slot);
+            let valid = entry.valid.swap(0, Ordering::Acquire) == 1;
+            if !compatible || !valid {
+                continue;
+            }
+            if let Some(&source) =
+                PacketSource::ALL.get(entry.source as usize)
+            {
+                taken.push((
+                    source,
+                    packet_bytes::as_packet(entry.packet_bytes),
+                ));
+            }
+        }
+
+        if !compatible {
+            let header = self.header();
+            header.version = VERSION;
+            header.entry_size = size_of::<BacklogEntry>() as u32;
+            header.magic = MAGIC;
+        }
+        taken
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_qos_internal_common::{
+        test_utils::{packet, packet_id, ShmRegion},
+        transaction_features::TransactionFeatures,
+        transaction_meta::F64,
+    };
+
+    use super::*;
+
+    type TestBacklog = Backlog<4>;
+
+    fn region(test: &str) -> ShmRegion {
+        ShmRegion::new(&format!("backlog_{test}"))
+    }
+
+    fn open(region: &ShmRegion) -> TestBacklog {
+        TestBacklog::open_or_create(region.name(), PageSize::Standard)
+            .unwrap()
+    }
+
+    fn transaction(id: u8, source: PacketSource) -> ScoredTransaction {
+        let mut features =
+            TransactionFeatures::new_for_tests(0, [0; 32]);
+        features.source = source;
+
+        ScoredTransaction {
+            score: F64::from(1.0),
+            sig_key: id as u64,
+            packet: packet(id),
+            ipv4: 0,
+            packet_key: id as u64,
+            features,
+            value_rate: F64::from(1.0),
+            model_version: 0,
+        }
+    }
+
+    fn ids(
+        taken: &[(PacketSource, Packet)],
+    ) -> Vec<(PacketSource, u8)> {
+        taken
+            .iter()
+            .map(|(source, packet)| (*source, packet_id(packet)))
+            .collect()
+    }
+
+    fn taken_ids(backlog: &mut TestBacklog) -> Vec<u8> {
+        ids(&backlog.take())
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect()
+    }
+
+    #[test]
+    fn test_write_take_round_trip() {
+        let region = region("round_trip");
+        let mut backlog = open(&region);
+        assert!(backlog.take().is_empty());
+
+        backlog.write(3, &transaction(1, PacketSource::Tpu));
+        backlog.write(0, &transaction(2, PacketSource::Re2));
+        backlog.write(1, &transaction(3, PacketSource::Fwd));
+
+        // A later instance takes the backlog in slot order, once,
+        // although the last one never saved anything on exit
+        drop(backlog);
+        let mut backlog = open(&region);
+        assert_eq!(
+            ids(&backlog.take()),
+            [
+                (PacketSource::Re2, 2),
+                (PacketSource::Fwd, 3),
+                (PacketSource::Tpu, 1),
+            ]
+        );
+        assert!(backlog.take().is_empty());
+    }
+
+    #[test]
+    fn test_cleared_entries_are_skipped() {
+        let region = region("cleared");
+        let mut backlog = open(&region);
+        backlog.take();
+        for id in 1..=3 {
+            backlog.write(
+                id as usize,
+                &transaction(id, PacketSource::Tpu),
+            );
+        }
+
+        // One was transmitted, and another's slot was reused
+        backlog.clear(2);
+        backlog.clear(3);
+        backlog.write(3, &transaction(4, PacketSource::Tpu));
+        assert_eq!(taken_ids(&mut backlog), [1, 4]);
+    }
+
+    #[test]
+    fn test_crash_mid_write_keeps_the_rest() {
+        let region = region("crash");
+        let mut backlog = open(&region);
+        backlog.take();
+        backlog.write(0, &transaction(1, PacketSource::Tpu));
+        backlog.write(1, &transaction(2, PacketSource::Tpu));
+
+        // Crashed while overwriting a reused slot, before marking it
+        // valid
+        let entry = backlog.entry(1);
+        entry.valid.store(0, Ordering::Relaxed);
+        entry.packet_bytes =
+            *transaction(3, PacketSource::Tpu).packet_bytes();
+        drop(backlog);
+
+        assert_eq!(taken_ids(&mut open(&region)), [1]);
+    }
+
+    #[test]
+    fn test_incompatible_backlog_is_dropped() {
+        let region = region("incompatible");
+        let mut backlog = open(&region);
+        let transaction = transaction(1, PacketSource::Tpu);
+
+        // Never taken, so never made compatible
+        backlog.write(0, &transaction);
+        assert!(backlog.take().is_empty());
+
+        backlog.write(0, &transaction);
+        backlog.header().version = VERSION + 1;
+        assert!(backlog.take().is_empty());
+
+        backlog.write(0, &transaction);
+        backlog.header().entry_size += 8;
+        assert!(backlog.take().is_empty());
+
+        // Taking made it compatible again
+        backlog.write(0, &transaction);
+        assert_eq!(taken_ids(&mut backlog), [1]);
+    }
+}
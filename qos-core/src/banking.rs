@@ -1,46 +1,95 @@
+use nohash_hasher::IntMap;
 use qos_lru::LRUCache;
-use qos_minmax::MinMaxHeap;
+use qos_minmax::{Handle, IndexedMinMaxHeap};
+use qos_model::interface::QoSModel;
 use que::headless_spmc::producer::Producer as QueProducer;
 use solana_qos_common::{
     ipc_parameters::IPC_QOS_TO_SIG_CAP, packet_bytes::PacketBytes,
 };
-use timer::Timer;
+use solana_qos_internal_common::transaction_meta::F64;
+use timer::{Clock, RealClock};
 
-use crate::{ScoredTransaction, Stats};
+use crate::{
+    backlog::Backlog, xxHash, QoSPartialMeta, ScoredTransaction, Stats,
+};
+
+/// Max number of queued transactions
+pub const QUEUE_CAPACITY: usize = 16384;
 
 /// Stores and prioritizes scored transactions, and periodically
 /// transmits them to the sigverify stage.
-pub struct TransactionContainer {
+///
+/// Queued transactions are indexed by signature so that they can be
+/// purged as soon as they are confirmed, rather than filtered at send
+/// time. Penalties reach queued transactions by rescoring them.
+///
+/// The queue can be persisted to a [Backlog], which is kept in sync
+/// entry by entry so that the next instance picks it up.
+///
+/// The send interval is timed by `C`, so tests can drive it with a
+/// [timer::ManualClock].
+pub struct TransactionContainer<C: Clock = RealClock> {
     /// The value 16384 was determined based on a benchmark that pushed
     /// values when full. Benchmark reached ≈16 Gbps on a Intel(R)
     /// Xeon(R) Gold 5218N CPU using random 1232 byte entries.
-    pub priority_queue_heap: MinMaxHeap<ScoredTransaction, 16384>,
+    ///
+    /// Transactions are keyed by score and stay in place in a slab, so
+    /// heap operations never move packets.
+    priority_queue_heap:
+        IndexedMinMaxHeap<F64, ScoredTransaction, QUEUE_CAPACITY>,
+
+    /// Handles of queued transactions, and their persisted copies
+    index: QueueIndex,
+
+    /// Progress rescoring queued transactions after a model change
+    sweep: RescoreSweep,
 
     /// This sends over scored and prioritized transactions over to be
     /// sigverified and scheduled.
     pub transmitter:
         Option<QueProducer<PacketBytes, IPC_QOS_TO_SIG_CAP>>,
 
+    clock: C,
+
     /// Records the time the transmitter last sent a batch of high
     /// priority transactions to the sigverify stage.
-    last_send: Timer,
+    last_send_ms: u64,
 
     /// Max number of packets per loop to transmit to banking stage
     max_send: usize,
 }
 
 impl TransactionContainer {
-    const SEND_INTERVAL_MS: usize = 100;
     pub fn new(
         transmitter: Option<
             QueProducer<PacketBytes, IPC_QOS_TO_SIG_CAP>,
         >,
         target_pps: usize,
     ) -> TransactionContainer {
+        TransactionContainer::with_clock(
+            transmitter,
+            target_pps,
+            RealClock::new(),
+        )
+    }
+}
+
+impl<C: Clock> TransactionContainer<C> {
+    const SEND_INTERVAL_MS: usize = 100;
+    pub fn with_clock(
+        transmitter: Option<
+            QueProducer<PacketBytes, IPC_QOS_TO_SIG_CAP>,
+        >,
+        target_pps: usize,
+        clock: C,
+    ) -> TransactionContainer<C> {
         TransactionContainer {
             transmitter,
-            priority_queue_heap: MinMaxHeap::new(),
-            last_send: Timer::new(),
+            priority_queue_heap: IndexedMinMaxHeap::new(),
+            index: QueueIndex::new(),
+            sweep: RescoreSweep::default(),
+            last_send_ms: clock.now_ms(),
+            clock,
             max_send: target_pps * Self::SEND_INTERVAL_MS / 1000,
         }
     }
@@ -51,55 +100,207 @@ impl TransactionContainer {
         }
     }
 
+    /// Number of queued transactions
+    pub fn len(&self) -> usize {
+        self.priority_queue_heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.priority_queue_heap.is_empty()
+    }
+
+    /// Max number of transactions transmitted per send interval
+    pub fn transmit_budget(&self) -> usize {
+        self.max_send
+    }
+
+    /// Persists queued transactions to `backlog` from now on, writing
+    /// each as it is queued and dropping it as it is dequeued.
+    /// Transactions already queued are written right away.
+    pub fn persist(&mut self, mut backlog: Backlog<QUEUE_CAPACITY>) {
+        for (handle, _score, tx) in self.priority_queue_heap.iter() {
+            backlog.write(handle.slot(), tx);
+        }
+        self.index.backlog = Some(backlog);
+    }
+
+    /// Queued transactions in no particular order, without dequeuing
+    /// them
+    pub fn iter(&self) -> impl Iterator<Item = &ScoredTransaction> {
+        self.priority_queue_heap
+            .iter()
+            .map(|(_handle, _score, tx)| tx)
+    }
+
+    /// The k highest priority queued transactions, without dequeuing
+    /// them
+    pub fn peek_top_k(
+        &self,
+        k: usize,
+    ) -> Vec<(F64, &ScoredTransaction)> {
+        self.priority_queue_heap
+            .peek_top_k(k)
+            .into_iter()
+            .map(|(_handle, score, tx)| (score, tx))
+            .collect()
+    }
+
     pub fn queue(
         &mut self,
         scored_transaction: ScoredTransaction,
         stats: &mut Stats,
     ) {
+        // A signature is queued at most once. Keep the higher scoring
+        // copy.
+        if let Some(&handle) = self
+            .index
+            .by_signature
+            .get(&scored_transaction.sig_key)
+        {
+            stats.duplicate_packets += 1;
+            match self.priority_queue_heap.get(handle) {
+                Some((score, _))
+                    if score >= scored_transaction.score =>
+                {
+                    return
+                }
+                _ => {
+                    if let Some((_, queued)) =
+                        self.priority_queue_heap.remove(handle)
+                    {
+                        self.index.remove(&queued);
+                    }
+                }
+            }
+        }
+
         // Add transaction to queue.
         // If full, this internally evicts lowest priority transaction
-        let is_full = self
+        let (handle, evicted) = self
             .priority_queue_heap
-            .push(scored_transaction)
-            .is_some();
+            .push(scored_transaction.score, scored_transaction);
+        // Unless it was evicted right away
+        if let Some((_, queued)) = self.priority_queue_heap.get(handle)
+        {
+            self.index.insert(queued, handle);
+        }
 
-        if is_full {
+        if let Some((_, evicted)) = evicted {
+            self.index.remove(&evicted);
             stats.leaked_priority += 1;
         }
     }
 
-    pub fn maybe_retrieve<'a>(
-        &'a mut self,
-        stats: &'a mut Stats,
-        recent_signatures: Option<
-            &'a LRUCache<u64, (), { 1024 * 1024 }>,
-        >,
-    ) -> Option<impl Iterator<Item = ScoredTransaction> + 'a> {
+    /// Dequeues the transaction with this signature, if any, as it has
+    /// already been processed
+    pub fn purge_signature(
+        &mut self,
+        sig_key: u64,
+        stats: &mut Stats,
+    ) -> bool {
+        let Some(handle) = self.index.by_signature.get(&sig_key) else {
+            return false;
+        };
+        let Some((_, tx)) = self.priority_queue_heap.remove(*handle)
+        else {
+            return false;
+        };
+
+        self.index.remove(&tx);
+        stats.recently_processed_queued += 1;
+        true
+    }
+
+    /// Rescores queued transactions that were scored by an older
+    /// version of the model, visiting at most `budget` of them. Meant to
+    /// be called every loop so that a model change is reflected over
+    /// several loops without stalling ingestion.
+    ///
+    /// Returns the number of transactions rescored.
+    pub fn rescore<M: QoSModel>(
+        &mut self,
+        model: &M,
+        args: &M::AdditionalArgs,
+        budget: usize,
+        stats: &mut Stats,
+    ) -> usize {
+        let version = model.version();
+        if version != self.sweep.version {
+            self.sweep = RescoreSweep {
+                version,
+                remaining: self.len(),
+                found_stale: false,
+                cursor: self.sweep.cursor,
+            };
+        }
+
+        let mut rescored = 0;
+        for _ in 0..budget {
+            if self.sweep.remaining == 0 {
+                if !self.sweep.found_stale {
+                    break;
+                }
+
+                // Transactions move within the heap as they are
+                // rescored, so a pass can miss some. Keep sweeping
+                // until a pass finds none stale.
+                self.sweep.remaining = self.len();
+                self.sweep.found_stale = false;
+                continue;
+            }
+            self.sweep.remaining -= 1;
+
+            let index = self.sweep.cursor % self.len().max(1);
+            self.sweep.cursor = index + 1;
+            let Some((handle, _score, tx)) =
+                self.priority_queue_heap.nth(index)
+            else {
+                continue;
+            };
+            if tx.model_version == version {
+                continue;
+            }
+            self.sweep.found_stale = true;
+
+            let score =
+                model.forward(&tx.features, args) * *tx.value_rate;
+            self.priority_queue_heap
+                .update_priority(handle, score);
+            if let Some(tx) = self.priority_queue_heap.get_mut(handle) {
+                tx.score = score;
+                tx.model_version = version;
+            }
+            rescored += 1;
+        }
+
+        stats.rescored_queued += rescored;
+        rescored
+    }
+
+    pub fn maybe_retrieve(
+        &mut self,
+    ) -> Option<impl Iterator<Item = ScoredTransaction> + '_> {
         // Check to see if it's been a while since we've sent to
         // sigverify
-        let send_tick = self.last_send.elapsed_ms()
-            >= Self::SEND_INTERVAL_MS as u64;
+        let now_ms = self.clock.now_ms();
+        let send_tick =
+            now_ms - self.last_send_ms >= Self::SEND_INTERVAL_MS as u64;
 
         if send_tick {
+            self.last_send_ms = now_ms;
+
             // Construct priority queue iterator for high
             // priority transactions
+            let index = &mut self.index;
             let high_prio_iterator = self
                 .priority_queue_heap
                 .get_max_values()
-                .filter(move |tx| {
-                    if recent_signatures
-                        .is_some_and(|rs| rs.contains(tx.sig_key))
-                    {
-                        stats.recently_processed_queued += 1;
-                        false
-                    } else {
-                        true
-                    }
+                .map(|(_score, tx)| {
+                    index.remove(&tx);
+                    tx
                 })
                 .take(self.max_send);
 
-            self.last_send = Timer::new();
-
             Some(high_prio_iterator)
         } else {
             None
@@ -108,38 +309,41 @@ impl TransactionContainer {
 
     /// Panics if there is no transmitter!
     ///
-    /// If using maybe transmit, you are probably running alongside validator so recent_signatures is not an Option
-    pub fn maybe_transmit<'a>(
-        &'a mut self,
-        stats: &'a mut Stats,
-        recent_signatures: &'a LRUCache<u64, (), { 1024 * 1024 }>,
+    /// Transmitted transactions have their partial meta marked as such.
+    pub fn maybe_transmit<const CACHE_SIZE: usize>(
+        &mut self,
+        stats: &mut Stats,
+        qos_tx_partial_metas: &mut LRUCache<
+            xxHash,
+            QoSPartialMeta,
+            CACHE_SIZE,
+            u64,
+        >,
     ) {
         if let Some(ref mut tx_mut) = self.transmitter {
             // Check to see if it's been a while since we've sent to
             // sigverify
-            let send_tick = self.last_send.elapsed_ms()
+            let now_ms = self.clock.now_ms();
+            let send_tick = now_ms - self.last_send_ms
                 >= Self::SEND_INTERVAL_MS as u64;
 
             if send_tick {
-                // Construct priority queue iterator for high
-                // priority transactions
-                let high_prio_iterator = self
-                    .priority_queue_heap
-                    .get_max_values()
-                    .filter(|tx| {
-                        if recent_signatures.contains(tx.sig_key) {
-                            stats.recently_processed_queued += 1;
-                            false
-                        } else {
-                            true
-                        }
-                    })
-                    .take(self.max_send);
-
-                // Send batch
+                // Send batch of high priority transactions
                 let mut sent = 0_usize;
-                for transaction in high_prio_iterator {
+                while sent < self.max_send {
+                    let Some((_score, transaction)) =
+                        self.priority_queue_heap.pop_max()
+                    else {
+                        break;
+                    };
+                    self.index.remove(&transaction);
+
                     tx_mut.push(transaction.packet_bytes());
+                    if let Some(partial_meta) = qos_tx_partial_metas
+                        .peek_mut(&transaction.packet_key)
+                    {
+                        partial_meta.transmitted = true;
+                    }
                     sent += 1;
                 }
 
@@ -149,7 +353,7 @@ impl TransactionContainer {
                 // Update last send attempt time
                 if sent > 0 {
                     stats.banking_transmissions += sent;
-                    self.last_send = Timer::new();
+                    self.last_send_ms = now_ms;
                 }
             }
         } else {
@@ -157,3 +361,299 @@ impl TransactionContainer {
         }
     }
 }
+
+/// State of an incremental pass over the queue
+#[derive(Default)]
+struct RescoreSweep {
+    /// Model version queued transactions are being rescored to
+    version: u64,
+
+    /// Heap position to visit next
+    cursor: usize,
+
+    /// Positions left to visit in this pass
+    remaining: usize,
+
+    /// Whether this pass rescored anything
+    found_stale: bool,
+}
+
+/// Handles of queued transactions by signature. Kept in sync with the
+/// heap, and the backlog if any, on every push, pop, eviction and
+/// purge.
+struct QueueIndex {
+    by_signature: IntMap<u64, Handle>,
+
+    /// Mirrors the queue by slab slot
+    backlog: Option<Backlog<QUEUE_CAPACITY>>,
+}
+
+impl QueueIndex {
+    fn new() -> QueueIndex {
+        QueueIndex {
+            by_signature: IntMap::default(),
+            backlog: None,
+        }
+    }
+
+    fn insert(&mut self, tx: &ScoredTransaction, handle: Handle) {
+        self.by_signature
+            .insert(tx.sig_key, handle);
+        if let Some(backlog) = &mut self.backlog {
+            backlog.write(handle.slot(), tx);
+        }
+    }
+
+    /// Signatures are unique in the queue, so a dequeued transaction is
+    /// identified by its signature
+    fn remove(&mut self, tx: &ScoredTransaction) {
+        let Some(handle) = self.by_signature.remove(&tx.sig_key) else {
+            return;
+        };
+        if let Some(backlog) = &mut self.backlog {
+            backlog.clear(handle.slot());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use que::page_size::PageSize;
+    use solana_qos_internal_common::{
+        test_utils::{packet, packet_id, ShmRegion},
+        transaction_features::TransactionFeatures,
+        transaction_meta::QoSTransactionMeta,
+    };
+    use timer::ManualClock;
+
+    use super::*;
+
+    fn container() -> TransactionContainer<ManualClock> {
+        TransactionContainer::with_clock(
+            None,
+            1_000,
+            ManualClock::new(),
+        )
+    }
+
+    fn scored(
+        sig_key: u64,
+        ipv4: u32,
+        score: f64,
+    ) -> ScoredTransaction {
+        ScoredTransaction {
+            score: F64::from(score),
+            sig_key,
+            packet: packet(sig_key as u8),
+            ipv4,
+            packet_key: sig_key,
+            features: TransactionFeatures::new_for_tests(ipv4, [0; 32]),
+            value_rate: F64::from(1.0),
+            model_version: 0,
+        }
+    }
+
+    /// Halves the score of one ip
+    struct PenalizeIp {
+        ip: u32,
+        version: u64,
+    }
+
+    impl QoSModel for PenalizeIp {
+        type AdditionalArgs = ();
+        type AdditionalTransactionMeta = ();
+        type AdditionalUpdateMeta = ();
+
+        fn forward(
+            &self,
+            features: &TransactionFeatures,
+            _args: &Self::AdditionalArgs,
+        ) -> F64 {
+            F64::from(if features.ip == self.ip { 0.5 } else { 1.0 })
+        }
+
+        fn update_model<'a>(
+            &'a mut self,
+            _transactions: impl Iterator<Item = &'a QoSTransactionMeta<()>>
+                + Clone,
+            _update_meta: Self::AdditionalUpdateMeta,
+        ) {
+        }
+
+        type IpFeedback = u32;
+        fn ip_feedback(&mut self, _ip: Self::IpFeedback) {}
+
+        fn version(&self) -> u64 {
+            self.version
+        }
+    }
+
+    fn queued_scores(
+        container: &TransactionContainer<ManualClock>,
+    ) -> Vec<(u64, f64)> {
+        let mut scores: Vec<_> = container
+            .peek_top_k(container.len())
+            .into_iter()
+            .map(|(score, tx)| (tx.sig_key, *score))
+            .collect();
+        // Ties have no defined order
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        scores
+    }
+
+    #[test]
+    fn test_queue_keeps_best_duplicate() {
+        let mut container = container();
+        let mut stats = Stats::default();
+
+        container.queue(scored(1, 0, 1.0), &mut stats);
+        container.queue(scored(1, 0, 2.0), &mut stats);
+        container.queue(scored(1, 0, 0.5), &mut stats);
+        assert_eq!(stats.duplicate_packets, 2);
+        assert_eq!(queued_scores(&container), [(1, 2.0)]);
+
+        // The replaced copy left no stale index entries
+        assert!(container.purge_signature(1, &mut stats));
+        assert!(!container.purge_signature(1, &mut stats));
+        assert!(container.is_empty());
+    }
+
+    #[test]
+    fn test_purge_signature() {
+        let mut container = container();
+        let mut stats = Stats::default();
+        container.queue(scored(1, 0, 1.0), &mut stats);
+        container.queue(scored(2, 0, 2.0), &mut stats);
+
+        assert!(container.purge_signature(1, &mut stats));
+        assert!(!container.purge_signature(1, &mut stats));
+        assert!(!container.purge_signature(3, &mut stats));
+        assert_eq!(stats.recently_processed_queued, 1);
+        assert_eq!(queued_scores(&container), [(2, 2.0)]);
+    }
+
+    #[test]
+    fn test_rescore() {
+        let mut container = container();
+        let mut stats = Stats::default();
+        for sig_key in 0..3 {
+            let mut tx = scored(sig_key, sig_key as u32, 2.0);
+            tx.value_rate = F64::from(2.0);
+            container.queue(tx, &mut stats);
+        }
+
+        // Nothing is stale until the model changes
+        let mut model = PenalizeIp { ip: 1, version: 0 };
+        assert_eq!(container.rescore(&model, &(), 10, &mut stats), 0);
+
+        // Scores are the model score times the value rate
+        model.version = 1;
+        assert_eq!(container.rescore(&model, &(), 2, &mut stats), 2);
+        assert_eq!(container.rescore(&model, &(), 10, &mut stats), 1);
+        assert_eq!(container.rescore(&model, &(), 10, &mut stats), 0);
+        assert_eq!(stats.rescored_queued, 3);
+        assert_eq!(
+            queued_scores(&container),
+            [(0, 2.0), (2, 2.0), (1, 1.0)]
+        );
+        assert!(container
+            .peek_top_k(3)
+            .iter()
+            .all(|(_, tx)| tx.model_version == 1));
+    }
+
+    #[test]
+    fn test_purge_signature_after_eviction() {
+        let mut container = container();
+        let mut stats = Stats::default();
+
+        // The heap holds one less than its capacity
+        let held = QUEUE_CAPACITY as u64 - 1;
+        for sig_key in 0..held {
+            container.queue(scored(sig_key, 1, 2.0), &mut stats);
+        }
+
+        // A lower scoring transaction is evicted on arrival
+        container.queue(scored(held, 0, 1.0), &mut stats);
+        assert_eq!(stats.leaked_priority, 1);
+        assert!(!container.purge_signature(held, &mut stats));
+
+        // A higher scoring one evicts another transaction into a reused
+        // slot
+        container.queue(scored(held + 1, 0, 3.0), &mut stats);
+        assert_eq!(stats.leaked_priority, 2);
+        let evicted = (0..held)
+            .filter(|&sig_key| {
+                !container.purge_signature(sig_key, &mut stats)
+            })
+            .count();
+        assert_eq!(evicted, 1);
+        assert!(container.purge_signature(held + 1, &mut stats));
+        assert!(container.is_empty());
+    }
+
+    #[test]
+    fn test_backlog_follows_queue() {
+        let region = ShmRegion::new("banking_backlog");
+        let open = || {
+            Backlog::<QUEUE_CAPACITY>::open_or_create(
+                region.name(),
+                PageSize::Standard,
+            )
+            .unwrap()
+        };
+        let clock = ManualClock::new();
+        let mut container =
+            TransactionContainer::with_clock(None, 10, clock.clone());
+        let mut stats = Stats::default();
+
+        // Queued before and after persisting
+        container.queue(scored(1, 0, 1.0), &mut stats);
+        let mut backlog = open();
+        assert!(backlog.take().is_empty());
+        container.persist(backlog);
+        for sig_key in 2..=4 {
+            container.queue(scored(sig_key, 0, 2.0), &mut stats);
+        }
+
+        // Replaced, purged and transmitted ones are dropped
+        container.queue(scored(2, 0, 3.0), &mut stats);
+        assert!(container.purge_signature(3, &mut stats));
+        clock.advance(Duration::from_millis(100));
+        let sent: Vec<u64> = container
+            .maybe_retrieve()
+            .unwrap()
+            .map(|tx| tx.sig_key)
+            .collect();
+        assert_eq!(sent, [2]);
+
+        let mut persisted: Vec<u8> = open()
+            .take()
+            .iter()
+            .map(|(_, packet)| packet_id(packet))
+            .collect();
+        persisted.sort_unstable();
+        assert_eq!(persisted, [1, 4]);
+    }
+
+    #[test]
+    fn test_send_interval_follows_clock() {
+        let clock = ManualClock::new();
+        let mut container = TransactionContainer::with_clock(
+            None,
+            1_000,
+            clock.clone(),
+        );
+
+        assert!(container.maybe_retrieve().is_none());
+
+        clock.advance(Duration::from_millis(99));
+        assert!(container.maybe_retrieve().is_none());
+
+        clock.advance(Duration::from_millis(1));
+        assert!(container.maybe_retrieve().is_some());
+        assert!(container.maybe_retrieve().is_none());
+    }
+}
@@ -1,3 +1,5 @@
+use solana_qos_common::channel::JoinError;
+
 pub type PacketProcessorResult<T = (), E = PacketProcessorError> =
     Result<T, E>;
 
@@ -10,3 +12,17 @@ pub enum PacketProcessorError {
     DuplicatePacket,
     RecentlyProcessed,
 }
+
+#[derive(Debug)]
+pub enum SharedCacheError {
+    /// Failed to open or create the shared memory region
+    Shmem(String),
+
+    /// Another live process is attached to the region. If it is not
+    /// a qos instance (e.g. the pid was reused), remove the region
+    /// (under `/dev/shm`, or the huge page mount) to start cold.
+    InUse { name: String, owner: u32 },
+}
+
+/// Failed to create or join a channel of the topology
+pub type IpcError = JoinError;
@@ -0,0 +1,180 @@
+use std::net::Ipv4Addr;
+
+use agave_transaction_view::transaction_view::TransactionView;
+use solana_qos_internal_common::transaction_features::{
+    ipv4_key, PacketSource, TransactionFeatures,
+};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::CaveyTransactionFee;
+
+/// Extracts the [TransactionFeatures] used for scoring from a
+/// sanitized view. This is done once per packet.
+pub fn transaction_features(
+    view: &TransactionView<true, &[u8]>,
+    ip: &Ipv4Addr,
+    fee_payer: &Pubkey,
+    fee: &CaveyTransactionFee,
+    packet_size: usize,
+    source: PacketSource,
+) -> TransactionFeatures {
+    let mut features = TransactionFeatures::new(
+        ipv4_key(ip),
+        fee_payer.to_bytes(),
+        source,
+    );
+
+    features.num_instructions = view.num_instructions();
+    features.num_signatures = view.num_signatures();
+    features.requested_cus = fee.requested_cus;
+    features.cu_price = fee.cu_price;
+    features.packet_size = packet_size as u16;
+
+    // Invoked programs
+    let static_account_keys = view.static_account_keys();
+    for ix in view.instructions_iter() {
+        if let Some(program_id) =
+            static_account_keys.get(ix.program_id_index as usize)
+        {
+            features.push_program_id(&program_id.to_bytes());
+        }
+    }
+
+    // Writable static accounts. The header splits the static keys into
+    //   [writable signed | readonly signed |
+    //    writable unsigned | readonly unsigned]
+    let num_static = static_account_keys.len();
+    let num_signed = view.num_required_signatures() as usize;
+    let writable_signed = num_signed
+        .saturating_sub(view.num_readonly_signed_accounts() as usize);
+    let writable_unsigned_end = num_static
+        .saturating_sub(view.num_readonly_unsigned_accounts() as usize);
+    let mut total_writable = 0_u32;
+    for (i, account) in static_account_keys.iter().enumerate() {
+        let is_writable = i < writable_signed
+            || (i >= num_signed && i < writable_unsigned_end);
+        if is_writable {
+            features.push_writable_account(&account.to_bytes());
+            total_writable += 1;
+        }
+    }
+    total_writable += view.total_writable_lookup_accounts();
+    features.total_writable_accounts =
+        total_writable.min(u16::MAX as u32) as u16;
+
+    features
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{
+        compute_budget::{self, ComputeBudgetInstruction},
+        hash::Hash,
+        message::Message,
+        signature::Keypair,
+        signer::Signer,
+        system_instruction, system_program,
+        transaction::Transaction,
+    };
+
+    use solana_qos_internal_common::transaction_features::account_key;
+
+    use super::*;
+    use crate::total_fee;
+
+    fn key(account: &Pubkey) -> u64 {
+        account_key(&account.to_bytes())
+    }
+
+    fn features_of(tx: &Transaction) -> TransactionFeatures {
+        let bytes = bincode::serialize(tx).unwrap();
+        let view = TransactionView::try_new_unsanitized(&bytes[..])
+            .unwrap()
+            .sanitize()
+            .unwrap();
+        let fee_payer = view.static_account_keys()[0];
+        let fee = total_fee(&view);
+        transaction_features(
+            &view,
+            &Ipv4Addr::new(1, 2, 3, 4),
+            &fee_payer,
+            &fee,
+            bytes.len(),
+            PacketSource::Fwd,
+        )
+    }
+
+    #[test]
+    fn test_transaction_features() {
+        let payer = Keypair::new();
+        let recipient = Pubkey::new_unique();
+        let message = Message::new(
+            &[
+                ComputeBudgetInstruction::set_compute_unit_limit(
+                    50_000,
+                ),
+                ComputeBudgetInstruction::set_compute_unit_price(10),
+                system_instruction::transfer(
+                    &payer.pubkey(),
+                    &recipient,
+                    1,
+                ),
+                system_instruction::transfer(
+                    &payer.pubkey(),
+                    &recipient,
+                    2,
+                ),
+            ],
+            Some(&payer.pubkey()),
+        );
+        let tx = Transaction::new(&[&payer], message, Hash::default());
+        let features = features_of(&tx);
+
+        assert_eq!(features.ip, ipv4_key(&Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(features.signer, payer.pubkey().to_bytes());
+        assert_eq!(features.source, PacketSource::Fwd);
+        assert_eq!(features.num_instructions, 4);
+        assert_eq!(features.num_signatures, 1);
+        assert_eq!(features.requested_cus, 50_000);
+        assert_eq!(features.cu_price, 10);
+        assert_eq!(
+            features.packet_size as usize,
+            bincode::serialized_size(&tx).unwrap() as usize
+        );
+
+        // Programs are recorded once each, in order of invocation
+        assert_eq!(
+            features.program_keys(),
+            &[key(&compute_budget::ID), key(&system_program::ID)]
+        );
+
+        // The payer and recipient are writable, programs are not
+        assert_eq!(
+            features.writable_account_keys(),
+            &[key(&payer.pubkey()), key(&recipient)]
+        );
+        assert_eq!(features.total_writable_accounts, 2);
+    }
+
+    #[test]
+    fn test_default_compute_budget() {
+        let payer = Keypair::new();
+        let message = Message::new(
+            &[system_instruction::transfer(
+                &payer.pubkey(),
+                &Pubkey::new_unique(),
+                1,
+            )],
+            Some(&payer.pubkey()),
+        );
+        let tx = Transaction::new(&[&payer], message, Hash::default());
+        let features = features_of(&tx);
+
+        assert_eq!(features.requested_cus, 200_000);
+        assert_eq!(features.cu_price, 0);
+        assert_eq!(
+            features.program_keys(),
+            &[key(&system_program::ID)]
+        );
+    }
+}
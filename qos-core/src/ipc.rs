@@ -0,0 +1,166 @@
+//! Creates and joins the channels described by a [Topology]. Every
+//! join stamps or verifies the
+//! [ChannelHeader](solana_qos_common::channel_header::ChannelHeader)
+//! of the channel.
+
+use que::{
+    headless_spmc::{consumer::Consumer, producer::Producer},
+    page_size::PageSize,
+};
+use solana_qos_common::{
+    channel::{join_consumer, join_producer},
+    channel_header::ChannelType,
+    ipc_parameters::*,
+    packet_bytes::PacketBytes,
+    recent_signature::RecentSignature,
+    remaining_meta::QoSRemainingMeta,
+    topology::{ChannelRole, InputSource, Topology},
+};
+use solana_qos_internal_common::transaction_features::PacketSource;
+
+use crate::error::IpcError;
+
+/// All inputs multiplexed into one consumer
+pub type IngressConsumer =
+    mpsc::Consumer<PacketBytes, IPC_TPU_TO_QOS_CAP>;
+
+/// The channels qos reads and writes
+pub struct QoSChannels {
+    pub ingress: IngressConsumer,
+
+    /// Source of each input, indexed by ingress lane id
+    pub ingress_sources: Vec<PacketSource>,
+
+    pub sigverify_feedback: Consumer<PacketBytes, IPC_SIG_TO_QOS_CAP>,
+
+    pub scheduler_feedback:
+        Consumer<QoSRemainingMeta<()>, IPC_SCH_TO_QOS_CAP>,
+
+    pub status_cache:
+        Option<Consumer<RecentSignature, IPC_STATUS_CACHE_CAP>>,
+
+    pub sigverify_output: Producer<PacketBytes, IPC_QOS_TO_SIG_CAP>,
+}
+
+/// Joins every channel in the topology. Inputs and feedback channels
+/// must already exist. The output channel is created if needed.
+pub fn join(
+    topology: &Topology,
+    page_size: PageSize,
+) -> Result<QoSChannels, IpcError> {
+    let mut ingress = IngressConsumer::new();
+    let mut ingress_sources = vec![];
+    for input in &topology.inputs {
+        let consumer = join_consumer(
+            &input.name,
+            ChannelType::Packets,
+            page_size,
+        )?;
+        let lane = ingress.add_lane(consumer, input.weight);
+        debug_assert_eq!(lane, ingress_sources.len());
+        ingress_sources.push(packet_source(input.source));
+    }
+
+    let feedback = &topology.feedback;
+    let sigverify_feedback = join_consumer(
+        &feedback.sigverify.name,
+        ChannelType::Packets,
+        page_size,
+    )?;
+    let scheduler_feedback = join_consumer(
+        &feedback.scheduler.name,
+        ChannelType::RemainingMeta,
+        page_size,
+    )?;
+    let status_cache = feedback
+        .status_cache
+        .as_ref()
+        .map(|channel| {
+            join_consumer(
+                &channel.name,
+                ChannelType::Signatures,
+                page_size,
+            )
+        })
+        .transpose()?;
+
+    let output = &topology.output.sigverify;
+    let sigverify_output = join_producer(
+        &output.name,
+        ChannelType::Packets,
+        page_size,
+        false,
+    )?;
+
+    Ok(QoSChannels {
+        ingress,
+        ingress_sources,
+        sigverify_feedback,
+        scheduler_feedback,
+        status_cache,
+        sigverify_output,
+    })
+}
+
+/// Creates a channel, or joins it if it already exists, and stamps its
+/// header. Run before qos and the client join, as this replaces a
+/// stale or half written header instead of rejecting it.
+pub fn create(
+    role: ChannelRole,
+    name: &str,
+    page_size: PageSize,
+) -> Result<(), IpcError> {
+    match role {
+        ChannelRole::Input => {
+            join_producer::<PacketBytes, IPC_TPU_TO_QOS_CAP>(
+                name,
+                ChannelType::Packets,
+                page_size,
+                true,
+            )?;
+        }
+        ChannelRole::SigverifyFeedback => {
+            join_producer::<PacketBytes, IPC_SIG_TO_QOS_CAP>(
+                name,
+                ChannelType::Packets,
+                page_size,
+                true,
+            )?;
+        }
+        ChannelRole::SchedulerFeedback => {
+            join_producer::<QoSRemainingMeta<()>, IPC_SCH_TO_QOS_CAP>(
+                name,
+                ChannelType::RemainingMeta,
+                page_size,
+                true,
+            )?;
+        }
+        ChannelRole::StatusCache => {
+            join_producer::<RecentSignature, IPC_STATUS_CACHE_CAP>(
+                name,
+                ChannelType::Signatures,
+                page_size,
+                true,
+            )?;
+        }
+        ChannelRole::SigverifyOutput => {
+            join_producer::<PacketBytes, IPC_QOS_TO_SIG_CAP>(
+                name,
+                ChannelType::Packets,
+                page_size,
+                true,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn packet_source(source: InputSource) -> PacketSource {
+    match source {
+        InputSource::Tpu => PacketSource::Tpu,
+        InputSource::Fwd => PacketSource::Fwd,
+        InputSource::Re1 => PacketSource::Re1,
+        InputSource::Re2 => PacketSource::Re2,
+    }
+}
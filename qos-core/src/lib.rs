@@ -3,18 +3,27 @@ use std::net::IpAddr;
 use agave_transaction_view::transaction_view::TransactionView;
 use error::{PacketProcessorError, PacketProcessorResult};
 use que::page_size::PageSize;
+use recent_signatures::RecentSignatures;
+use shadow::Shadow;
 use solana_sdk::{
     packet::{Packet, PACKET_DATA_SIZE},
     pubkey::Pubkey,
 };
 
+pub mod backlog;
 pub mod banking;
 pub mod error;
+pub mod features;
+pub mod ipc;
+pub mod recent_signatures;
+pub mod shadow;
+pub mod shared_lru;
 
 pub use {
     qos_lru::LRUCache,
     qos_model::{
-        interface::QoSModel, models::ip_signer::IpSignerModel,
+        cost::ExecutionCostModel, interface::QoSModel,
+        models::ip_signer::IpSignerModel,
     },
     solana_qos_common::{
         remaining_meta::QoSRemainingMeta,
@@ -25,6 +34,10 @@ pub use {
         partial_meta::QoSPartialMeta,
         scored_transaction::ScoredTransaction,
         signature_bytes::{sig_bytes, u64_key},
+        transaction_features::{
+            ipv4_key, PacketSource, TransactionFeatures,
+        },
+        transaction_meta::{QoSTransactionMeta, F64},
         xxhash::packet_hash,
     },
     timer::Timer,
@@ -37,15 +50,20 @@ pub fn try_process_packet<
     const SIG_CACHE_SIZE: usize,
 >(
     packet: Packet,
-    recent_signatures: Option<&LRUCache<u64, (), SIG_CACHE_SIZE>>,
+    source: PacketSource,
+    recent_signatures: Option<&RecentSignatures<SIG_CACHE_SIZE>>,
     qos_model: &mut IpSignerModel<SIGNERS, IPS>,
+    cost_model: &ExecutionCostModel,
+    mut shadow: Option<&mut Shadow>,
     qos_tx_partial_metas: &mut LRUCache<
         xxHash,
         QoSPartialMeta,
         CACHE_SIZE,
+        u64,
     >,
     stats: &mut Stats,
     xxhasher: &xxHasher,
+    timestamp_ms: u64,
 ) -> PacketProcessorResult<ScoredTransaction> {
     // Increment total packets
     stats.total_packets += 1;
@@ -79,7 +97,11 @@ pub fn try_process_packet<
         }
         Some(Err(_)) => {
             // Source is sending bad data. Reduce score
-            qos_model.ip_feedback(ipv4.to_bits());
+            let ip = ipv4_key(&ipv4);
+            qos_model.ip_feedback(ip);
+            if let Some(shadow) = shadow {
+                shadow.ip_feedback(ip);
+            }
             stats.failed_view += 1;
             return Err(PacketProcessorError::FailedTransactionView);
         }
@@ -92,8 +114,7 @@ pub fn try_process_packet<
     // Check to see if this tx has been recently processed
     let signature = &transaction.signatures()[0];
     let sig_key = u64_key(sig_bytes(signature));
-    if recent_signatures.is_some_and(|rs| rs.contains(sig_key)) {
-        stats.recently_processed += 1;
+    if recent_signatures.is_some_and(|rs| rs.check(sig_key, stats)) {
         return Err(PacketProcessorError::RecentlyProcessed);
     }
 
@@ -103,20 +124,37 @@ pub fn try_process_packet<
         return Err(PacketProcessorError::InvalidMetadata);
     };
     let tx_fee = total_fee(&transaction);
-    let partial_meta = QoSPartialMeta::new(
+    let features = features::transaction_features(
+        &transaction,
         &ipv4,
         fee_payer,
-        tx_fee.total_fee,
-        tx_fee.requested_cus,
+        &tx_fee,
+        meta.size,
+        source,
     );
-    let score =
-        qos_model.forward(partial_meta.ip, &partial_meta.signer, &())
-            * (partial_meta.total_fee as f64
-                / partial_meta.cus.max(1) as f64);
+    let partial_meta = QoSPartialMeta::new(&features, tx_fee.total_fee);
+    qos_model.observe(&features);
+    if let Some(shadow) = shadow.as_deref_mut() {
+        shadow.observe(&features);
+    }
 
-    // Store partial meta
+    // Value per nanosecond of expected execution
+    let value_rate = partial_meta.total_fee as f64
+        / cost_model.estimate_nanos(
+            partial_meta.program_keys(),
+            partial_meta.num_instructions,
+            partial_meta.cus,
+        );
+    let score = qos_model.forward(&features, &()) * value_rate;
+
+    // Store partial meta. A duplicate keeps its meta, so that a resent
+    // packet neither clears its transmitted flag nor delays its expiry.
     let packet_key = packet_hash(xxhasher, &packet);
-    match qos_tx_partial_metas.put(packet_key, partial_meta) {
+    match qos_tx_partial_metas.put_new_with_timestamp(
+        packet_key,
+        partial_meta,
+        timestamp_ms,
+    ) {
         (Some((_packet_hash, partial_meta)), _) => {
             log::debug!(
                 "partial meta LRU is full and packet from {:?} was dropped",
@@ -136,14 +174,57 @@ pub fn try_process_packet<
         }
     }
 
+    // Score in shadow. This never affects ordering
+    if let Some(shadow) = shadow {
+        shadow.score_packet(packet_key, &features, score, value_rate);
+    }
+
     Ok(ScoredTransaction {
         score,
         sig_key,
         packet,
-        ipv4: u32::from_le_bytes(ipv4.octets()),
+        ipv4: ipv4_key(&ipv4),
+        packet_key,
+        features,
+        value_rate: F64::from(value_rate),
+        model_version: qos_model.version(),
     })
 }
 
+/// Expires partial metas older than `cutoff_ms` whose scheduler
+/// feedback never arrived. Transmitted ones become worthless complete
+/// metas, i.e. negative feedback for their sender.
+pub fn expire_partial_metas<const CACHE_SIZE: usize>(
+    qos_tx_partial_metas: &mut LRUCache<
+        xxHash,
+        QoSPartialMeta,
+        CACHE_SIZE,
+        u64,
+    >,
+    qos_tx_complete_metas: &mut Vec<QoSTransactionMeta<()>>,
+    mut shadow: Option<&mut Shadow>,
+    stats: &mut Stats,
+    cutoff_ms: u64,
+) {
+    for (packet_hash, partial_meta) in
+        qos_tx_partial_metas.expire_older_than(cutoff_ms)
+    {
+        if partial_meta.transmitted {
+            // Transmitted but never scheduled. Treat as worthless.
+            let complete_entry = partial_meta.expire();
+            if let Some(shadow) = shadow.as_deref_mut() {
+                shadow
+                    .record_outcome(packet_hash, complete_entry.value);
+            }
+            qos_tx_complete_metas.push(complete_entry);
+            stats.expired_transmitted += 1;
+        } else {
+            // Dropped before sigverify. Not the sender's fault.
+            stats.expired_untransmitted += 1;
+        }
+    }
+}
+
 #[inline(always)]
 fn fee_payer<'a>(
     view: &'a TransactionView<true, &'a [u8]>,
@@ -236,3 +317,89 @@ pub fn get_page_size(use_huge_pages: bool) -> PageSize {
 pub fn get_page_size() -> PageSize {
     PageSize::Standard
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use solana_sdk::{
+        hash::Hash, signature::Keypair, signer::Signer,
+        system_transaction,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_resent_packet_keeps_transmitted_partial_meta() {
+        let mut qos_model = IpSignerModel::<16, 16>::new(
+            std::iter::empty(),
+            std::iter::empty(),
+        );
+        let cost_model = ExecutionCostModel::new();
+        let mut partial_metas =
+            LRUCache::<xxHash, QoSPartialMeta, 16, u64>::new_boxed();
+        let mut stats = Stats::new();
+        let xxhasher = xxHasher::initialize_with_seed(0);
+
+        let payer = Keypair::new();
+        let transaction = system_transaction::transfer(
+            &payer,
+            &Pubkey::new_unique(),
+            1,
+            Hash::default(),
+        );
+        let addr = SocketAddr::from((Ipv4Addr::new(1, 2, 3, 4), 8000));
+        let packet =
+            Packet::from_data(Some(&addr), &transaction).unwrap();
+
+        let mut process =
+            |partial_metas: &mut LRUCache<_, _, 16, _>,
+             timestamp_ms| {
+                try_process_packet::<16, 16, 16, 16>(
+                    packet.clone(),
+                    PacketSource::Tpu,
+                    None,
+                    &mut qos_model,
+                    &cost_model,
+                    None,
+                    partial_metas,
+                    &mut stats,
+                    &xxhasher,
+                    timestamp_ms,
+                )
+            };
+
+        let Ok(scored) = process(&mut partial_metas, 100) else {
+            panic!("failed to process packet");
+        };
+        assert!(matches!(
+            process(&mut partial_metas, 200),
+            Err(PacketProcessorError::DuplicatePacket)
+        ));
+
+        // Transmitted as TransactionContainer does, then resent
+        partial_metas
+            .peek_mut(&scored.packet_key)
+            .unwrap()
+            .transmitted = true;
+        assert!(matches!(
+            process(&mut partial_metas, 300),
+            Err(PacketProcessorError::DuplicatePacket)
+        ));
+
+        // It still expires from its first arrival, as negative feedback
+        let mut complete_metas = vec![];
+        expire_partial_metas(
+            &mut partial_metas,
+            &mut complete_metas,
+            None,
+            &mut stats,
+            150,
+        );
+        assert_eq!(complete_metas.len(), 1);
+        assert_eq!(complete_metas[0].value, F64::from(0.0));
+        assert_eq!(stats.expired_transmitted, 1);
+        assert_eq!(stats.expired_untransmitted, 0);
+        assert_eq!(stats.duplicate_packets, 2);
+    }
+}
@@ -0,0 +1,265 @@
+use que::page_size::PageSize;
+use solana_qos_common::{
+    recent_signature::RecentSignature, shared_stats::Stats,
+};
+use solana_qos_internal_common::signature_bytes::u64_key;
+use solana_sdk::clock::MAX_PROCESSING_AGE;
+
+use crate::{error::SharedCacheError, shared_lru::SharedLRUCache};
+
+/// Signatures older than this many slots reference an expired
+/// blockhash and can not be processed again
+pub const MAX_AGE_SLOTS: u64 = MAX_PROCESSING_AGE as u64;
+
+/// Upper bounds (exclusive) of all but the last age bucket of
+/// [Stats::recently_processed_by_age], in slots
+const AGE_BUCKETS: [u64; 2] = [4, 32];
+
+/// Layout version of the cached values, which are slots
+const VALUE_VERSION: u32 = 1;
+
+/// Signatures the bank recently processed, keyed by [u64_key] and
+/// tagged with their slot. Entries are evicted once they fall out of
+/// the blockhash window rather than when the cache is full.
+///
+/// Eviction assumes signatures arrive in roughly nondecreasing slot
+/// order, as they do from the status cache. A straggler from an older
+/// slot is only evicted once the newer entries ahead of it are.
+pub struct RecentSignatures<const N: usize> {
+    cache: SharedLRUCache<u64, u64, N, u64>,
+
+    /// Highest slot seen
+    slot: u64,
+}
+
+impl<const N: usize> RecentSignatures<N> {
+    pub fn open_or_create(
+        name: &str,
+        page_size: PageSize,
+    ) -> Result<RecentSignatures<N>, SharedCacheError> {
+        let cache = SharedLRUCache::open_or_create(
+            name,
+            page_size,
+            VALUE_VERSION,
+        )?;
+        let slot = cache
+            .iter()
+            .map(|(_key, &slot)| slot)
+            .max()
+            .unwrap_or(0);
+
+        Ok(RecentSignatures { cache, slot })
+    }
+
+    /// Whether entries from a previous run were restored
+    pub fn restored(&self) -> bool {
+        self.cache.restored()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Highest slot seen
+    pub fn slot(&self) -> u64 {
+        self.slot
+    }
+
+    /// Records a processed signature and evicts those that left the
+    /// blockhash window. Returns the signature's key, or None if it
+    /// was already too old to record.
+    pub fn insert(
+        &mut self,
+        recent: &RecentSignature,
+        stats: &mut Stats,
+    ) -> Option<u64> {
+        stats.recent_signatures_received += 1;
+        self.slot = self.slot.max(recent.slot);
+
+        let cutoff = self.slot.saturating_sub(MAX_AGE_SLOTS);
+        stats.recent_signatures_expired += self
+            .cache
+            .expire_older_than(cutoff)
+            .count();
+
+        if recent.slot < cutoff {
+            stats.recent_signatures_expired += 1;
+            return None;
+        }
+
+        let key = u64_key(&recent.signature);
+        self.cache
+            .put_with_timestamp(key, recent.slot, recent.slot);
+        Some(key)
+    }
+
+    /// Slots since the signature was processed, if it was recently
+    pub fn age(&self, sig_key: u64) -> Option<u64> {
+        self.cache
+            .peek(&sig_key)
+            .map(|&slot| self.slot.saturating_sub(slot))
+    }
+
+    /// Checks for a recently processed signature, counting it by age
+    #[inline(always)]
+    pub fn check(&self, sig_key: u64, stats: &mut Stats) -> bool {
+        let Some(age) = self.age(sig_key) else {
+            return false;
+        };
+
+        let bucket = AGE_BUCKETS
+            .iter()
+            .position(|&bound| age < bound)
+            .unwrap_or(AGE_BUCKETS.len());
+        stats.recently_processed += 1;
+        stats.recently_processed_by_age[bucket] += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_qos_internal_common::test_utils::ShmRegion;
+
+    use super::*;
+
+    type Signatures = RecentSignatures<64>;
+
+    fn region(test: &str) -> ShmRegion {
+        ShmRegion::new(&format!("recent_signatures_{test}"))
+    }
+
+    fn open(region: &ShmRegion) -> Signatures {
+        Signatures::open_or_create(region.name(), PageSize::Standard)
+            .unwrap()
+    }
+
+    /// A signature whose key is `key`
+    fn recent(slot: u64, key: u8) -> RecentSignature {
+        let mut signature = [0; 64];
+        signature[0] = key;
+        RecentSignature::new(slot, signature)
+    }
+
+    #[test]
+    fn test_insert_cutoff_and_expiry() {
+        let region = region("cutoff");
+        let mut signatures = open(&region);
+        let mut stats = Stats::new();
+
+        assert_eq!(
+            signatures.insert(&recent(100, 1), &mut stats),
+            Some(1)
+        );
+        assert_eq!(
+            signatures.insert(&recent(200, 2), &mut stats),
+            Some(2)
+        );
+        assert_eq!(signatures.slot(), 200);
+        assert_eq!(signatures.age(1), Some(100));
+
+        // Slot 100 leaves the window
+        let slot = 100 + MAX_AGE_SLOTS + 1;
+        assert_eq!(
+            signatures.insert(&recent(slot, 3), &mut stats),
+            Some(3)
+        );
+        assert_eq!(signatures.age(1), None);
+        assert_eq!(signatures.len(), 2);
+        assert_eq!(stats.recent_signatures_expired, 1);
+
+        // Too old to record, while the cutoff itself is recorded
+        let cutoff = slot - MAX_AGE_SLOTS;
+        assert_eq!(
+            signatures.insert(&recent(cutoff - 1, 4), &mut stats),
+            None
+        );
+        assert_eq!(
+            signatures.insert(&recent(cutoff, 5), &mut stats),
+            Some(5)
+        );
+        assert_eq!(signatures.age(4), None);
+        assert_eq!(signatures.age(5), Some(MAX_AGE_SLOTS));
+        assert_eq!(stats.recent_signatures_expired, 2);
+        assert_eq!(stats.recent_signatures_received, 5);
+    }
+
+    #[test]
+    fn test_straggler_is_evicted_behind_newer_entries() {
+        let region = region("straggler");
+        let mut signatures = open(&region);
+        let mut stats = Stats::new();
+
+        signatures.insert(&recent(200, 1), &mut stats);
+        signatures.insert(&recent(100, 2), &mut stats);
+        signatures.insert(&recent(210, 3), &mut stats);
+
+        // Slot 100 is out of the window, but 200 is ahead of it
+        signatures
+            .insert(&recent(100 + MAX_AGE_SLOTS + 1, 4), &mut stats);
+        assert_eq!(signatures.age(2), Some(MAX_AGE_SLOTS + 1));
+        assert_eq!(stats.recent_signatures_expired, 0);
+
+        // Both go once 200 leaves the window
+        signatures
+            .insert(&recent(200 + MAX_AGE_SLOTS + 1, 5), &mut stats);
+        assert_eq!(signatures.age(1), None);
+        assert_eq!(signatures.age(2), None);
+        assert!(signatures.age(3).is_some());
+        assert_eq!(stats.recent_signatures_expired, 2);
+    }
+
+    #[test]
+    fn test_check_counts_by_age() {
+        let region = region("check");
+        let mut signatures = open(&region);
+        let mut stats = Stats::new();
+
+        // Ages 32, 31, 4, 3 and 0 at slot 100
+        for (key, slot) in
+            [(1, 68), (2, 69), (3, 96), (4, 97), (5, 100)]
+        {
+            signatures.insert(&recent(slot, key), &mut stats);
+        }
+
+        for key in 1..=5 {
+            assert!(signatures.check(key as u64, &mut stats));
+        }
+        assert!(!signatures.check(6, &mut stats));
+        assert_eq!(stats.recently_processed, 5);
+        assert_eq!(stats.recently_processed_by_age, [2, 2, 1]);
+    }
+
+    #[test]
+    fn test_restore() {
+        let region = region("restore");
+        let mut stats = Stats::new();
+        {
+            let mut signatures = open(&region);
+            assert!(!signatures.restored());
+            signatures.insert(&recent(100, 1), &mut stats);
+            signatures.insert(&recent(120, 2), &mut stats);
+
+            // Only one instance may be attached
+            assert!(matches!(
+                Signatures::open_or_create(region.name(), PageSize::Standard),
+                Err(SharedCacheError::InUse { owner, .. })
+                    if owner == std::process::id()
+            ));
+        }
+
+        let signatures = open(&region);
+        assert!(signatures.restored());
+        assert_eq!(signatures.len(), 2);
+        assert_eq!(signatures.slot(), 120);
+        assert_eq!(signatures.age(1), Some(20));
+    }
+}
@@ -0,0 +1,513 @@
+//! Shadow evaluation of a candidate model against the primary model.
+//!
+//! The candidate receives the same observations, feedback and updates
+//! as the primary model and scores every packet, but never decides
+//! ordering. Periodic reports compare the two rankings and the value
+//! each would have captured. Windows are evaluated on a side thread, so
+//! sorting and ranking them never stalls the main loop.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::mpsc::{self, Receiver, SyncSender},
+};
+
+use qos_model::{
+    interface::QoSModel, models::ip_signer::IpSignerModel,
+};
+use solana_qos_common::xxhash::xxHash;
+use solana_qos_internal_common::{
+    transaction_features::TransactionFeatures,
+    transaction_meta::{QoSTransactionMeta, F64},
+};
+
+/// Only one in this many packets is sampled for evaluation. Packet
+/// hashes are uniform so this is an unbiased sample.
+// TODO: hard coded parameter
+const SAMPLE_ONE_IN: u64 = 16;
+
+/// Max number of sampled packets in each evaluation window
+// TODO: hard coded parameter
+const WINDOW: usize = 65_536;
+
+/// Fraction of each window treated as the "batch" the scheduler would
+/// have picked, for overlap and value captured
+// TODO: hard coded parameter
+const TOP_FRACTION: f64 = 0.1;
+
+/// A model run in shadow. Object safe so that candidates of different
+/// types can be swapped in without touching the sidecar loop.
+pub trait ShadowModel {
+    /// Model score, prior to multiplying by the value rate
+    fn score(&self, features: &TransactionFeatures) -> F64;
+
+    fn observe(&mut self, features: &TransactionFeatures);
+
+    fn ip_feedback(&mut self, ip: u32);
+
+    fn update_model(&mut self, transactions: &[QoSTransactionMeta<()>]);
+}
+
+/// An [IpSignerModel] candidate, typically with different table sizes
+/// than the primary model
+pub struct IpSignerCandidate<const SIGNERS: usize, const IPS: usize> {
+    pub model: IpSignerModel<SIGNERS, IPS>,
+    pub max_signers: usize,
+    pub max_ips: usize,
+}
+
+impl<const SIGNERS: usize, const IPS: usize> ShadowModel
+    for IpSignerCandidate<SIGNERS, IPS>
+{
+    #[inline(always)]
+    fn score(&self, features: &TransactionFeatures) -> F64 {
+        self.model.forward(features, &())
+    }
+
+    #[inline(always)]
+    fn observe(&mut self, features: &TransactionFeatures) {
+        self.model.observe(features);
+    }
+
+    fn ip_feedback(&mut self, ip: u32) {
+        self.model.ip_feedback(ip);
+    }
+
+    fn update_model(
+        &mut self,
+        transactions: &[QoSTransactionMeta<()>],
+    ) {
+        self.model.update_model(
+            transactions,
+            self.max_signers,
+            self.max_ips,
+        );
+    }
+}
+
+/// Summary of one evaluation window
+#[derive(Debug, Clone, Copy)]
+pub struct ShadowReport {
+    /// Number of sampled packets in the window
+    pub samples: usize,
+
+    /// Number of those with a realized value from the scheduler
+    pub completed: usize,
+
+    /// Spearman rank correlation of primary and candidate scores
+    pub rank_correlation: f64,
+
+    /// Fraction of the primary's top batch also in the candidate's
+    pub batch_overlap: f64,
+
+    /// Realized value of each model's top batch, as a fraction of the
+    /// best achievable. Only completed packets are considered.
+    pub primary_value_captured: f64,
+    pub candidate_value_captured: f64,
+}
+
+struct Sample {
+    primary: f64,
+    candidate: f64,
+    realized: Option<f64>,
+}
+
+pub struct Shadow {
+    candidate: Box<dyn ShadowModel>,
+    samples: Vec<Sample>,
+    index: HashMap<xxHash, usize>,
+
+    /// Full windows go to the evaluation thread, which hands them back
+    /// empty. Only one is in flight at a time.
+    windows: SyncSender<Vec<Sample>>,
+    spare: Receiver<Vec<Sample>>,
+}
+
+impl Shadow {
+    /// Spawns the evaluation thread, which calls `on_report` with the
+    /// report of every published window
+    pub fn new(
+        candidate: Box<dyn ShadowModel>,
+        mut on_report: impl FnMut(ShadowReport) + Send + 'static,
+    ) -> io::Result<Shadow> {
+        let (windows, published) = mpsc::sync_channel(1);
+        let (spare_sender, spare) = mpsc::sync_channel(1);
+        spare_sender
+            .send(Vec::with_capacity(WINDOW))
+            .expect("receiver is alive");
+
+        std::thread::Builder::new()
+            .name("qos-shadow".to_string())
+            .spawn(move || {
+                // Ends once the shadow is dropped
+                for mut window in published {
+                    on_report(evaluate(&window));
+                    window.clear();
+                    if spare_sender.send(window).is_err() {
+                        break;
+                    }
+                }
+            })?;
+
+        Ok(Shadow {
+            candidate,
+            samples: Vec::with_capacity(WINDOW),
+            index: HashMap::with_capacity(WINDOW),
+            windows,
+            spare,
+        })
+    }
+
+    #[inline(always)]
+    pub fn observe(&mut self, features: &TransactionFeatures) {
+        self.candidate.observe(features);
+    }
+
+    pub fn ip_feedback(&mut self, ip: u32) {
+        self.candidate.ip_feedback(ip);
+    }
+
+    pub fn update_model(
+        &mut self,
+        transactions: &[QoSTransactionMeta<()>],
+    ) {
+        self.candidate
+            .update_model(transactions);
+    }
+
+    /// Scores a sampled packet with the candidate and records it
+    /// alongside the primary score. Both are scaled by the same value
+    /// rate.
+    #[inline(always)]
+    pub fn score_packet(
+        &mut self,
+        packet_key: xxHash,
+        features: &TransactionFeatures,
+        primary_score: F64,
+        value_rate: f64,
+    ) {
+        if packet_key % SAMPLE_ONE_IN != 0
+            || self.samples.len() == WINDOW
+        {
+            return;
+        }
+
+        let candidate_score =
+            *self.candidate.score(features) * value_rate;
+        self.index
+            .insert(packet_key, self.samples.len());
+        self.samples.push(Sample {
+            primary: *primary_score,
+            candidate: candidate_score,
+            realized: None,
+        });
+    }
+
+    /// Records the realized value of a packet once the scheduler has
+    /// reported on it. Packets that were not sampled or are from past
+    /// windows are ignored.
+    pub fn record_outcome(&mut self, packet_key: xxHash, value: F64) {
+        if let Some(&i) = self.index.get(&packet_key) {
+            self.samples[i].realized = Some(*value);
+        }
+    }
+
+    /// Hands the current window to the evaluation thread and starts a
+    /// new one. Packets whose outcome has not yet been reported count
+    /// toward ranking metrics but not value captured.
+    ///
+    /// If the last window is still being evaluated, the current one is
+    /// dropped rather than waited on. Returns whether it was published.
+    #[cold]
+    pub fn publish(&mut self) -> bool {
+        self.index.clear();
+        let Ok(spare) = self.spare.try_recv() else {
+            self.samples.clear();
+            return false;
+        };
+
+        let window = std::mem::replace(&mut self.samples, spare);
+        // Never blocks as the thread returned the only other window
+        self.windows.send(window).is_ok()
+    }
+}
+
+fn evaluate(samples: &[Sample]) -> ShadowReport {
+    let primary: Vec<f64> = samples
+        .iter()
+        .map(|s| s.primary)
+        .collect();
+    let candidate: Vec<f64> = samples
+        .iter()
+        .map(|s| s.candidate)
+        .collect();
+    let k = top_k(samples.len());
+    let top_primary = top_indices(&primary, k);
+    let top_candidate = top_indices(&candidate, k);
+    let overlap = top_primary
+        .iter()
+        .filter(|i| top_candidate.binary_search(i).is_ok())
+        .count();
+
+    // Value captured only considers completed packets
+    let completed: Vec<&Sample> = samples
+        .iter()
+        .filter(|s| s.realized.is_some())
+        .collect();
+    let realized: Vec<f64> = completed
+        .iter()
+        .map(|s| s.realized.unwrap_or_default())
+        .collect();
+    let value_of = |scores: Vec<f64>| -> f64 {
+        top_indices(&scores, top_k(completed.len()))
+            .into_iter()
+            .map(|i| realized[i])
+            .sum()
+    };
+    let best = value_of(realized.clone());
+    let captured = |value: f64| {
+        if best > 0.0 {
+            value / best
+        } else {
+            0.0
+        }
+    };
+
+    ShadowReport {
+        samples: samples.len(),
+        completed: completed.len(),
+        rank_correlation: spearman(&primary, &candidate),
+        batch_overlap: overlap as f64 / k.max(1) as f64,
+        primary_value_captured: captured(value_of(
+            completed
+                .iter()
+                .map(|s| s.primary)
+                .collect(),
+        )),
+        candidate_value_captured: captured(value_of(
+            completed
+                .iter()
+                .map(|s| s.candidate)
+                .collect(),
+        )),
+    }
+}
+
+fn top_k(n: usize) -> usize {
+    ((n as f64 * TOP_FRACTION).ceil() as usize).min(n)
+}
+
+/// Indices of the k highest values, sorted by index
+fn top_indices(values: &[f64], k: usize) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..values.len()).collect();
+    indices.sort_unstable_by(|&a, &b| values[b].total_cmp(&values[a]));
+    indices.truncate(k);
+    indices.sort_unstable();
+    indices
+}
+
+/// Spearman rank correlation, with ties assigned their average rank
+fn spearman(x: &[f64], y: &[f64]) -> f64 {
+    pearson(&ranks(x), &ranks(y))
+}
+
+fn ranks(values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_unstable_by(|&a, &b| values[a].total_cmp(&values[b]));
+
+    let mut ranks = vec![0.0; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len()
+            && values[order[end]] == values[order[start]]
+        {
+            end += 1;
+        }
+        let average_rank = (start + end - 1) as f64 / 2.0;
+        for &i in &order[start..end] {
+            ranks[i] = average_rank;
+        }
+        start = end;
+    }
+
+    ranks
+}
+
+fn pearson(x: &[f64], y: &[f64]) -> f64 {
+    let n = x.len() as f64;
+    if n == 0.0 {
+        return 0.0;
+    }
+    let mean_x = x.iter().sum::<f64>() / n;
+    let mean_y = y.iter().sum::<f64>() / n;
+
+    let (mut cov, mut var_x, mut var_y) = (0.0, 0.0, 0.0);
+    for (a, b) in x.iter().zip(y) {
+        cov += (a - mean_x) * (b - mean_y);
+        var_x += (a - mean_x) * (a - mean_x);
+        var_y += (b - mean_y) * (b - mean_y);
+    }
+
+    if var_x == 0.0 || var_y == 0.0 {
+        // Constant scores carry no ranking information
+        return 0.0;
+    }
+    cov / (var_x * var_y).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scores every packet by its ip
+    struct IpCandidate;
+
+    impl ShadowModel for IpCandidate {
+        fn score(&self, features: &TransactionFeatures) -> F64 {
+            F64::from(features.ip as f64)
+        }
+
+        fn observe(&mut self, _features: &TransactionFeatures) {}
+
+        fn ip_feedback(&mut self, _ip: u32) {}
+
+        fn update_model(
+            &mut self,
+            _transactions: &[QoSTransactionMeta<()>],
+        ) {
+        }
+    }
+
+    fn sample(
+        primary: f64,
+        candidate: f64,
+        realized: Option<f64>,
+    ) -> Sample {
+        Sample {
+            primary,
+            candidate,
+            realized,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{actual} != {expected}"
+        );
+    }
+
+    #[test]
+    fn test_ranks_average_ties() {
+        assert_eq!(ranks(&[3.0, 1.0, 2.0]), [2.0, 0.0, 1.0]);
+        assert_eq!(ranks(&[5.0, 1.0, 5.0, 5.0]), [2.0, 0.0, 2.0, 2.0]);
+        assert!(ranks(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_spearman() {
+        let x = [1.0, 2.0, 3.0, 4.0];
+
+        // Only the order matters
+        assert_close(spearman(&x, &[10.0, 20.0, 300.0, 4000.0]), 1.0);
+        assert_close(spearman(&x, &[4.0, 3.0, 2.0, 1.0]), -1.0);
+
+        // Ranks [0, 1.5, 1.5, 3]
+        assert_close(
+            spearman(&x, &[1.0, 2.0, 2.0, 3.0]),
+            4.5 / (5.0_f64 * 4.5).sqrt(),
+        );
+
+        // Constant scores and empty windows carry no information
+        assert_eq!(spearman(&x, &[1.0; 4]), 0.0);
+        assert_eq!(spearman(&[], &[]), 0.0);
+    }
+
+    #[test]
+    fn test_evaluate_empty_window() {
+        let report = evaluate(&[]);
+        assert_eq!(report.samples, 0);
+        assert_eq!(report.completed, 0);
+        assert_eq!(report.rank_correlation, 0.0);
+        assert_eq!(report.batch_overlap, 0.0);
+        assert_eq!(report.primary_value_captured, 0.0);
+        assert_eq!(report.candidate_value_captured, 0.0);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        // Candidate ranks in reverse. Only the first and last
+        // packets completed, and the first was worth more.
+        let samples: Vec<Sample> = (0..10)
+            .map(|i| {
+                let realized = match i {
+                    0 => Some(5.0),
+                    9 => Some(1.0),
+                    _ => None,
+                };
+                sample(i as f64, (10 - i) as f64, realized)
+            })
+            .collect();
+
+        let report = evaluate(&samples);
+        assert_eq!(report.samples, 10);
+        assert_eq!(report.completed, 2);
+        assert_close(report.rank_correlation, -1.0);
+        assert_eq!(report.batch_overlap, 0.0);
+        assert_close(report.primary_value_captured, 0.2);
+        assert_close(report.candidate_value_captured, 1.0);
+    }
+
+    #[test]
+    fn test_evaluate_ties() {
+        // Identical rankings, all tied at the top
+        let samples: Vec<Sample> = (0..4)
+            .map(|_| sample(1.0, 1.0, Some(1.0)))
+            .collect();
+
+        let report = evaluate(&samples);
+        assert_eq!(report.rank_correlation, 0.0);
+        assert_eq!(report.batch_overlap, 1.0);
+        assert_eq!(report.primary_value_captured, 1.0);
+        assert_eq!(report.candidate_value_captured, 1.0);
+    }
+
+    #[test]
+    fn test_publish_samples_and_resets() {
+        let (sender, reports) = mpsc::channel();
+        let mut shadow =
+            Shadow::new(Box::new(IpCandidate), move |report| {
+                sender.send(report).unwrap();
+            })
+            .unwrap();
+        for key in 0..4 * SAMPLE_ONE_IN {
+            let features =
+                TransactionFeatures::new_for_tests(key as u32, [0; 32]);
+            shadow.score_packet(key, &features, F64::from(1.0), 2.0);
+        }
+
+        // Candidate scores are scaled by the value rate
+        assert_eq!(shadow.samples.len(), 4);
+        assert_eq!(
+            shadow.samples[1].candidate,
+            2.0 * SAMPLE_ONE_IN as f64
+        );
+
+        // Outcomes of unsampled packets are ignored
+        shadow.record_outcome(SAMPLE_ONE_IN, F64::from(3.0));
+        shadow.record_outcome(1, F64::from(7.0));
+
+        assert!(shadow.publish());
+        let report = reports.recv().unwrap();
+        assert_eq!(report.samples, 4);
+        assert_eq!(report.completed, 1);
+
+        // The next window starts empty, once the last one is evaluated
+        assert!(shadow.samples.is_empty());
+        while !shadow.publish() {
+            std::thread::yield_now();
+        }
+        assert_eq!(reports.recv().unwrap().samples, 0);
+    }
+}
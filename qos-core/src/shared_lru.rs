@@ -0,0 +1,143 @@
+use std::{
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+
+use log::{info, warn};
+use nohash_hasher::IsEnabled;
+use qos_lru::{Joined, LRUCache, LayoutError, Timestamp};
+use que::{page_size::PageSize, shmem::Shmem};
+
+use crate::error::SharedCacheError;
+
+/// An [LRUCache] resident in a named shared memory region, so that a
+/// restarted sidecar can reattach to it instead of starting cold.
+///
+/// The cache is detached when dropped. A cache with an incompatible
+/// layout is reinitialized. A cache that is still attached is only
+/// reinitialized if the process that attached it is gone (e.g. it
+/// crashed, possibly mid-operation), and is an error otherwise.
+pub struct SharedLRUCache<K, V, const N: usize, T = ()>
+where
+    K: Copy + Eq + std::hash::Hash + IsEnabled,
+    V: Copy,
+    T: Timestamp,
+{
+    cache: NonNull<LRUCache<K, V, N, T>>,
+    shmem: Shmem,
+    restored: bool,
+}
+
+impl<K, V, const N: usize, T> SharedLRUCache<K, V, N, T>
+where
+    K: Copy + Eq + std::hash::Hash + IsEnabled,
+    V: Copy,
+    T: Timestamp,
+{
+    /// `value_version` identifies the layout of `V`. See
+    /// [LRUCache::initialize_in].
+    pub fn open_or_create(
+        name: &str,
+        page_size: PageSize,
+        value_version: u32,
+    ) -> Result<Self, SharedCacheError> {
+        let shmem = Shmem::open_or_create(
+            name,
+            LRUCache::<K, V, N, T>::SHARED_SIZE as i64,
+            page_size,
+        )
+        .map_err(|e| SharedCacheError::Shmem(format!("{e:?}")))?;
+        let ptr = shmem.get_mut_ptr();
+
+        // SAFETY:
+        //
+        // The region is page aligned, large enough, and only accessed
+        // through this cache while attached
+        let (cache, restored) = match unsafe {
+            LRUCache::<K, V, N, T>::join_or_initialize(
+                ptr,
+                value_version,
+                is_running,
+            )
+        } {
+            Ok(Joined::Restored(cache)) => {
+                info!(
+                    "restored {} entries of shared cache {name}",
+                    cache.len()
+                );
+                (cache, true)
+            }
+            Ok(Joined::Initialized(cache, e)) => {
+                if e != LayoutError::BadMagic {
+                    warn!("reinitialized shared cache {name}: {e:?}");
+                }
+                (cache, false)
+            }
+            Err(owner) => {
+                return Err(SharedCacheError::InUse {
+                    name: name.to_string(),
+                    owner,
+                });
+            }
+        };
+
+        Ok(SharedLRUCache {
+            cache: NonNull::from(cache),
+            shmem,
+            restored,
+        })
+    }
+
+    /// Whether entries from a previous run were restored
+    pub fn restored(&self) -> bool {
+        self.restored
+    }
+}
+
+/// Whether a process with this pid exists. A pid reused since the
+/// owner died reads as running, which errs on the side of not
+/// clobbering the cache.
+pub fn is_running(pid: u32) -> bool {
+    // Signal 0 only checks that the process exists
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0
+        || std::io::Error::last_os_error().raw_os_error()
+            == Some(libc::EPERM)
+}
+
+impl<K, V, const N: usize, T> Deref for SharedLRUCache<K, V, N, T>
+where
+    K: Copy + Eq + std::hash::Hash + IsEnabled,
+    V: Copy,
+    T: Timestamp,
+{
+    type Target = LRUCache<K, V, N, T>;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.cache.as_ref() }
+    }
+}
+
+impl<K, V, const N: usize, T> DerefMut for SharedLRUCache<K, V, N, T>
+where
+    K: Copy + Eq + std::hash::Hash + IsEnabled,
+    V: Copy,
+    T: Timestamp,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.cache.as_mut() }
+    }
+}
+
+impl<K, V, const N: usize, T> Drop for SharedLRUCache<K, V, N, T>
+where
+    K: Copy + Eq + std::hash::Hash + IsEnabled,
+    V: Copy,
+    T: Timestamp,
+{
+    fn drop(&mut self) {
+        unsafe {
+            LRUCache::<K, V, N, T>::detach_in(self.shmem.get_mut_ptr())
+        };
+    }
+}
@@ -1,44 +1,61 @@
 use std::{
     net::IpAddr,
-    ptr::NonNull,
-    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use clap::Parser;
-use log::{info, warn};
+use log::{error, info, warn};
 use qos_model::{
-    interface::QoSModel, models::ip_signer::IpSignerModel,
+    cost::ExecutionCostModel, interface::QoSModel,
+    models::ip_signer::IpSignerModel,
 };
 use que::{
-    headless_spmc::{consumer::Consumer, producer::Producer},
-    page_size::PageSize,
+    headless_spmc::consumer::Consumer, page_size::PageSize,
+    shmem::Shmem,
 };
 use solana_qos_common::{
+    channel_header::{ChannelHeader, HandshakeError},
     checked_drop_privileges,
     ipc_parameters::*,
     packet_bytes::PacketBytes,
+    recent_signature::RecentSignature,
     remaining_meta::QoSRemainingMeta,
-    shared_stats::Stats,
+    shared_stats::{SharedStats, Stats},
+    topology::Topology,
     xxhash::{xxHash, xxHasher},
 };
 use solana_qos_core::{
-    banking::TransactionContainer, get_page_size, try_process_packet,
-    u64_key,
+    backlog::Backlog,
+    banking::{TransactionContainer, QUEUE_CAPACITY},
+    error::IpcError,
+    expire_partial_metas, get_page_size,
+    ipc::{self, IngressConsumer, QoSChannels},
+    packet_hash,
+    recent_signatures::RecentSignatures,
+    shadow::{IpSignerCandidate, Shadow},
+    shared_lru::{is_running, SharedLRUCache},
+    try_process_packet,
 };
 use solana_qos_internal_common::{
-    packet_bytes, partial_meta::QoSPartialMeta,
+    packet_bytes,
+    partial_meta::QoSPartialMeta,
+    transaction_features::{ipv4_key, PacketSource},
     transaction_meta::QoSTransactionMeta,
 };
 use timer::Timer;
 
-#[cfg(feature = "demo")]
-use {que::shmem::Shmem, solana_qos_common::shared_stats::SharedStats};
-
 use qos_lru::LRUCache;
 
-static EXIT: AtomicBool = AtomicBool::new(false);
+use crate::metrics::{Snapshot, MAX_CHANNELS};
 
-type SignatureBytes = [u8; 64];
+mod metrics;
+
+static EXIT: AtomicBool = AtomicBool::new(false);
 
 #[derive(Parser)]
 pub struct Args {
@@ -57,6 +74,37 @@ pub struct Args {
 
     #[clap(long, default_value_t = 10_000)]
     max_ips: usize,
+
+    /// Run a candidate model in shadow and periodically report how it
+    /// compares to the primary model. It never affects ordering.
+    #[clap(long)]
+    shadow: bool,
+
+    #[clap(long, default_value_t = 10_000)]
+    shadow_max_signers: usize,
+
+    #[clap(long, default_value_t = 10_000)]
+    shadow_max_ips: usize,
+
+    /// Partial metas without scheduler feedback after this long are
+    /// expired. Transmitted ones become negative feedback.
+    #[clap(long, default_value_t = 10_000)]
+    partial_meta_ttl_ms: u64,
+
+    /// Max queued transactions visited per loop when rescoring them
+    /// after the model changes
+    #[clap(long, default_value_t = 1024)]
+    rescore_budget: usize,
+
+    /// Toml file describing the channels to join. Defaults to the
+    /// topology in topology.toml.
+    #[clap(long)]
+    topology: Option<PathBuf>,
+
+    /// Serve Prometheus metrics on this localhost port. Disabled if
+    /// not set.
+    #[clap(long)]
+    metrics_port: Option<u16>,
 }
 
 #[allow(unused_must_use)]
@@ -83,62 +131,172 @@ fn main() {
         .filter_level(log::LevelFilter::Info)
         .init();
 
+    if let Err(e) = run(args) {
+        error!("{e}");
+        std::process::exit(1);
+    }
+}
+
+/// Returns early on any error, so that the shared caches are cleanly
+/// detached and restored by the next run
+fn run(args: Args) -> Result<(), String> {
     // Initialize all IPC channels
+    //
+    // NOTE: the default topology has four inputs because when using a modified co-hosted relayer with qos, there is still some residual traffic to the host's original (and now unadvertised) TPU.
+    let topology = match args.topology {
+        Some(ref path) => Topology::load(path),
+        None => Ok(Topology::default()),
+    };
+    let topology = topology
+        .map_err(|e| format!("failed to load topology: {e:?}"))?;
     let page_size = get_page_size(
         #[cfg(target_os = "linux")]
         args.use_huge_pages,
     );
-    let (
-        mut tpu_consumer,
-        mut fwd_consumer,
-        mut re1_consumer,
-        mut re2_consumer,
-        mut sig_consumer,
-        mut sch_consumer,
-        sig_producer,
-        mut recent_sig_consumer,
-    ) = join_ipc(page_size);
+    let QoSChannels {
+        mut ingress,
+        ingress_sources,
+        sigverify_feedback: mut sig_consumer,
+        scheduler_feedback: mut sch_consumer,
+        status_cache: mut recent_sig_consumer,
+        sigverify_output: sig_producer,
+    } = ipc::join(&topology, page_size).map_err(|e| match e {
+        IpcError::Handshake {
+            error: HandshakeError::Incomplete,
+            ..
+        } => format!(
+            "failed to join ipc channels: {e:?}. Rerun solana-qos-cli \
+             or remove the channel to reset its header"
+        ),
+        e => format!("failed to join ipc channels: {e:?}"),
+    })?;
 
     // Initialize stats
+    // Stats are published to shared memory for external readers
     let mut stats = Stats::new();
-    #[cfg(feature = "demo")]
-    let stats_shmem =
-        Shmem::open_or_create("qos_stats", 2048, PageSize::Standard)
-            .unwrap();
+    let stats_shmem = Shmem::open_or_create(
+        "qos_stats",
+        SharedStats::<Stats>::SIZE as i64,
+        PageSize::Standard,
+    )
+    .map_err(|e| format!("failed to open stats: {e:?}"))?;
+    let shared_stats = unsafe {
+        SharedStats::<Stats>::join_or_initialize(
+            stats_shmem.get_mut_ptr(),
+            is_running,
+        )
+    }
+    .map_err(|owner| {
+        format!("stats are being initialized by process {owner}")
+    })?;
 
-    // Remove sudo privileges
-    if let Err(e) = checked_drop_privileges() {
-        panic!("{e:?}");
+    // Open caches in shared memory so that they survive restarts
+    let mut qos_tx_partial_metas = SharedLRUCache::<
+        xxHash,
+        QoSPartialMeta,
+        { 1024 * 1024 },
+        u64,
+    >::open_or_create(
+        "qos_partial_metas",
+        page_size,
+        QoSPartialMeta::LAYOUT_VERSION,
+    )
+    .map_err(|e| format!("failed to open partial metas: {e:?}"))?;
+    let mut recent_signatures =
+        RecentSignatures::<{ 1024 * 1024 }>::open_or_create(
+            "qos_recent_signatures",
+            page_size,
+        )
+        .map_err(|e| {
+            format!("failed to open recent signatures: {e:?}")
+        })?;
+    let backlog = Backlog::<QUEUE_CAPACITY>::open_or_create(
+        "qos_backlog",
+        page_size,
+    )
+    .map_err(|e| format!("failed to open backlog: {e:?}"))?;
+    info!(
+        "partial metas restored: {}, recent signatures restored: {} (up to slot {})",
+        qos_tx_partial_metas.restored(),
+        recent_signatures.restored(),
+        recent_signatures.slot(),
+    );
+
+    // Serve metrics from a side thread, before dropping privileges in
+    // case the port is privileged
+    if topology.inputs.len() > MAX_CHANNELS {
+        warn!("only the first {MAX_CHANNELS} inputs export metrics");
     }
+    let metrics = args
+        .metrics_port
+        .map(|port| {
+            let snapshot = Arc::new(SharedStats::<Snapshot>::new());
+            let channels: Vec<String> = topology
+                .inputs
+                .iter()
+                .map(|input| input.name.clone())
+                .collect();
+            metrics::serve(port, &channels, snapshot.clone())
+                .map(|()| snapshot)
+        })
+        .transpose()
+        .map_err(|e| format!("failed to serve metrics: {e}"))?;
+    let mut metrics_snapshot = Snapshot::default();
+    // Counts from before the last reset of the shared stats, so that
+    // the metrics counters never go backwards
+    let mut reset_stats = Stats::default();
+    let mut last_metrics_ms = 0;
 
-    // Write seed to first 8 bytes of scheduler channel metadata
+    // Remove sudo privileges
+    checked_drop_privileges()?;
+
+    // Write seed to the scheduler channel header
     unsafe {
-        let metadata_ptr: NonNull<AtomicU64> =
-            sch_consumer.get_padding_ptr().cast();
-        metadata_ptr
-            .as_ref()
-            .store(args.xxhash_seed, Ordering::Release);
+        ChannelHeader::from_padding(sch_consumer.get_padding_ptr())
     }
+    .seed
+    .store(args.xxhash_seed, Ordering::Release);
 
     // Initialize QoS Model
     let mut qos_model = IpSignerModel::new([], []);
-    let mut qos_tx_partial_metas =
-        LRUCache::<_, _, { 1024 * 1024 }>::new_boxed();
+    let mut cost_model = ExecutionCostModel::new();
+    let mut shadow = args
+        .shadow
+        .then(|| {
+            Shadow::new(
+                Box::new(IpSignerCandidate::<16384, 16384> {
+                    model: IpSignerModel::new([], []),
+                    max_signers: args.shadow_max_signers,
+                    max_ips: args.shadow_max_ips,
+                }),
+                |report| info!("shadow: {report:?}"),
+            )
+        })
+        .transpose()
+        .map_err(|e| format!("failed to spawn shadow thread: {e}"))?;
     let mut qos_tx_complete_metas = Vec::with_capacity(1024 * 1024);
 
     // Initialize container with banking stage transmitter
     let mut container =
         TransactionContainer::new(Some(sig_producer), args.target_pps);
 
-    // Initialize LRU cache for filtering recently confirmed signatures
-    let mut recent_signatures =
-        LRUCache::<u64, (), { 1024 * 1024 }>::new_boxed();
-
     // Initialize hasher
     let xxhasher = xxHasher::initialize_with_seed(args.xxhash_seed);
 
-    // If on x86, tune rdtsc-based timer
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    // Queue what the previous run left queued
+    requeue_backlog(
+        backlog,
+        &mut qos_model,
+        &cost_model,
+        shadow.as_mut(),
+        &mut qos_tx_partial_metas,
+        &mut stats,
+        &mut container,
+        &xxhasher,
+        &recent_signatures,
+    );
+
+    // Calibrate the hardware counter timer, if there is one
     Timer::memoize_ticks_per_ms_and_invariant_tsc_check();
 
     // Start timer
@@ -147,52 +305,64 @@ fn main() {
     info!("starting qos");
     while !EXIT.load(Ordering::Relaxed) {
         // Consume packets
-        //
-        // NOTE: four consumers are used because when using a modified co-hosted relayer with qos, there is still some residual traffic to the host's original (and now unadvertised) TPU.
-        for consumer in [
-            &mut tpu_consumer,
-            &mut fwd_consumer,
-            &mut re1_consumer,
-            &mut re2_consumer,
-        ] {
-            consume_transaction_packets(
-                consumer,
-                &mut qos_model,
-                &mut qos_tx_partial_metas,
-                &mut stats,
+        consume_transaction_packets(
+            &mut ingress,
+            &ingress_sources,
+            &mut qos_model,
+            &cost_model,
+            shadow.as_mut(),
+            &mut qos_tx_partial_metas,
+            &mut stats,
+            &mut container,
+            &xxhasher,
+            &recent_signatures,
+            unix_millis(),
+        );
+
+        // Consume recent signatures.
+        if let Some(ref mut recent_sig_consumer) = recent_sig_consumer {
+            consume_recent_signatures(
+                recent_sig_consumer,
+                &mut recent_signatures,
                 &mut container,
-                &xxhasher,
-                &recent_signatures,
+                &mut stats,
             );
         }
 
-        // Consume recent signatures.
-        consume_recent_signatures(
-            &mut recent_sig_consumer,
-            &mut recent_signatures,
-            &mut stats,
-        );
-
         // Log periodically
         static mut LAST_LOG: u64 = 0;
         let elapsed_ms = timer.elapsed_ms();
         let elapsed_5s = elapsed_ms / 5000;
         if unsafe { elapsed_5s > LAST_LOG } {
             unsafe { LAST_LOG = elapsed_5s };
-            log_stats(&timer, &mut stats);
+            log_stats(&timer, &mut stats, &ingress, shadow.as_mut());
         }
-        #[cfg(feature = "demo")]
-        unsafe {
-            static mut LAST_SAVE: u64 = 0;
-            let elapsed_100ms = elapsed_ms / 100;
-            if elapsed_100ms > LAST_SAVE {
-                LAST_SAVE = elapsed_100ms;
-                if SharedStats::update(
-                    stats_shmem.get_mut_ptr(),
+        static mut LAST_PUBLISH: u64 = 0;
+        let elapsed_100ms = elapsed_ms / 100;
+        if unsafe { elapsed_100ms > LAST_PUBLISH } {
+            unsafe { LAST_PUBLISH = elapsed_100ms };
+            if shared_stats.publish(&stats) {
+                reset_stats.add(&stats);
+                stats = Stats::default();
+            }
+        }
+        if let Some(ref metrics) = metrics {
+            // TODO: hard coded parameter
+            if elapsed_ms >= last_metrics_ms + 1000 {
+                publish_metrics(
+                    metrics,
+                    &mut metrics_snapshot,
+                    elapsed_ms - last_metrics_ms,
+                    &reset_stats,
                     &stats,
-                ) {
-                    stats = Stats::default();
-                }
+                    &ingress,
+                    &container,
+                    &qos_tx_partial_metas,
+                    &recent_signatures,
+                    &qos_model,
+                    &cost_model,
+                );
+                last_metrics_ms = elapsed_ms;
             }
         }
 
@@ -203,30 +373,126 @@ fn main() {
             &mut qos_tx_partial_metas,
             &mut qos_tx_complete_metas,
             &mut qos_model,
+            &mut cost_model,
+            shadow.as_mut(),
             &mut stats,
             args.max_signers,
             args.max_ips,
         );
 
+        // Expire partial metas whose feedback never arrived
+        expire_partial_metas(
+            &mut qos_tx_partial_metas,
+            &mut qos_tx_complete_metas,
+            shadow.as_mut(),
+            &mut stats,
+            unix_millis().saturating_sub(args.partial_meta_ttl_ms),
+        );
+
         // Handle any failed sigverify signals
-        consume_sigverify_signals(&mut sig_consumer, &mut qos_model);
+        consume_sigverify_signals(
+            &mut sig_consumer,
+            &mut qos_model,
+            shadow.as_mut(),
+        );
+
+        // Bring queued scores up to date with the model, including ip
+        // feedback held back by the version interval
+        qos_model.flush_feedback();
+        container.rescore(
+            &qos_model,
+            &(),
+            args.rescore_budget,
+            &mut stats,
+        );
     }
 
     info!("received exit signal");
     qos_model.save_ip_scores("ip_scores");
+
+    // Untransmitted transactions are already persisted for the next
+    // run
+    info!("persisted {} queued transactions", container.len());
+
     info!("graceful exit complete");
+    Ok(())
 }
 
-fn consume_recent_signatures(
-    recent_sig_consumer: &mut Consumer<[u8; 64], { 1024 * 1024 }>,
-    recent_signatures: &mut LRUCache<u64, (), { 1024 * 1024 }>,
+/// Scores the transactions persisted by the previous run with the
+/// current model and queues them, persisting the queue from then on.
+/// Any that were processed in the meantime are dropped as recently
+/// processed.
+fn requeue_backlog(
+    mut backlog: Backlog<QUEUE_CAPACITY>,
+    qos_model: &mut IpSignerModel<16384, 16384>,
+    cost_model: &ExecutionCostModel,
+    mut shadow: Option<&mut Shadow>,
+    qos_tx_partial_metas: &mut LRUCache<
+        xxHash,
+        QoSPartialMeta,
+        { 1024 * 1024 },
+        u64,
+    >,
     stats: &mut Stats,
+    banking: &mut TransactionContainer,
+    xxhasher: &xxHasher,
+    recent_signatures: &RecentSignatures<{ 1024 * 1024 }>,
 ) {
-    while let Some(signature) = recent_sig_consumer.pop() {
-        let key = u64_key(&signature);
-        recent_signatures.put(key, ());
+    let persisted = backlog.take();
+    banking.persist(backlog);
+    let total = persisted.len();
+    let mut requeued = 0;
+    for (source, packet) in persisted {
+        // The previous run stored a partial meta for each queued
+        // packet. Drop it so that the packet is not mistaken for a
+        // duplicate.
+        qos_tx_partial_metas.pop(&packet_hash(xxhasher, &packet));
+
+        if let Ok(scored_transaction) = try_process_packet(
+            packet,
+            source,
+            Some(recent_signatures),
+            qos_model,
+            cost_model,
+            shadow.as_deref_mut(),
+            qos_tx_partial_metas,
+            stats,
+            xxhasher,
+            unix_millis(),
+        ) {
+            banking.queue(scored_transaction, stats);
+            requeued += 1;
+        }
+    }
 
-        stats.recent_signatures_received += 1;
+    info!("requeued {requeued} of {total} persisted transactions");
+}
+
+/// Wall clock timestamp for cache entries. Unlike [Timer] this is
+/// meaningful across restarts, as the caches are.
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn consume_recent_signatures(
+    recent_sig_consumer: &mut Consumer<
+        RecentSignature,
+        IPC_STATUS_CACHE_CAP,
+    >,
+    recent_signatures: &mut RecentSignatures<{ 1024 * 1024 }>,
+    banking: &mut TransactionContainer,
+    stats: &mut Stats,
+) {
+    while let Some(recent) = recent_sig_consumer.pop() {
+        // Too old to be processed again either way
+        let Some(key) = recent_signatures.insert(&recent, stats) else {
+            continue;
+        };
+
+        // Already processed, so no need to send it again
+        banking.purge_signature(key, stats);
     }
 }
 
@@ -239,9 +505,12 @@ fn consume_remaining_metas(
         xxHash,
         QoSPartialMeta,
         { 1024 * 1024 },
+        u64,
     >,
     qos_tx_complete_metas: &mut Vec<QoSTransactionMeta<()>>,
     qos_model: &mut IpSignerModel<16384, 16384>,
+    cost_model: &mut ExecutionCostModel,
+    mut shadow: Option<&mut Shadow>,
     stats: &mut Stats,
     max_signers: usize,
     max_ips: usize,
@@ -252,10 +521,22 @@ fn consume_remaining_metas(
             qos_tx_partial_metas.pop(&remaining_meta.packet_hash)
         {
             // Complete metadata entry
-            let was_scheduled = remaining_meta.execution_nanos > 0;
-            let mut complete_entry = partial_meta.merge(remaining_meta);
-            if was_scheduled {
-                *complete_entry.value *= 10.0;
+            let executed = remaining_meta
+                .outcome()
+                .is_some_and(|outcome| outcome.executed());
+            if executed && remaining_meta.execution_nanos > 0 {
+                cost_model.train(
+                    partial_meta.program_keys(),
+                    partial_meta.num_instructions,
+                    remaining_meta.execution_nanos,
+                );
+            }
+            let complete_entry = partial_meta.merge(remaining_meta);
+            if let Some(shadow) = shadow.as_deref_mut() {
+                shadow.record_outcome(
+                    remaining_meta.packet_hash,
+                    complete_entry.value,
+                );
             }
             qos_tx_complete_metas.push(complete_entry);
 
@@ -271,11 +552,18 @@ fn consume_remaining_metas(
             // I don't like this hardcoded threshold.
             // At current traffic (2.0.21) this is roughly every block.
             if qos_tx_complete_metas.len() > 400 {
+                if let Some(shadow) = shadow.as_deref_mut() {
+                    shadow.update_model(qos_tx_complete_metas);
+                }
+                let update_timer = Timer::new();
                 qos_model.update_model(
                     qos_tx_complete_metas.drain(..),
                     max_signers,
                     max_ips,
                 );
+                stats.model_updates += 1;
+                stats.model_update_us +=
+                    update_timer.elapsed_us() as usize;
                 qos_model.save_ip_scores("scores");
                 break;
             }
@@ -291,15 +579,21 @@ fn consume_remaining_metas(
 fn consume_sigverify_signals(
     sig_consumer: &mut Consumer<PacketBytes, IPC_SIG_TO_QOS_CAP>,
     qos_model: &mut IpSignerModel<16384, 16384>,
+    mut shadow: Option<&mut Shadow>,
 ) {
     while let Some(sigverify_failed) = sig_consumer.pop() {
-        process_failed_sigverify(sigverify_failed, qos_model);
+        process_failed_sigverify(
+            sigverify_failed,
+            qos_model,
+            shadow.as_deref_mut(),
+        );
     }
 }
 
-fn process_failed_sigverify(
+fn process_failed_sigverify<M: QoSModel<IpFeedback = u32>>(
     sigverify_failed: PacketBytes,
-    qos_model: &mut IpSignerModel<16384, 16384>,
+    qos_model: &mut M,
+    shadow: Option<&mut Shadow>,
 ) {
     // Parse ip from packet
     let packet = packet_bytes::as_packet(sigverify_failed);
@@ -309,36 +603,53 @@ fn process_failed_sigverify(
             &packet.meta().addr,
         );
     };
-    let ip: u32 = u32::from_le_bytes(ip.octets());
+    let ip = ipv4_key(&ip);
 
+    // Queued transactions from this ip are rescored with the penalty
+    // rather than dropped, as an ip may be shared by many senders
     qos_model.ip_feedback(ip);
+    if let Some(shadow) = shadow {
+        shadow.ip_feedback(ip);
+    }
 }
 
 fn consume_transaction_packets(
-    consumer: &mut Consumer<PacketBytes, IPC_TPU_TO_QOS_CAP>,
+    ingress: &mut IngressConsumer,
+    ingress_sources: &[PacketSource],
     qos_model: &mut IpSignerModel<16384, 16384>,
+    cost_model: &ExecutionCostModel,
+    mut shadow: Option<&mut Shadow>,
     qos_tx_partial_metas: &mut LRUCache<
         xxHash,
         QoSPartialMeta,
         { 1024 * 1024 },
+        u64,
     >,
     stats: &mut Stats,
     banking: &mut TransactionContainer,
     xxhasher: &xxHasher,
-    recent_signatures: &LRUCache<u64, (), { 1024 * 1024 }>,
+    recent_signatures: &RecentSignatures<{ 1024 * 1024 }>,
+    timestamp_ms: u64,
 ) {
-    for _ in 0..1_000 {
-        if let Some(packet_bytes) = consumer.pop() {
+    for _ in 0..4_000 {
+        if let Some((lane, packet_bytes)) = ingress.pop_tagged() {
             // Process packet and score transaction
-            let Ok(scored_transaction) = try_process_packet(
-                packet_bytes::as_packet(packet_bytes),
+            let source = ingress_sources[lane];
+            let packet = packet_bytes::as_packet(packet_bytes);
+            let scored_transaction = match try_process_packet(
+                packet,
+                source,
                 Some(recent_signatures),
                 qos_model,
+                cost_model,
+                shadow.as_deref_mut(),
                 qos_tx_partial_metas,
                 stats,
                 xxhasher,
-            ) else {
-                continue;
+                timestamp_ms,
+            ) {
+                Ok(scored_transaction) => scored_transaction,
+                Err(_) => continue,
             };
 
             // Record zero score transactions
@@ -348,109 +659,146 @@ fn consume_transaction_packets(
 
             // Send to bank/sigverify
             banking.queue(scored_transaction, stats);
-            banking.maybe_transmit(stats, recent_signatures);
+            banking.maybe_transmit(stats, qos_tx_partial_metas);
         } else {
             break;
         }
     }
-    consumer.beat();
+    ingress.beat();
     banking.beat();
 }
 
 #[cold]
-fn log_stats(timer: &Timer, stats: &mut Stats) {
+fn log_stats(
+    timer: &Timer,
+    stats: &mut Stats,
+    ingress: &IngressConsumer,
+    shadow: Option<&mut Shadow>,
+) {
     info!(
         "stats: {stats:?}; average = {:.3}/s",
         stats.total_packets as f64 * 1e3
             / (timer.elapsed_ms().max(1) as f64),
     );
+    info!("ingress: {:?}", ingress.stats().collect::<Vec<_>>());
+    // Logged by the shadow thread once evaluated
+    if let Some(shadow) = shadow {
+        if !shadow.publish() {
+            warn!("shadow: previous window still being evaluated");
+        }
+    }
 }
 
-fn join_ipc(
-    page_size: PageSize,
-) -> (
-    Consumer<PacketBytes, IPC_TPU_TO_QOS_CAP>,
-    Consumer<PacketBytes, IPC_FWD_TO_QOS_CAP>,
-    Consumer<PacketBytes, IPC_RE1_TO_QOS_CAP>,
-    Consumer<PacketBytes, IPC_RE2_TO_QOS_CAP>,
-    Consumer<PacketBytes, IPC_SIG_TO_QOS_CAP>,
-    Consumer<QoSRemainingMeta<()>, IPC_SCH_TO_QOS_CAP>,
-    Producer<PacketBytes, IPC_QOS_TO_SIG_CAP>,
-    Consumer<SignatureBytes, { 1024 * 1024 }>,
+/// Publishes a snapshot for the metrics thread. Ingest rates are over
+/// the `interval_ms` since the last snapshot, and counters add the
+/// `reset_stats` counted before the last reset.
+#[cold]
+fn publish_metrics(
+    metrics: &SharedStats<Snapshot>,
+    snapshot: &mut Snapshot,
+    interval_ms: u64,
+    reset_stats: &Stats,
+    stats: &Stats,
+    ingress: &IngressConsumer,
+    banking: &TransactionContainer,
+    qos_tx_partial_metas: &LRUCache<
+        xxHash,
+        QoSPartialMeta,
+        { 1024 * 1024 },
+        u64,
+    >,
+    recent_signatures: &RecentSignatures<{ 1024 * 1024 }>,
+    qos_model: &IpSignerModel<16384, 16384>,
+    cost_model: &ExecutionCostModel,
 ) {
-    let tpu_consumer = unsafe {
-        Consumer::<PacketBytes, IPC_TPU_TO_QOS_CAP>::join_shmem(
-            IPC_TPU_TO_QOS_NAME,
-            page_size,
-        )
-        .unwrap()
-    };
-    let fwd_consumer = unsafe {
-        Consumer::<PacketBytes, IPC_FWD_TO_QOS_CAP>::join_shmem(
-            IPC_FWD_TO_QOS_NAME,
-            page_size,
-        )
-        .unwrap()
-    };
+    for (ingest, lane) in snapshot
+        .ingest
+        .iter_mut()
+        .zip(ingress.stats())
+    {
+        ingest.rate = lane
+            .popped
+            .saturating_sub(ingest.popped) as f64
+            * 1e3
+            / interval_ms.max(1) as f64;
+        ingest.popped = lane.popped;
+        ingest.empty_turns = lane.empty_turns;
+        ingest.saturated_turns = lane.saturated_turns;
+    }
 
-    let re1_consumer = unsafe {
-        Consumer::<PacketBytes, IPC_RE1_TO_QOS_CAP>::join_shmem(
-            IPC_RE1_TO_QOS_NAME,
-            page_size,
-        )
-        .unwrap()
-    };
-    let re2_consumer = unsafe {
-        Consumer::<PacketBytes, IPC_RE2_TO_QOS_CAP>::join_shmem(
-            IPC_RE2_TO_QOS_NAME,
-            page_size,
-        )
-        .unwrap()
-    };
+    snapshot.stats = *reset_stats;
+    snapshot.stats.add(stats);
+    snapshot.queued = banking.len();
+    snapshot.queue_capacity = QUEUE_CAPACITY;
+    snapshot.partial_metas = qos_tx_partial_metas.len();
+    snapshot.partial_metas_capacity = qos_tx_partial_metas.capacity();
+    snapshot.recent_signatures = recent_signatures.len();
+    snapshot.recent_signatures_capacity = recent_signatures.capacity();
+    snapshot.model_ips = qos_model.num_ips();
+    snapshot.model_signers = qos_model.num_signers();
+    snapshot.model_signer_utilizations =
+        qos_model.num_signer_utilizations();
+    snapshot.cost_model_samples = cost_model.samples();
+    snapshot.transmit_budget = banking.transmit_budget();
+
+    metrics.publish(snapshot);
+}
 
-    let sig_consumer = unsafe {
-        Consumer::<PacketBytes, IPC_SIG_TO_QOS_CAP>::join_shmem(
-            IPC_SIG_TO_QOS_NAME,
-            page_size,
-        )
-        .unwrap()
-    };
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
 
-    let sch_consumer = unsafe {
-        Consumer::<
-        QoSRemainingMeta<()>,
-        IPC_SCH_TO_QOS_CAP,
-    >::join_shmem(
-        IPC_SCH_TO_QOS_NAME,
-        page_size,
-    )
-    .unwrap()
+    use solana_qos_internal_common::{
+        scored_transaction::ScoredTransaction,
+        transaction_features::TransactionFeatures,
+        transaction_meta::F64,
     };
 
-    let sig_producer = unsafe {
-        Producer::<
-            PacketBytes,
-            IPC_QOS_TO_SIG_CAP,
-        >::join_or_create_shmem(IPC_QOS_TO_SIG_NAME, page_size)
-        .unwrap()
-    };
+    use super::*;
+
+    #[test]
+    fn test_failed_sigverify_keeps_shared_ip_queued() {
+        // A forwarder relaying for many senders
+        let forwarder = Ipv4Addr::new(10, 0, 0, 1);
+        let ip = ipv4_key(&forwarder);
+
+        let mut qos_model =
+            IpSignerModel::<16, 16>::new([(ip, 1.0)], []);
+        let mut container = TransactionContainer::new(None, 1_000);
+        let mut stats = Stats::default();
+        for sig_key in 0..3 {
+            container.queue(
+                ScoredTransaction {
+                    score: F64::from(1.0),
+                    sig_key,
+                    packet: packet_bytes::as_packet(
+                        PacketBytes::default(),
+                    ),
+                    ipv4: ip,
+                    packet_key: sig_key,
+                    features: TransactionFeatures::new_for_tests(
+                        ip,
+                        [sig_key as u8; 32],
+                    ),
+                    value_rate: F64::from(1.0),
+                    model_version: qos_model.version(),
+                },
+                &mut stats,
+            );
+        }
 
-    let recent_sig_consumer = unsafe {
-        Consumer::<SignatureBytes, IPC_STATUS_CACHE_CAP>::join_shmem(
-            IPC_STATUS_CACHE_NAME,
-            page_size,
-        )
-        .unwrap()
-    };
+        // One of its senders fails sigverify
+        let mut failed =
+            packet_bytes::as_packet(PacketBytes::default());
+        failed.meta_mut().addr = IpAddr::V4(forwarder);
+        process_failed_sigverify(
+            *packet_bytes::from_packet(&failed),
+            &mut qos_model,
+            None,
+        );
 
-    (
-        tpu_consumer,
-        fwd_consumer,
-        re1_consumer,
-        re2_consumer,
-        sig_consumer,
-        sch_consumer,
-        sig_producer,
-        recent_sig_consumer,
-    )
+        // The penalty only reaches the queue through rescoring
+        container.rescore(&qos_model, &(), QUEUE_CAPACITY, &mut stats);
+        assert_eq!(container.len(), 3);
+    }
 }
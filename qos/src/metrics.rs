@@ -0,0 +1,340 @@
+//! Prometheus endpoint. The main loop periodically publishes a
+//! [Snapshot] through a [SharedStats] seqlock, and a side thread serves
+//! the latest one, so scrapes never block the main loop.
+
+use std::{
+    fmt::{self, Write as _},
+    io::{self, Read, Write as _},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::debug;
+use solana_qos_common::shared_stats::{SharedStats, Stats};
+
+/// Max number of ingest channels exported. Lanes past this are not.
+pub const MAX_CHANNELS: usize = 16;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IngestMetrics {
+    pub popped: u64,
+
+    /// Packets popped per second over the last publish interval
+    pub rate: f64,
+
+    pub empty_turns: u64,
+    pub saturated_turns: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Snapshot {
+    /// Counted since startup, ignoring resets of the shared stats
+    pub stats: Stats,
+
+    /// Indexed by ingress lane id
+    pub ingest: [IngestMetrics; MAX_CHANNELS],
+
+    pub queued: usize,
+    pub queue_capacity: usize,
+
+    pub partial_metas: usize,
+    pub partial_metas_capacity: usize,
+    pub recent_signatures: usize,
+    pub recent_signatures_capacity: usize,
+
+    pub model_ips: usize,
+    pub model_signers: usize,
+    pub model_signer_utilizations: usize,
+    pub cost_model_samples: u64,
+
+    /// Max number of transactions transmitted per send interval
+    pub transmit_budget: usize,
+}
+
+/// Binds `port` on localhost and serves `/metrics` from a side thread.
+/// `channels` names the ingest channels by lane id.
+pub fn serve(
+    port: u16,
+    channels: &[String],
+    snapshot: Arc<SharedStats<Snapshot>>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    let channels: Vec<String> = channels
+        .iter()
+        .take(MAX_CHANNELS)
+        .map(|name| escape(name))
+        .collect();
+
+    std::thread::Builder::new()
+        .name("qos-metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| {
+                    respond(stream, &channels, &snapshot)
+                });
+                if let Err(e) = result {
+                    debug!("failed to serve metrics: {e}");
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn respond(
+    mut stream: TcpStream,
+    channels: &[String],
+    snapshot: &SharedStats<Snapshot>,
+) -> io::Result<()> {
+    // TODO: hard coded parameter
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+
+    // Only the request line matters
+    let mut request = [0; 1024];
+    let len = stream.read(&mut request)?;
+    let request = String::from_utf8_lossy(&request[..len]);
+    let mut request_line = request.split_whitespace();
+
+    let (status, body) =
+        match (request_line.next(), request_line.next()) {
+            (Some("GET"), Some("/metrics")) => {
+                ("200 OK", render(&snapshot.read(), channels))
+            }
+            _ => ("404 Not Found", String::new()),
+        };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len(),
+    )
+}
+
+/// Prometheus text format of `snapshot`
+pub fn render(snapshot: &Snapshot, channels: &[String]) -> String {
+    let mut out = String::new();
+    render_into(&mut out, snapshot, channels)
+        .expect("writing to a string never fails");
+    out
+}
+
+fn render_into(
+    out: &mut String,
+    snapshot: &Snapshot,
+    channels: &[String],
+) -> fmt::Result {
+    let Snapshot {
+        stats,
+        ingest,
+        queued,
+        queue_capacity,
+        partial_metas,
+        partial_metas_capacity,
+        recent_signatures,
+        recent_signatures_capacity,
+        model_ips,
+        model_signers,
+        model_signer_utilizations,
+        cost_model_samples,
+        transmit_budget,
+    } = snapshot;
+
+    render_stats(out, stats)?;
+
+    // Ingest
+    let lanes = || channels.iter().zip(ingest);
+    header(out, "qos_ingest_popped_total", "counter")?;
+    for (channel, lane) in lanes() {
+        writeln!(
+            out,
+            "qos_ingest_popped_total{{channel=\"{channel}\"}} {}",
+            lane.popped
+        )?;
+    }
+    header(out, "qos_ingest_packets_per_second", "gauge")?;
+    for (channel, lane) in lanes() {
+        writeln!(
+            out,
+            "qos_ingest_packets_per_second{{channel=\"{channel}\"}} {}",
+            lane.rate
+        )?;
+    }
+    header(out, "qos_ingest_empty_turns_total", "counter")?;
+    for (channel, lane) in lanes() {
+        writeln!(
+            out,
+            "qos_ingest_empty_turns_total{{channel=\"{channel}\"}} {}",
+            lane.empty_turns
+        )?;
+    }
+    header(out, "qos_ingest_saturated_turns_total", "counter")?;
+    for (channel, lane) in lanes() {
+        writeln!(
+            out,
+            "qos_ingest_saturated_turns_total{{channel=\"{channel}\"}} {}",
+            lane.saturated_turns
+        )?;
+    }
+
+    // Occupancy
+    gauge(out, "qos_queued_transactions", *queued)?;
+    gauge(out, "qos_queue_capacity", *queue_capacity)?;
+    gauge(out, "qos_partial_metas", *partial_metas)?;
+    gauge(out, "qos_partial_metas_capacity", *partial_metas_capacity)?;
+    gauge(out, "qos_recent_signatures", *recent_signatures)?;
+    gauge(
+        out,
+        "qos_recent_signatures_capacity",
+        *recent_signatures_capacity,
+    )?;
+
+    // Model
+    gauge(out, "qos_model_ips", *model_ips)?;
+    gauge(out, "qos_model_signers", *model_signers)?;
+    gauge(
+        out,
+        "qos_model_signer_utilizations",
+        *model_signer_utilizations,
+    )?;
+    gauge(out, "qos_cost_model_samples", *cost_model_samples)?;
+
+    gauge(out, "qos_transmit_budget", *transmit_budget)
+}
+
+/// Every [Stats] counter. Destructured so that new counters are not
+/// missed.
+fn render_stats(out: &mut String, stats: &Stats) -> fmt::Result {
+    let Stats {
+        total_packets,
+        non_ipv4,
+        non_transaction_packet,
+        recently_processed,
+        recently_processed_by_age,
+        recently_processed_queued,
+        rescored_queued,
+        recent_signatures_received,
+        recent_signatures_expired,
+        invalid_meta_size,
+        failed_sanitize,
+        failed_view,
+        invalid_packet_data,
+        leaked_priority,
+        duplicate_packets,
+        banking_transmissions,
+        zero_score,
+        completed,
+        expired_transmitted,
+        expired_untransmitted,
+        model_updates,
+        model_update_us,
+    } = stats;
+
+    for (name, value) in [
+        ("total_packets", total_packets),
+        ("non_ipv4", non_ipv4),
+        ("non_transaction_packet", non_transaction_packet),
+        ("recently_processed", recently_processed),
+        ("recently_processed_queued", recently_processed_queued),
+        ("rescored_queued", rescored_queued),
+        ("recent_signatures_received", recent_signatures_received),
+        ("recent_signatures_expired", recent_signatures_expired),
+        ("invalid_meta_size", invalid_meta_size),
+        ("failed_sanitize", failed_sanitize),
+        ("failed_view", failed_view),
+        ("invalid_packet_data", invalid_packet_data),
+        ("leaked_priority", leaked_priority),
+        ("duplicate_packets", duplicate_packets),
+        ("banking_transmissions", banking_transmissions),
+        ("zero_score", zero_score),
+        ("completed", completed),
+        ("expired_transmitted", expired_transmitted),
+        ("expired_untransmitted", expired_untransmitted),
+        ("model_updates", model_updates),
+        ("model_update_us", model_update_us),
+    ] {
+        header(out, &format!("qos_{name}_total"), "counter")?;
+        writeln!(out, "qos_{name}_total {value}")?;
+    }
+
+    // Slots since the signature was processed
+    header(out, "qos_recently_processed_by_age_total", "counter")?;
+    for (age, value) in ["0-3", "4-31", "32+"]
+        .iter()
+        .zip(recently_processed_by_age)
+    {
+        writeln!(
+            out,
+            "qos_recently_processed_by_age_total{{age=\"{age}\"}} {value}"
+        )?;
+    }
+
+    Ok(())
+}
+
+fn header(out: &mut String, name: &str, kind: &str) -> fmt::Result {
+    writeln!(out, "# TYPE {name} {kind}")
+}
+
+fn gauge(
+    out: &mut String,
+    name: &str,
+    value: impl fmt::Display,
+) -> fmt::Result {
+    header(out, name, "gauge")?;
+    writeln!(out, "{name} {value}")
+}
+
+/// Escapes a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut snapshot = Snapshot {
+            queued: 7,
+            ..Snapshot::default()
+        };
+        snapshot.stats.total_packets = 42;
+        snapshot.stats.recently_processed_by_age = [1, 2, 3];
+        snapshot.ingest[1].rate = 2.5;
+
+        let channels = ["tpu".to_string(), escape("fwd\"")];
+        let text = render(&snapshot, &channels);
+
+        let lines: Vec<&str> = text.lines().collect();
+        for line in [
+            "# TYPE qos_total_packets_total counter",
+            "qos_total_packets_total 42",
+            "qos_recently_processed_by_age_total{age=\"32+\"} 3",
+            "qos_ingest_packets_per_second{channel=\"tpu\"} 0",
+            "qos_ingest_packets_per_second{channel=\"fwd\\\"\"} 2.5",
+            "qos_queued_transactions 7",
+        ] {
+            assert!(lines.contains(&line), "missing {line}");
+        }
+
+        // Only named channels are exported
+        assert_eq!(
+            lines
+                .iter()
+                .filter(
+                    |line| line.starts_with("qos_ingest_popped_total{")
+                )
+                .count(),
+            2
+        );
+    }
+}
@@ -1,7 +1,6 @@
 use timer::Timer;
 
 fn main() {
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     Timer::memoize_ticks_per_ms_and_invariant_tsc_check();
 
     let timer = Timer::new();
@@ -9,5 +8,10 @@ fn main() {
     #[allow(deprecated)]
     std::thread::sleep_ms(1000);
 
-    println!("timer measured {} ms", timer.elapsed_ms());
+    println!(
+        "timer measured {} ms, {} us, {} ns",
+        timer.elapsed_ms(),
+        timer.elapsed_us(),
+        timer.elapsed_ns(),
+    );
 }
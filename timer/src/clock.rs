@@ -0,0 +1,114 @@
+//! Time sources that time dependent logic can be generic over, so that
+//! it can be driven by a [ManualClock] instead of real sleeps.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::Timer;
+
+/// A monotonic time source
+pub trait Clock {
+    /// Nanoseconds since an arbitrary but fixed epoch
+    fn now_ns(&self) -> u64;
+
+    fn now_ms(&self) -> u64 {
+        self.now_ns() / 1_000_000
+    }
+}
+
+/// Reads the hardware counter or [std::time::Instant], like [Timer].
+/// The epoch is the clock's creation.
+#[derive(Clone, Debug, Default)]
+pub struct RealClock {
+    epoch: Timer,
+}
+
+impl RealClock {
+    pub fn new() -> RealClock {
+        RealClock {
+            epoch: Timer::new(),
+        }
+    }
+}
+
+impl Clock for RealClock {
+    #[inline(always)]
+    fn now_ns(&self) -> u64 {
+        self.epoch.elapsed_ns()
+    }
+}
+
+/// Only moves when told to. Clones share the same time, so a test can
+/// keep a clone to advance a clock it has handed out.
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock {
+    now_ns: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    /// A clock at its epoch
+    pub fn new() -> ManualClock {
+        ManualClock::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now_ns
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Panics if this would move the clock backwards
+    pub fn set(&self, since_epoch: Duration) {
+        let now_ns = since_epoch.as_nanos() as u64;
+        let previous = self
+            .now_ns
+            .swap(now_ns, Ordering::Relaxed);
+        assert!(previous <= now_ns, "clock must be monotonic");
+    }
+}
+
+impl Clock for ManualClock {
+    #[inline(always)]
+    fn now_ns(&self) -> u64 {
+        self.now_ns.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock_is_shared() {
+        let clock = ManualClock::new();
+        let handed_out = clock.clone();
+        assert_eq!(handed_out.now_ms(), 0);
+
+        clock.advance(Duration::from_micros(2_500));
+        assert_eq!(handed_out.now_ns(), 2_500_000);
+        assert_eq!(handed_out.now_ms(), 2);
+
+        clock.set(Duration::from_secs(1));
+        assert_eq!(handed_out.now_ms(), 1_000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_manual_clock_is_monotonic() {
+        let clock = ManualClock::new();
+        clock.advance(Duration::from_secs(1));
+        clock.set(Duration::ZERO);
+    }
+
+    #[test]
+    fn test_real_clock_advances() {
+        let clock = RealClock::new();
+        let before = clock.now_ns();
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(clock.now_ns() >= before + 1_000_000);
+    }
+}
@@ -1,41 +1,94 @@
-use core::arch::x86_64::_rdtsc;
 use std::time::Instant;
 
+mod clock;
+pub use clock::{Clock, ManualClock, RealClock};
+
+/// A cheap monotonic timer. Reads a hardware counter where one is
+/// known to tick at a constant rate, and falls back to [Instant]
+/// otherwise.
 #[derive(Clone, Debug)]
 pub enum Timer {
+    /// Timestamp counter at start, on x86 with an invariant tsc
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     RDTSC(u64),
+
+    /// Virtual counter at start, on aarch64
+    #[cfg(target_arch = "aarch64")]
+    CNTVCT(u64),
+
     Instant(Instant),
 }
 
 impl Timer {
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    /// Runs the counter checks and calibration up front so that the
+    /// first [Timer::new] on a hot path doesn't pay for them
     pub fn memoize_ticks_per_ms_and_invariant_tsc_check() {
-        check_cpu_supports_invariant_tsc();
-        ticks_per_ms();
+        if counter::usable() {
+            counter::ticks_per_sec();
+        }
     }
 
     pub fn new() -> Self {
-        if cfg!(any(target_arch = "x86", target_arch = "x86_64"))
-            && check_cpu_supports_invariant_tsc()
-        {
-            Timer::RDTSC(unsafe { _rdtsc() })
-        } else {
-            Timer::Instant(Instant::now())
+        if counter::usable() {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            return Timer::RDTSC(counter::read());
+
+            #[cfg(target_arch = "aarch64")]
+            return Timer::CNTVCT(counter::read());
         }
+
+        #[allow(unreachable_code)]
+        Timer::Instant(Instant::now())
     }
 
     pub fn elapsed_ms(&self) -> u64 {
+        self.elapsed_scaled(1_000)
+    }
+
+    pub fn elapsed_us(&self) -> u64 {
+        self.elapsed_scaled(1_000_000)
+    }
+
+    pub fn elapsed_ns(&self) -> u64 {
+        self.elapsed_scaled(1_000_000_000)
+    }
+
+    /// Elapsed time in units of 1/`units_per_sec` seconds
+    #[inline(always)]
+    fn elapsed_scaled(&self, units_per_sec: u64) -> u64 {
         match self {
-            Timer::RDTSC(rdtsc) => {
-                (unsafe { _rdtsc() - rdtsc } / ticks_per_ms())
-            }
-            Timer::Instant(_) => {
-                unimplemented!("Instant timer disabled for now")
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Timer::RDTSC(start) => ticks_to_units(
+                counter::read().saturating_sub(*start),
+                units_per_sec,
+            ),
+            #[cfg(target_arch = "aarch64")]
+            Timer::CNTVCT(start) => ticks_to_units(
+                counter::read().saturating_sub(*start),
+                units_per_sec,
+            ),
+            Timer::Instant(start) => {
+                let elapsed = start.elapsed().as_nanos();
+                (elapsed * units_per_sec as u128 / 1_000_000_000) as u64
             }
         }
     }
 }
 
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new()
+    }
+}
+
+#[allow(dead_code)]
+#[inline(always)]
+fn ticks_to_units(ticks: u64, units_per_sec: u64) -> u64 {
+    // u128 so that ticks * units doesn't overflow for long timers
+    (ticks as u128 * units_per_sec as u128
+        / counter::ticks_per_sec() as u128) as u64
+}
+
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub fn check_cpu_supports_invariant_tsc() -> bool {
     use std::sync::OnceLock;
@@ -54,48 +107,172 @@ pub fn check_cpu_supports_invariant_tsc() -> bool {
     })
 }
 
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+pub fn check_cpu_supports_invariant_tsc() -> bool {
+    false
+}
+
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-fn ticks_per_ms() -> u64 {
-    use std::{sync::OnceLock, time::Duration};
+mod counter {
+    use std::{
+        sync::OnceLock,
+        time::{Duration, Instant},
+    };
 
-    static TICKS_PER_MS: OnceLock<u64> = OnceLock::new();
+    #[cfg(target_arch = "x86")]
+    use core::arch::x86::_rdtsc;
+    #[cfg(target_arch = "x86_64")]
+    use core::arch::x86_64::_rdtsc;
 
-    *TICKS_PER_MS.get_or_init(|| {
-        let warm_up_duration = Duration::from_millis(1000);
-        let measurement_duration = Duration::from_millis(1000);
+    pub fn usable() -> bool {
+        super::check_cpu_supports_invariant_tsc()
+    }
 
-        // Warm up
-        let warm_up_start = Instant::now();
-        while warm_up_start.elapsed() < warm_up_duration {
-            // Spin
-        }
+    #[inline(always)]
+    pub fn read() -> u64 {
+        unsafe { _rdtsc() }
+    }
 
-        let start = Instant::now();
-        let start_tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    /// The tsc frequency isn't exposed portably, so it is measured
+    /// against [Instant] over a few short windows. The median rejects
+    /// windows that were preempted or ran before the core clocked up.
+    pub fn ticks_per_sec() -> u64 {
+        static TICKS_PER_SEC: OnceLock<u64> = OnceLock::new();
 
-        // Measure
-        while Instant::now().duration_since(start)
-            < measurement_duration
-        {
-            // Spin
-        }
+        *TICKS_PER_SEC.get_or_init(|| {
+            const WINDOWS: usize = 7;
+            const WINDOW: Duration = Duration::from_millis(5);
+
+            let mut rates = [0_u64; WINDOWS];
+            for rate in &mut rates {
+                let start = Instant::now();
+                let start_tsc = read();
+                while start.elapsed() < WINDOW {
+                    // Spin
+                }
+                let end_tsc = read();
+                let elapsed = start.elapsed();
 
-        let end_tsc = unsafe { core::arch::x86_64::_rdtsc() };
-        let elapsed_tsc = end_tsc - start_tsc;
+                *rate = ((end_tsc - start_tsc) as u128 * 1_000_000_000
+                    / elapsed.as_nanos())
+                    as u64;
+            }
 
-        let duration_ms = measurement_duration.as_secs_f64() * 1000.0;
-        let tsc_per_ms = elapsed_tsc as f64 / duration_ms;
+            rates.sort_unstable();
+            rates[WINDOWS / 2]
+        })
+    }
+}
 
-        tsc_per_ms as u64
-    })
+#[cfg(target_arch = "aarch64")]
+mod counter {
+    use std::sync::OnceLock;
+
+    pub fn usable() -> bool {
+        ticks_per_sec() > 0
+    }
+
+    /// The virtual counter is architecturally required to tick at a
+    /// constant rate
+    #[inline(always)]
+    pub fn read() -> u64 {
+        let ticks: u64;
+        unsafe {
+            // isb keeps the read from being speculated early
+            core::arch::asm!(
+                "isb",
+                "mrs {}, cntvct_el0",
+                out(reg) ticks,
+                options(nostack),
+            );
+        }
+        ticks
+    }
+
+    /// Read from cntfrq_el0, so no calibration is needed. Zero if the
+    /// firmware didn't set it, in which case [Instant] is used.
+    pub fn ticks_per_sec() -> u64 {
+        static TICKS_PER_SEC: OnceLock<u64> = OnceLock::new();
+
+        *TICKS_PER_SEC.get_or_init(|| {
+            let frequency: u64;
+            unsafe {
+                core::arch::asm!(
+                    "mrs {}, cntfrq_el0",
+                    out(reg) frequency,
+                    options(nomem, nostack),
+                );
+            }
+            frequency
+        })
+    }
 }
 
-#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
-fn ticks_per_ms() -> u64 {
-    unimplemented!("rdtsc only used on x86")
+#[cfg(not(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "aarch64"
+)))]
+mod counter {
+    pub fn usable() -> bool {
+        false
+    }
+
+    pub fn ticks_per_sec() -> u64 {
+        unreachable!("no hardware counter on this target")
+    }
 }
 
-#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
-fn check_cpu_supports_invariant_tsc() -> bool {
-    false
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Starts the hardware timer, where usable, and the fallback
+    const TIMERS: [fn() -> Timer; 2] =
+        [Timer::new, || Timer::Instant(Instant::now())];
+
+    #[test]
+    fn test_elapsed_is_monotonic() {
+        for start in TIMERS {
+            let timer = start();
+            let mut last = 0;
+            for _ in 0..100_000 {
+                let elapsed = timer.elapsed_ns();
+                assert!(elapsed >= last, "{timer:?} went backwards");
+                last = elapsed;
+            }
+        }
+    }
+
+    #[test]
+    fn test_elapsed_is_bracketed_by_instant() {
+        for start in TIMERS {
+            // The timer runs for less than the outer interval and more
+            // than the inner one, however long a loaded machine stalls
+            // between any two of these reads
+            let outer = Instant::now();
+            let timer = start();
+            let inner = Instant::now();
+            std::thread::sleep(Duration::from_millis(10));
+            let inner_ns = inner.elapsed().as_nanos() as u64;
+            let elapsed_ns = timer.elapsed_ns();
+            let outer_ns = outer.elapsed().as_nanos() as u64;
+
+            // Apart from the calibration error of a hardware counter
+            assert!(
+                elapsed_ns >= inner_ns * 9 / 10,
+                "{timer:?}: {elapsed_ns}ns < {inner_ns}ns"
+            );
+            assert!(
+                elapsed_ns <= outer_ns * 11 / 10,
+                "{timer:?}: {elapsed_ns}ns > {outer_ns}ns"
+            );
+
+            // Coarser units agree with the nanoseconds
+            let elapsed_ms = timer.elapsed_ms();
+            assert!(elapsed_ms >= elapsed_ns / 1_000_000);
+            assert!(elapsed_ms <= timer.elapsed_us() / 1_000);
+        }
+    }
 }